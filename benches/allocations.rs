@@ -0,0 +1,114 @@
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use criterion::black_box;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+use splayers::Entry;
+use splayers::FileEntry;
+use splayers::ItemType;
+use splayers::Meta;
+use splayers::Ownership;
+use splayers::Status;
+
+use annul::Config;
+
+/// How many containers there are, and how many members each has.
+const WIDTH: usize = 100;
+
+/// The system allocator, counting how many times it's asked for memory.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn entry(path: String, children: Status) -> Entry {
+    Entry {
+        local: FileEntry {
+            path: path.into_bytes().into_boxed_slice(),
+            temp: None,
+            meta: Meta {
+                atime: 0,
+                mtime: 0,
+                ctime: 0,
+                btime: 0,
+                item_type: ItemType::RegularFile,
+                ownership: Ownership::Unknown,
+                xattrs: HashMap::new(),
+            },
+        },
+        children,
+    }
+}
+
+/// A source package with `WIDTH` directories of `WIDTH` files, none of them with any content,
+/// so all that's measured is walking the tree and writing the records.
+fn wide() -> Vec<Entry> {
+    let directories = (0..WIDTH)
+        .map(|d| {
+            let files = (0..WIDTH)
+                .map(|f| entry(format!("src/module{}/file{}.c", d, f), Status::Unrecognised))
+                .collect();
+            entry(format!("src/module{}", d), Status::Success(files))
+        })
+        .collect();
+    vec![entry(
+        "pkg_1.0.orig.tar.gz".to_string(),
+        Status::Success(directories),
+    )]
+}
+
+fn allocations(c: &mut Criterion) {
+    let entries = wide();
+    let members = 1 + WIDTH + WIDTH * WIDTH;
+    // on the calling thread, so building a pool isn't counted
+    let config = Config {
+        threads: Some(1),
+        ..Config::default()
+    };
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    annul::output_with(&entries, &[], &mut io::sink(), &config).unwrap();
+    let allocated = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    eprintln!(
+        "{} allocations writing {} members: {:.2} each",
+        allocated,
+        members,
+        allocated as f64 / members as f64
+    );
+
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Elements(members as u64));
+    group.bench_function("wide tree", |b| {
+        b.iter(|| annul::output_with(black_box(&entries), &[], &mut io::sink(), &config).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, allocations);
+criterion_main!(benches);
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+use splayers::Entry;
+use splayers::FileEntry;
+use splayers::ItemType;
+use splayers::Meta;
+use splayers::Ownership;
+use splayers::Status;
+
+use annul::buckets::Registry;
+use annul::Config;
+use annul::DEFAULT_LEVEL;
+
+/// How many members of each kind the measured archive has, and how many each dictionary is
+/// trained on; different ones, generated from a different seed.
+const MEMBERS: usize = 250;
+const SAMPLES: usize = 400;
+
+/// Small, as the point is lots of them, one per bucket.
+const DICTIONARY_SIZE: usize = 16 * 1024;
+
+const WORDS: &[&str] = &[
+    "buffer", "count", "state", "node", "parser", "entry", "config", "value", "length", "index",
+    "table", "header", "stream", "error", "result", "offset", "handle", "context", "queue",
+    "request", "reply", "cache", "module", "option", "symbol", "token", "frame", "window",
+];
+
+/// Some made-up content for a kind of member.
+type Generate = fn(&mut Random) -> String;
+
+/// The kinds of member, with a name in each bucket, and one which isn't in any.
+const KINDS: &[(&str, Generate)] = &[
+    ("src/{}.c", c),
+    ("scripts/{}.sh", script),
+    ("doc/{}.txt", text),
+    ("{}/Makefile.am", build),
+    ("debian/{}", text),
+];
+
+fn c(random: &mut Random) -> String {
+    let mut out = format!(
+        "#include <stdio.h>\n#include <stdlib.h>\n#include \"{}.h\"\n\n",
+        random.word()
+    );
+    for _ in 0..random.below(6) + 2 {
+        let (name, kind, arg, field) = (random.word(), random.word(), random.word(), random.word());
+        out += &format!(
+            "static int {}_{}(struct {} *{}, size_t len)\n{{\n    if (!{}) {{\n        \
+             return -EINVAL;\n    }}\n    for (size_t i = 0; i < len; ++i) {{\n        \
+             {}->{}[i] = {};\n    }}\n    return 0;\n}}\n\n",
+            name,
+            kind,
+            kind,
+            arg,
+            arg,
+            arg,
+            field,
+            random.below(256)
+        );
+    }
+    out
+}
+
+fn script(random: &mut Random) -> String {
+    let mut out = String::from("#!/bin/sh\nset -e\n\n");
+    for _ in 0..random.below(6) + 2 {
+        let (var, word) = (random.word().to_uppercase(), random.word());
+        out += &format!(
+            "if [ -z \"${}\" ]; then\n    echo \"$0: missing {}\" >&2\n    exit 1\nfi\n\
+             for f in \"${}\"/*.{}; do\n    install -m 644 \"$f\" \"$DESTDIR/usr/share/{}\"\n\
+             done\n\n",
+            var, word, var, word, word
+        );
+    }
+    out
+}
+
+fn text(random: &mut Random) -> String {
+    let mut out = String::new();
+    for _ in 0..random.below(10) + 5 {
+        let (first, second, third) = (random.word(), random.word(), random.word());
+        out += &format!(
+            "The {} is passed to the {} before the {} is read, so it must be set first. \
+             See the documentation for the {} for more details.\n",
+            first, second, third, first
+        );
+    }
+    out
+}
+
+fn build(random: &mut Random) -> String {
+    let mut out = String::from("AUTOMAKE_OPTIONS = foreign\n\n");
+    for _ in 0..random.below(6) + 2 {
+        let (program, first, second) = (random.word(), random.word(), random.word());
+        out += &format!(
+            "bin_PROGRAMS += {}\n{}_SOURCES = {}.c {}.c\n{}_LDADD = $(LIBS) lib{}.la\n\
+             {}_CFLAGS = $(AM_CFLAGS) -I$(top_srcdir)/include\n\n",
+            program, program, first, second, program, first, program
+        );
+    }
+    out
+}
+
+/// xorshift64, so the inputs are the same every run, without a dependency.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn word(&mut self) -> &'static str {
+        WORDS[self.below(WORDS.len() as u64) as usize]
+    }
+}
+
+fn registry() -> Registry {
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    let mut registry = Registry::default();
+    for &(bucket, generate) in &[
+        ("c", c as Generate),
+        ("script", script),
+        ("text", text),
+        ("build", build),
+    ] {
+        let samples = (0..SAMPLES)
+            .map(|_| io::Cursor::new(generate(&mut random)))
+            .collect();
+        let dictionary = annul::dict::train(samples, DICTIONARY_SIZE).unwrap();
+        registry = registry.with(bucket, dictionary).unwrap();
+    }
+    registry
+}
+
+/// `MEMBERS` of each of the `KINDS`, interleaved, as they might be in a real source package.
+fn members(dir: &Path) -> (Vec<Entry>, u64) {
+    let mut random = Random(0x9e37_79b9_7f4a_7c15);
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for i in 0..MEMBERS {
+        for (kind, &(name, generate)) in KINDS.iter().enumerate() {
+            let data = generate(&mut random);
+            total += data.len() as u64;
+            let temp = dir.join(format!("member{}-{}", kind, i));
+            fs::write(&temp, data).unwrap();
+            entries.push(Entry {
+                local: FileEntry {
+                    path: name
+                        .replace("{}", &format!("{}{}", random.word(), i))
+                        .into_bytes()
+                        .into_boxed_slice(),
+                    temp: Some(temp),
+                    meta: Meta {
+                        atime: 0,
+                        mtime: 0,
+                        ctime: 0,
+                        btime: 0,
+                        item_type: ItemType::RegularFile,
+                        ownership: Ownership::Unknown,
+                        xattrs: HashMap::new(),
+                    },
+                },
+                children: Status::Unrecognised,
+            });
+        }
+    }
+    (entries, total)
+}
+
+/// The records, and then how big they are once they've been through the main stream too.
+fn sizes(entries: &[Entry], config: &Config) -> (usize, usize) {
+    let mut records = Vec::new();
+    annul::output_with(entries, &[], &mut records, config).unwrap();
+    let mut stream =
+        zstd::Encoder::with_dictionary(Vec::new(), DEFAULT_LEVEL, annul::dict::ORIG).unwrap();
+    stream.write_all(&records).unwrap();
+    (records.len(), stream.finish().unwrap().len())
+}
+
+fn buckets(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let (entries, total) = members(dir.path());
+    let configs = [
+        (
+            "one stream",
+            Config {
+                threads: Some(1),
+                ..Config::default()
+            },
+        ),
+        (
+            "bucket dictionaries",
+            Config {
+                threads: Some(1),
+                buckets: registry(),
+                ..Config::default()
+            },
+        ),
+    ];
+
+    for (name, config) in &configs {
+        let (records, compressed) = sizes(&entries, config);
+        eprintln!(
+            "{}: {} bytes in, {} of records, {} compressed: {:.2}%",
+            name,
+            total,
+            records,
+            compressed,
+            100. * compressed as f64 / total as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("buckets");
+    group.throughput(Throughput::Bytes(total));
+    group.sample_size(10);
+    for (name, config) in &configs {
+        group.bench_function(*name, |b| b.iter(|| sizes(&entries, config)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, buckets);
+criterion_main!(benches);
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+use splayers::Entry;
+use splayers::FileEntry;
+use splayers::ItemType;
+use splayers::Meta;
+use splayers::Ownership;
+use splayers::Status;
+
+use annul::flags::ContentState;
+use annul::Config;
+
+/// How many members the archive has.
+const MEMBERS: usize = 10_000;
+
+/// Source-like members, a few KiB each, every tenth with a binary byte which strings removes,
+/// all different, so none of them are duplicates.
+fn members(dir: &Path) -> Vec<Entry> {
+    (0..MEMBERS)
+        .map(|i| {
+            let mut data = Vec::new();
+            for line in 0..80 {
+                data.extend_from_slice(
+                    format!("    let value{} = compute(input, {});\n", line, i).as_bytes(),
+                );
+            }
+            if 0 == i % 10 {
+                data.push(0x01);
+            }
+            let temp = dir.join(format!("member{}", i));
+            fs::write(&temp, data).unwrap();
+            Entry {
+                local: FileEntry {
+                    path: format!("src/file{}.rs", i).into_bytes().into_boxed_slice(),
+                    temp: Some(temp),
+                    meta: Meta {
+                        atime: 0,
+                        mtime: 0,
+                        ctime: 0,
+                        btime: 0,
+                        item_type: ItemType::RegularFile,
+                        ownership: Ownership::Unknown,
+                        xattrs: HashMap::new(),
+                    },
+                },
+                children: Status::Unrecognised,
+            }
+        })
+        .collect()
+}
+
+/// Bytes this process has passed to `write`, and how many calls it took; the output's going to
+/// a sink, so that's all temporary files. Linux only, and zeros elsewhere.
+fn written() -> (u64, u64) {
+    let io = fs::read_to_string("/proc/self/io").unwrap_or_default();
+    let field = |name: &str| {
+        io.lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line[name.len()..].trim().parse().ok())
+            .unwrap_or(0)
+    };
+    (field("wchar:"), field("syscw:"))
+}
+
+fn skip_length_compare(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let entries = members(dir.path());
+    let config = |skip_length_compare| Config {
+        threads: Some(1),
+        skip_length_compare,
+        ..Config::default()
+    };
+
+    for &(name, skip) in &[("compared", false), ("--skip-length-compare", true)] {
+        let (bytes, calls) = written();
+        let start = Instant::now();
+        let stats = annul::output_with(&entries, &[], &mut io::sink(), &config(skip)).unwrap();
+        let took = start.elapsed();
+        let (after_bytes, after_calls) = written();
+        let temporaries = MEMBERS as u64 - stats.with_state(ContentState::NotCompared);
+        eprintln!(
+            "{}: {:?}, {} temporary files, {} bytes written to them in {} calls",
+            name,
+            took,
+            temporaries,
+            after_bytes - bytes,
+            after_calls - calls
+        );
+    }
+
+    let mut group = c.benchmark_group("skip length compare");
+    group.throughput(Throughput::Elements(MEMBERS as u64));
+    group.sample_size(10);
+    for &(name, skip) in &[("compared", false), ("--skip-length-compare", true)] {
+        let config = config(skip);
+        group.bench_function(name, |b| {
+            b.iter(|| annul::output_with(&entries, &[], &mut io::sink(), &config).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, skip_length_compare);
+criterion_main!(benches);
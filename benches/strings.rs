@@ -0,0 +1,101 @@
+use std::io;
+
+use criterion::black_box;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use annul::strings::strings_reader;
+use annul::strings::StringsConfig;
+
+/// How big each input is; big enough that the per-call setup doesn't matter.
+const LEN: usize = 8 * 1024 * 1024;
+
+/// Something like a source file: lines of ASCII, the occasional accent, and a stray control byte.
+fn mostly_ascii() -> Vec<u8> {
+    let mut data = Vec::with_capacity(LEN);
+    while data.len() < LEN {
+        for _ in 0..10 {
+            for _ in 0..50 {
+                data.extend_from_slice(
+                    b"    let value = compute(input, &mut state)?; // keep going\n",
+                );
+            }
+            data.extend_from_slice("    // caf\u{e9}\n".as_bytes());
+        }
+        data.push(0x01);
+    }
+    data
+}
+
+/// Printable ASCII, and nothing else, so it's all one long run.
+fn all_ascii() -> Vec<u8> {
+    (b' '..0x7f).cycle().take(LEN).collect()
+}
+
+/// Short words between short bursts of binary, like the string table of an object file.
+fn mixed() -> Vec<u8> {
+    let mut random = Random(0x9e37_79b9_7f4a_7c15);
+    let mut data = Vec::with_capacity(LEN);
+    while data.len() < LEN {
+        let word = 1 + random.next() % 12;
+        data.extend((0..word).map(|_| b'a' + (random.next() % 26) as u8));
+        let binary = 1 + random.next() % 4;
+        data.extend((0..binary).map(|_| (random.next() % 32) as u8));
+    }
+    data
+}
+
+/// Text which is almost entirely two, three and four byte characters.
+fn multibyte() -> Vec<u8> {
+    let text = "Ελληνικά русский 日本語の文章 한국어 🦀🎉 ";
+    text.as_bytes().iter().cloned().cycle().take(LEN).collect()
+}
+
+/// Noise, which strings throws almost all of away.
+fn all_binary() -> Vec<u8> {
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    (0..LEN).map(|_| random.next() as u8).collect()
+}
+
+/// xorshift64, so the inputs are the same every run, without a dependency.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn strings(c: &mut Criterion) {
+    let inputs = vec![
+        ("mostly ascii", mostly_ascii()),
+        ("all ascii", all_ascii()),
+        ("mixed ascii and binary", mixed()),
+        ("multibyte utf-8", multibyte()),
+        ("all binary", all_binary()),
+    ];
+
+    let mut group = c.benchmark_group("strings");
+    for (name, data) in inputs {
+        // the generators finish whatever they were in the middle of, so some run over `LEN`
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                io::copy(
+                    &mut strings_reader(black_box(&data[..]), StringsConfig::default()),
+                    &mut io::sink(),
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, strings);
+criterion_main!(benches);
@@ -0,0 +1,129 @@
+//! Small dictionaries for particular kinds of member, for `--bucket-dict`.
+//!
+//! Members whose name puts them in a bucket with a dictionary have their (strings'd) content
+//! compressed, on its own, with that dictionary, before it joins the main stream. The frame
+//! header records the dictionary id, which is how it's found again when reading.
+
+use std::collections::HashMap;
+
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+
+use crate::dict;
+
+/// The buckets members can be classified into.
+pub const BUCKETS: &[&str] = &["build", "c", "script", "text", "web"];
+
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    by_bucket: HashMap<String, Vec<u8>>,
+}
+
+impl Registry {
+    /// Add (or replace) the dictionary for a bucket.
+    pub fn with(mut self, bucket: &str, dictionary: Vec<u8>) -> Result<Registry, Error> {
+        ensure!(
+            BUCKETS.contains(&bucket),
+            "unknown bucket {:?}, try one of: {}",
+            bucket,
+            BUCKETS.join(", ")
+        );
+
+        let id = dict::id(&dictionary)
+            .ok_or_else(|| format_err!("{} dictionary must be a trained dictionary", bucket))?;
+
+        ensure!(
+            self.by_id(id).is_none(),
+            "dictionary id {:08x} is used twice",
+            id
+        );
+
+        self.by_bucket.insert(bucket.to_string(), dictionary);
+        Ok(self)
+    }
+
+    /// The dictionary for the bucket a member with this file name would be in, if there is one.
+    pub fn for_name(&self, name: &[u8]) -> Option<&[u8]> {
+        classify(name)
+            .and_then(|bucket| self.by_bucket.get(bucket))
+            .map(|dictionary| dictionary.as_slice())
+    }
+
+    /// The dictionary with this id, for decompressing.
+    pub fn by_id(&self, id: u32) -> Option<&[u8]> {
+        self.by_bucket
+            .values()
+            .find(|dictionary| Some(id) == dict::id(dictionary))
+            .map(|dictionary| dictionary.as_slice())
+    }
+}
+
+/// Which bucket, if any, a member belongs in, from the last component of its name.
+pub fn classify(name: &[u8]) -> Option<&'static str> {
+    let file_name = name.rsplit(|&b| b'/' == b).next().unwrap_or(name);
+
+    match file_name {
+        b"Makefile" | b"CMakeLists.txt" | b"configure" | b"rules" => return Some("build"),
+        _ => (),
+    }
+
+    let extension = file_name.rsplit(|&b| b'.' == b).next()?;
+    if extension.len() == file_name.len() {
+        return None;
+    }
+
+    Some(match extension {
+        b"am" | b"in" | b"ac" | b"m4" | b"mk" | b"cmake" => "build",
+        b"c" | b"h" | b"cc" | b"cpp" | b"cxx" | b"hh" | b"hpp" => "c",
+        b"sh" | b"py" | b"pl" | b"pm" | b"rb" | b"lua" | b"tcl" => "script",
+        b"txt" | b"md" | b"rst" | b"texi" | b"1" | b"3" | b"8" => "text",
+        b"js" | b"css" | b"html" | b"htm" | b"xml" | b"svg" | b"json" => "web",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::classify;
+    use super::Registry;
+
+    #[test]
+    fn classification() {
+        assert_eq!(Some("c"), classify(b"src/foo.c"));
+        assert_eq!(Some("c"), classify(b"foo.tar/include/foo.hpp"));
+        assert_eq!(Some("build"), classify(b"debian/rules"));
+        assert_eq!(Some("build"), classify(b"Makefile"));
+        assert_eq!(Some("web"), classify(b"docs/index.html"));
+        assert_eq!(None, classify(b"COPYING"));
+        assert_eq!(None, classify(b"logo.png"));
+        assert_eq!(None, classify(b"dir.c/README"));
+    }
+
+    #[test]
+    fn registry() {
+        use crate::dict;
+
+        let registry = Registry::default()
+            .with("c", dict::DIFF.to_vec())
+            .unwrap()
+            .with("text", dict::ORIG.to_vec())
+            .unwrap();
+
+        assert_eq!(Some(dict::DIFF), registry.for_name(b"foo.c"));
+        assert_eq!(Some(dict::ORIG), registry.for_name(b"NEWS.txt"));
+        assert_eq!(None, registry.for_name(b"foo.py"));
+        assert_eq!(
+            Some(dict::ORIG),
+            registry.by_id(dict::id(dict::ORIG).unwrap())
+        );
+
+        assert!(Registry::default()
+            .with("nonsense", dict::DIFF.to_vec())
+            .is_err());
+        assert!(Registry::default()
+            .with("c", b"raw content".to_vec())
+            .is_err());
+        assert!(registry.with("web", dict::DIFF.to_vec()).is_err());
+    }
+}
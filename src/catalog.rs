@@ -0,0 +1,137 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Row<'a> {
+    pub url: &'a str,
+    pub package: &'a str,
+    pub version: &'a str,
+    pub output: Option<&'a Path>,
+    pub entries: u64,
+    pub compressed_size: u64,
+    pub status: &'a str,
+    pub elapsed: Duration,
+}
+
+const HEADER: &str = "url,package,version,output,entries,compressed_size,status,elapsed_secs\n";
+
+/// Open the catalog, for rows to be appended to, creating it, with a header, if necessary.
+pub fn open(catalog: &Path) -> io::Result<fs::File> {
+    match fs::OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(catalog)
+    {
+        Ok(mut file) => {
+            file.write_all(HEADER.as_bytes())?;
+            Ok(file)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::OpenOptions::new().append(true).open(catalog)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Add a row to the catalog `open` returned.
+///
+/// Other annul processes may be appending to the same catalog at once, so the row is
+/// assembled in memory and handed to the kernel in a single append.
+pub fn append<W: Write>(mut catalog: W, row: &Row) -> io::Result<()> {
+    let mut line = Vec::with_capacity(256);
+    write_row(&mut line, row);
+    catalog.write_all(&line)
+}
+
+fn write_row(line: &mut Vec<u8>, row: &Row) {
+    let output = row
+        .output
+        .map(|output| output.to_string_lossy())
+        .unwrap_or_default();
+    let fields = [
+        row.url.to_string(),
+        row.package.to_string(),
+        row.version.to_string(),
+        output.to_string(),
+        row.entries.to_string(),
+        row.compressed_size.to_string(),
+        row.status.to_string(),
+        format!("{:.3}", row.elapsed.as_secs_f64()),
+    ];
+
+    for (i, field) in fields.iter().enumerate() {
+        if 0 != i {
+            line.push(b',');
+        }
+        escape(line, field);
+    }
+    line.push(b'\n');
+}
+
+fn escape(line: &mut Vec<u8>, field: &str) {
+    if !field.contains(&[',', '"', '\n', '\r'][..]) {
+        line.extend_from_slice(field.as_bytes());
+        return;
+    }
+
+    line.push(b'"');
+    for c in field.bytes() {
+        if b'"' == c {
+            line.push(b'"');
+        }
+        line.push(c);
+    }
+    line.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn header_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = dir.path().join("catalog.csv");
+        let row = super::Row {
+            url: "http://urika/foo_1.0.dsc",
+            status: "ok",
+            ..super::Row::default()
+        };
+
+        super::append(super::open(&catalog).unwrap(), &row).unwrap();
+        super::append(super::open(&catalog).unwrap(), &row).unwrap();
+        let written = fs::read_to_string(&catalog).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(3, lines.len(), "{}", written);
+        assert_eq!(super::HEADER.trim_end(), lines[0]);
+        assert_eq!(lines[1], lines[2]);
+    }
+
+    #[test]
+    fn row() {
+        let mut line = Vec::new();
+        super::write_row(
+            &mut line,
+            &super::Row {
+                url: "http://urika/foo_1.0.dsc",
+                package: "foo",
+                version: "1.0-1",
+                output: Some(Path::new("/tmp/foo_1.0.dsc.annul")),
+                entries: 7,
+                compressed_size: 1234,
+                status: "failed: \"short write\", probably",
+                elapsed: Duration::from_millis(1500),
+            },
+        );
+        assert_eq!(
+            "http://urika/foo_1.0.dsc,foo,1.0-1,/tmp/foo_1.0.dsc.annul,7,1234,\
+             \"failed: \"\"short write\"\", probably\",1.500\n",
+            String::from_utf8(line).unwrap()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+/// The urls recorded as complete. A missing checkpoint has nothing in it.
+///
+/// Only newline-terminated lines count, so a record torn by a crash is ignored.
+pub fn load(checkpoint: &Path) -> io::Result<HashSet<String>> {
+    let done = match fs::read(checkpoint) {
+        Ok(done) => done,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    let complete = match done.iter().rposition(|&b| b'\n' == b) {
+        Some(end) => &done[..end],
+        None => return Ok(HashSet::new()),
+    };
+
+    Ok(complete
+        .split(|&b| b'\n' == b)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect())
+}
+
+/// Note that `url` is complete, and make sure that's on disk before returning.
+///
+/// A record torn by a crash is finished off with a newline first, so it doesn't swallow this one.
+pub fn record(checkpoint: &Path, url: &str) -> io::Result<()> {
+    let (mut file, created) = match fs::OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(checkpoint)
+    {
+        Ok(file) => (file, true),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(checkpoint)?;
+            (file, false)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut line = Vec::with_capacity(url.len() + 2);
+    if !created && !ends_in_newline(&mut file)? {
+        line.push(b'\n');
+    }
+    line.extend_from_slice(url.as_bytes());
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()?;
+
+    // the file's name isn't on disk until its directory is, either
+    if created {
+        if let Some(dir) = checkpoint
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::File::open(dir)?.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Is `file` empty, or is its last byte a newline?
+fn ends_in_newline(file: &mut fs::File) -> io::Result<bool> {
+    if 0 == file.metadata()?.len() {
+        return Ok(true);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0];
+    file.read_exact(&mut last)?;
+    Ok(b'\n' == last[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+
+        assert!(super::load(&checkpoint).unwrap().is_empty());
+        super::record(&checkpoint, "http://a/b.dsc").unwrap();
+        super::record(&checkpoint, "http://a/c.dsc").unwrap();
+        let done = super::load(&checkpoint).unwrap();
+        assert!(done.contains("http://a/b.dsc"));
+        assert!(done.contains("http://a/c.dsc"));
+        assert!(!done.contains("http://a/"));
+    }
+
+    #[test]
+    fn torn() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        fs::write(&checkpoint, "http://a/b.dsc\nhttp://a/c.dsc").unwrap();
+        let done = super::load(&checkpoint).unwrap();
+        assert!(done.contains("http://a/b.dsc"));
+        assert!(!done.contains("http://a/c.dsc"));
+    }
+
+    #[test]
+    fn torn_then_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        fs::write(&checkpoint, "http://a/b.dsc\nhttp://a/c.ds").unwrap();
+        super::record(&checkpoint, "http://a/d.dsc").unwrap();
+        let done = super::load(&checkpoint).unwrap();
+        assert!(done.contains("http://a/b.dsc"));
+        assert!(done.contains("http://a/d.dsc"));
+        assert_eq!(
+            "http://a/b.dsc\nhttp://a/c.ds\nhttp://a/d.dsc\n",
+            fs::read_to_string(&checkpoint).unwrap()
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::io;
+use std::io::Read;
+
+use byteorder::ByteOrder;
+use byteorder::ReadBytesExt;
+use byteorder::LE;
+use cast::u64;
+
+use crate::skippable;
+use crate::strings;
+
+pub const ORIG: &[u8] = include_bytes!("../dicts/orig.zstd-dictionary");
+pub const DIFF: &[u8] = include_bytes!("../dicts/diff.zstd-dictionary");
+pub const DEBIAN: &[u8] = include_bytes!("../dicts/debian.tar.zstd-dictionary");
+
+/// The compressions dpkg-source accepts for the files in a source package.
+const COMPRESSED: &[&str] = &[".gz", ".bz2", ".xz", ".lzma", ".zst"];
+
+/// Which of the embedded dictionaries suits a source file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Dictionary {
+    Orig,
+    Diff,
+    Debian,
+}
+
+impl Dictionary {
+    /// Guessed from the (file) name the sources are being fetched from.
+    pub fn for_name(name: &str) -> Dictionary {
+        Dictionary::from_name(name).unwrap_or(Dictionary::Orig)
+    }
+
+    /// Picked by the (file) name, if it follows the Debian naming rules: `.diff.gz`,
+    /// `.debian.tar.*`, or `.orig.tar.*`, or `.orig-component.tar.*`, at the very end, in any
+    /// case. Anything earlier in the name, or the directories it's in, doesn't count.
+    pub fn from_name(name: &str) -> Option<Dictionary> {
+        let name = name.rsplit('/').next().unwrap_or(name).to_ascii_lowercase();
+        let stem = COMPRESSED
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .unwrap_or(&name);
+
+        if stem.ends_with(".diff") {
+            return Some(Dictionary::Diff);
+        }
+
+        let tar = stem.strip_suffix(".tar")?;
+        let last = tar.rsplit('.').next().unwrap_or(tar);
+        if tar.contains('.') && "debian" == last {
+            Some(Dictionary::Debian)
+        } else if tar.contains('.') && ("orig" == last || last.starts_with("orig-")) {
+            Some(Dictionary::Orig)
+        } else {
+            None
+        }
+    }
+
+    /// Guessed from the start of the file, for names which don't say: a unified diff gets the
+    /// diff dictionary, and anything else, including an archive which happens to contain a
+    /// diff, the original sources one.
+    pub fn sniff<R: Read>(from: R) -> io::Result<Dictionary> {
+        let mut start = Vec::with_capacity(SNIFF_BYTES);
+        from.take(u64(SNIFF_BYTES)).read_to_end(&mut start)?;
+
+        let archive = start.starts_with(b"\x1f\x8b")
+            || start.starts_with(b"\xfd7zXZ\x00")
+            || start.starts_with(b"BZh")
+            || start.starts_with(&FRAME_MAGIC)
+            || start.get(257..262) == Some(&b"ustar"[..]);
+
+        let mut lines = start.split(|&b| b'\n' == b);
+        let diff = lines.any(|line| line.starts_with(b"--- "))
+            && lines.any(|line| line.starts_with(b"+++ "));
+
+        Ok(if diff && !archive {
+            Dictionary::Diff
+        } else {
+            Dictionary::Orig
+        })
+    }
+
+    pub fn bytes(self) -> &'static [u8] {
+        match self {
+            Dictionary::Orig => ORIG,
+            Dictionary::Diff => DIFF,
+            Dictionary::Debian => DEBIAN,
+        }
+    }
+}
+
+/// How big `train` makes a dictionary, unless asked otherwise: 110KiB, as `zstd --train` does.
+///
+/// Bigger dictionaries help the small members most, but are loaded for every one of them.
+pub const DEFAULT_TRAINED_SIZE: usize = 110 * 1024;
+
+/// A zstd dictionary, of at most `max_size` bytes, for content like `samples`, for `--bucket-dict`.
+///
+/// The samples are put through strings first, as members are before they're compressed, so
+/// the dictionary learns what's actually stored. zstd wants lots of them: a few hundred at
+/// the very least, and together around a hundred times `max_size`; with too few it'll fail,
+/// or produce a dictionary which doesn't help.
+pub fn train<R: Read>(samples: Vec<R>, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut stringified = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let mut data = Vec::new();
+        io::copy(
+            &mut strings::strings_reader(sample, strings::StringsConfig::default()),
+            &mut data,
+        )?;
+        stringified.push(data);
+    }
+
+    zstd::dict::from_samples(&stringified, max_size)
+}
+
+/// How much of a file `Dictionary::sniff` looks at.
+const SNIFF_BYTES: usize = 8 * 1024;
+
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+const FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The id a trained zstd dictionary was assigned, if it has one.
+pub fn id(dictionary: &[u8]) -> Option<u32> {
+    if dictionary.len() < 8 || dictionary[..4] != DICTIONARY_MAGIC {
+        return None;
+    }
+
+    Some(LE::read_u32(&dictionary[4..8])).filter(|&id| 0 != id)
+}
+
+/// The embedded dictionary with this id, for reading outputs back.
+pub fn embedded(id: u32) -> Option<&'static [u8]> {
+    [ORIG, DIFF, DEBIAN]
+        .iter()
+        .cloned()
+        .find(|dictionary| Some(id) == self::id(dictionary))
+}
+
+/// The dictionary id recorded in the header of the first zstd frame in `from`, if any.
+///
+/// Skippable frames before it are, well, skipped.
+pub fn frame_id<R: Read>(mut from: R) -> io::Result<Option<u32>> {
+    let mut header = [0u8; 5];
+    from.read_exact(&mut header[..4])?;
+
+    while skippable::is_magic(LE::read_u32(&header[..4])) {
+        let len = u64::from(from.read_u32::<LE>()?);
+        if len != io::copy(&mut (&mut from).take(len), &mut io::sink())? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        from.read_exact(&mut header[..4])?;
+    }
+
+    from.read_exact(&mut header[4..])?;
+
+    if header[..4] != FRAME_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a zstd frame",
+        ));
+    }
+
+    let descriptor = header[4];
+    let single_segment = 0 != descriptor & 0b0010_0000;
+    let id_len = match descriptor & 0b11 {
+        0 => return Ok(None),
+        1 => 1,
+        2 => 2,
+        _ => 4,
+    };
+
+    if !single_segment {
+        // window descriptor
+        from.read_exact(&mut [0u8])?;
+    }
+
+    let mut id = [0u8; 4];
+    from.read_exact(&mut id[..id_len])?;
+    Ok(Some(LE::read_u32(&id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::DIFF;
+    use super::ORIG;
+
+    #[test]
+    fn embedded_ids() {
+        assert_eq!(Some(0x360e_6b0b), super::id(ORIG));
+        assert_ne!(super::id(ORIG), super::id(DIFF));
+        assert_eq!(None, super::id(b"just some content"));
+        assert_eq!(Some(DIFF), super::embedded(super::id(DIFF).unwrap()));
+        assert_eq!(None, super::embedded(7));
+    }
+
+    #[test]
+    fn for_name() {
+        use super::Dictionary;
+        assert_eq!(Dictionary::Diff, Dictionary::for_name("foo_1.2-3.diff.gz"));
+        assert_eq!(
+            Dictionary::Debian,
+            Dictionary::for_name("foo_1.2-3.debian.tar.xz")
+        );
+        assert_eq!(
+            Dictionary::Orig,
+            Dictionary::for_name("foo_1.2.orig.tar.gz")
+        );
+        assert_eq!(DIFF, Dictionary::Diff.bytes());
+
+        assert_eq!(None, Dictionary::from_name("foo-1.2.tar.gz"));
+        assert_eq!(Dictionary::Orig, Dictionary::for_name("foo-1.2.tar.gz"));
+
+        let named = |name| Dictionary::from_name(name);
+        assert_eq!(Some(Dictionary::Orig), named("foo_1.2.orig.tar.bz2"));
+        assert_eq!(Some(Dictionary::Orig), named("foo_1.2.orig.tar.lzma"));
+        assert_eq!(Some(Dictionary::Orig), named("foo_1.2.orig-docs.tar.xz"));
+        assert_eq!(Some(Dictionary::Orig), named("FOO_1.2.ORIG.TAR.GZ"));
+        assert_eq!(Some(Dictionary::Debian), named("foo_1.2-3.debian.tar.gz"));
+        assert_eq!(Some(Dictionary::Debian), named("foo_1.2-3.Debian.tar.zst"));
+        assert_eq!(Some(Dictionary::Diff), named("foo.DIFF.gz"));
+        assert_eq!(None, named("foo_1.2.tar.xz"));
+
+        // only the end of the name counts
+        assert_eq!(Some(Dictionary::Diff), named("My.Debian.Notes.diff.gz"));
+        assert_eq!(
+            Some(Dictionary::Orig),
+            named("x.diff.d/foo_1.2.orig.tar.gz")
+        );
+        assert_eq!(None, named("foo.diff.gz.asc"));
+        assert_eq!(None, named("foo.debian.notes.tar.gz"));
+        assert_eq!(None, named("foo.orig.d/debian.tar.gz"));
+        assert_eq!(None, named("foo.original.tar.gz"));
+    }
+
+    #[test]
+    fn sniff() {
+        use super::Dictionary;
+        let sniff = |data: &[u8]| Dictionary::sniff(data).unwrap();
+
+        let diff = b"diff -ru a/x b/x\n--- a/x\n+++ b/x\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(Dictionary::Diff, sniff(diff));
+        assert_eq!(Dictionary::Orig, sniff(b"+++ b/x\n--- a/x\n"));
+        assert_eq!(Dictionary::Orig, sniff(b""));
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        tar.extend_from_slice(diff);
+        assert_eq!(Dictionary::Orig, sniff(&tar));
+
+        let mut gzip = b"\x1f\x8b\x08\x00\n".to_vec();
+        gzip.extend_from_slice(diff);
+        assert_eq!(Dictionary::Orig, sniff(&gzip));
+    }
+
+    #[test]
+    fn train() {
+        let samples: Vec<Vec<u8>> = (0..400)
+            .map(|i| {
+                format!(
+                    "/* Copyright (C) {} The Authors */\n#include <stdio.h>\n\n\
+                     int function_{}(int argc, char **argv) {{\n  return {};\n}}\n\0\x01\x02",
+                    2000 + i % 20,
+                    i,
+                    i * 7
+                )
+                .into_bytes()
+            })
+            .collect();
+
+        let dictionary = super::train(samples.iter().map(|s| &s[..]).collect(), 4096).unwrap();
+        assert!(dictionary.len() <= 4096);
+        assert!(super::id(&dictionary).is_some());
+        assert!(super::DEFAULT_TRAINED_SIZE > dictionary.len());
+    }
+
+    #[test]
+    fn frame_ids() {
+        assert_eq!(
+            Some(0x0403_0201),
+            super::frame_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x03, 0x50, 1, 2, 3, 4][..]).unwrap()
+        );
+        assert_eq!(
+            Some(0x07),
+            super::frame_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x21, 0x07][..]).unwrap()
+        );
+        assert_eq!(
+            None,
+            super::frame_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x50][..]).unwrap()
+        );
+        assert!(super::frame_id(&b"hello world"[..]).is_err());
+
+        let mut skipped = Vec::new();
+        crate::skippable::write(&mut skipped, b"metadata").unwrap();
+        skipped.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0x21, 0x07]);
+        assert_eq!(Some(0x07), super::frame_id(skipped.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn written_frame() {
+        let mut encoder = zstd::Encoder::with_dictionary(Vec::new(), 3, DIFF).unwrap();
+        encoder.write_all(b"--- a/foo\n+++ b/foo\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(super::id(DIFF), super::frame_id(&compressed[..]).unwrap());
+    }
+}
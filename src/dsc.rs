@@ -0,0 +1,197 @@
+use std::io;
+use std::io::Read;
+
+use cast::u64;
+
+/// The value of a simple, single-line field in a Debian control file, such as a `.dsc`.
+pub fn field<'d>(dsc: &'d str, name: &str) -> Option<&'d str> {
+    dsc.lines().find_map(|line| {
+        let (key, value) = split_field(line)?;
+        if key.eq_ignore_ascii_case(name) {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// The most a compressed dsc can expand to; real ones are a few KiB, so anything near this is a
+/// decompression bomb, not a dsc.
+const MAX_DECOMPRESSED: u64 = 4 * 1024 * 1024;
+
+/// The dsc as it was fetched, decompressed, if it was gzipped or xz'd, as some mirrors serve it.
+pub fn decompressed(fetched: Vec<u8>) -> io::Result<Vec<u8>> {
+    let dsc = match fetched.as_slice() {
+        [0x1f, 0x8b, ..] => bounded(
+            flate2::read::GzDecoder::new(fetched.as_slice()),
+            fetched.len(),
+        )?,
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => {
+            bounded(xz2::read::XzDecoder::new(fetched.as_slice()), fetched.len())?
+        }
+        _ => return Ok(fetched),
+    };
+    if u64(dsc.len()) > MAX_DECOMPRESSED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decompresses to more than {} bytes", MAX_DECOMPRESSED),
+        ));
+    }
+    Ok(dsc)
+}
+
+/// Up to one byte more than `MAX_DECOMPRESSED` of `decoder`, into room for four times the
+/// `compressed_len` it's reading, or for just that much, if it's less; a plain dsc allocates none.
+fn bounded<R: Read>(decoder: R, compressed_len: usize) -> io::Result<Vec<u8>> {
+    let mut dsc = Vec::with_capacity(
+        compressed_len
+            .saturating_mul(4)
+            .min(MAX_DECOMPRESSED as usize + 1),
+    );
+    decoder.take(MAX_DECOMPRESSED + 1).read_to_end(&mut dsc)?;
+    Ok(dsc)
+}
+
+/// Whether `dsc` plausibly is a Debian control file, and not, say, the HTML of a 404 page.
+///
+/// It's signed, sometimes, so the fields might not start at the top.
+pub fn looks_valid(dsc: &str) -> bool {
+    ["Format", "Source", "Files", "Checksums-Sha256"]
+        .iter()
+        .any(|name| field(dsc, name).is_some())
+}
+
+fn split_field(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with(|c: char| c.is_whitespace()) {
+        return None;
+    }
+    let colon = line.find(':')?;
+    Some((&line[..colon], line[colon + 1..].trim()))
+}
+
+/// A file listed in a multi-line field, e.g. `Files`, or `Checksums-Sha256`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listed<'d> {
+    pub digest: &'d str,
+    pub size: u64,
+    pub name: &'d str,
+}
+
+/// The files listed, one per continuation line, in the field `name`. Unparseable lines are skipped.
+pub fn listed<'d>(dsc: &'d str, name: &str) -> Vec<Listed<'d>> {
+    let mut lines = dsc.lines();
+    let found = lines
+        .by_ref()
+        .any(|line| split_field(line).is_some_and(|(key, _)| key.eq_ignore_ascii_case(name)));
+    if !found {
+        return Vec::new();
+    }
+
+    lines
+        .take_while(|line| line.starts_with(|c: char| c.is_whitespace()))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let listed = Listed {
+                digest: parts.next()?,
+                size: parts.next()?.parse().ok()?,
+                name: parts.next()?,
+            };
+            Some(listed).filter(|_| parts.next().is_none())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Write;
+
+    use super::Listed;
+
+    const DSC: &str = "Format: 3.0 (quilt)
+Source: foo
+Version: 1.2-3
+Checksums-Sha256:
+ 0123abcd 1234 foo_1.2.orig.tar.gz
+ 4567ef01 56 foo_1.2-3.debian.tar.xz
+Files:
+ 89ab 1234 foo_1.2.orig.tar.gz
+";
+
+    #[test]
+    fn fields() {
+        assert_eq!(Some("foo"), super::field(DSC, "source"));
+        assert_eq!(Some(""), super::field(DSC, "Files"));
+        assert_eq!(None, super::field(DSC, "Missing"));
+    }
+
+    #[test]
+    fn decompressed() {
+        assert_eq!(
+            DSC.as_bytes(),
+            super::decompressed(DSC.as_bytes().to_vec())
+                .unwrap()
+                .as_slice()
+        );
+        assert!(super::decompressed(Vec::new()).unwrap().is_empty());
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(DSC.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            DSC.as_bytes(),
+            super::decompressed(gzip.clone()).unwrap().as_slice()
+        );
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(DSC.as_bytes()).unwrap();
+        assert_eq!(
+            DSC.as_bytes(),
+            super::decompressed(xz.finish().unwrap())
+                .unwrap()
+                .as_slice()
+        );
+
+        assert!(super::decompressed(gzip[..gzip.len() / 2].to_vec()).is_err());
+
+        let mut bomb = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        bomb.write_all(&vec![b'a'; super::MAX_DECOMPRESSED as usize + 1])
+            .unwrap();
+        let err = super::decompressed(bomb.finish().unwrap()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("more than"), "{}", err);
+    }
+
+    #[test]
+    fn looks_valid() {
+        assert!(super::looks_valid(DSC));
+        assert!(super::looks_valid(
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\nSource: foo\n"
+        ));
+        assert!(!super::looks_valid(
+            "<html>\n<head><title>404 Not Found</title></head>\n</html>\n"
+        ));
+        assert!(!super::looks_valid(""));
+    }
+
+    #[test]
+    fn listed() {
+        assert_eq!(
+            vec![
+                Listed {
+                    digest: "0123abcd",
+                    size: 1234,
+                    name: "foo_1.2.orig.tar.gz",
+                },
+                Listed {
+                    digest: "4567ef01",
+                    size: 56,
+                    name: "foo_1.2-3.debian.tar.xz",
+                },
+            ],
+            super::listed(DSC, "Checksums-Sha256")
+        );
+        assert_eq!(1, super::listed(DSC, "files").len());
+        assert_eq!(Vec::<Listed>::new(), super::listed(DSC, "Checksums-Sha1"));
+    }
+}
@@ -0,0 +1,117 @@
+//! Re-encodes the records of an `.annul` as length-delimited protobuf messages,
+//! as described in `proto/annul.proto`.
+
+use std::io::Read;
+use std::io::Write;
+
+use failure::Error;
+use protobuf::CodedOutputStream;
+
+use crate::reader::AnnulEntry;
+use crate::reader::AnnulReader;
+
+/// Write each entry as a varint length, then an `annul.Entry` message, returning the count.
+pub fn proto<R: Read, W: Write>(mut entries: AnnulReader<R>, mut out: W) -> Result<u64, Error> {
+    let mut written = 0;
+    let mut message = Vec::new();
+
+    while let Some(mut entry) = entries.next_entry()? {
+        entries.resolve_duplicate(&mut entry)?;
+
+        message.clear();
+        encode(&entry, &mut message)?;
+
+        let mut os = CodedOutputStream::new(&mut out);
+        os.write_raw_varint64(message.len() as u64)?;
+        os.write_raw_bytes(&message)?;
+        os.flush()?;
+
+        written += 1;
+    }
+
+    out.flush()?;
+    Ok(written)
+}
+
+fn encode(entry: &AnnulEntry, into: &mut Vec<u8>) -> Result<(), Error> {
+    let mut os = CodedOutputStream::vec(into);
+    for component in entry.path_components() {
+        os.write_bytes(1, component)?;
+    }
+    if let Some(flag) = entry.content_flag() {
+        os.write_uint32(2, u32::from(flag))?;
+    }
+    if let Some(status) = entry.children_status() {
+        os.write_uint32(3, u32::from(status))?;
+    }
+    if !entry.data.is_empty() {
+        os.write_bytes(4, &entry.data)?;
+    }
+    if entry.bucketed() {
+        os.write_bool(5, true)?;
+    }
+    if let Some(mime) = entry.mime() {
+        os.write_string(6, mime)?;
+    }
+    if let Some(mode) = entry.mode() {
+        os.write_uint32(7, mode)?;
+    }
+    if let Some(len) = entry.original_len().filter(|&len| 0 != len) {
+        os.write_uint64(8, len)?;
+    }
+    if let Some(kind) = entry.kind().filter(|&kind| 0 != kind) {
+        os.write_uint32(9, u32::from(kind))?;
+    }
+    if let Some(reason) = entry.reason() {
+        os.write_string(10, reason)?;
+    }
+    if entry.unsafe_path() {
+        os.write_bool(11, true)?;
+    }
+    if let Some(hash) = entry.content_hash() {
+        os.write_bytes(12, hash)?;
+    }
+    if let Some(index) = entry.archive_index().filter(|&index| 0 != index) {
+        os.write_uint32(13, index)?;
+    }
+    os.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::AnnulReader;
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    #[test]
+    fn length_delimited() {
+        let meta = meta()
+            .children(3)
+            .original_len(2)
+            .name("a")
+            .name("b")
+            .build();
+        let mut data = Vec::new();
+        record(&mut data, &meta, b"hi");
+
+        let mut out = Vec::new();
+        assert_eq!(
+            1,
+            super::proto(AnnulReader::new(&data[..]), &mut out).unwrap()
+        );
+        assert_eq!(
+            &[
+                18, // length
+                0x0a, 1, b'a', // path
+                0x0a, 1, b'b', // path
+                0x10, 0, // content
+                0x18, 3, // children
+                0x22, 2, b'h', b'i', // data
+                0x40, 2, // original length
+                0x48, 1, // kind
+            ][..],
+            out.as_slice()
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! The first two bytes of a record's meta, by name; see `proto/annul.proto` for what they mean.
+
+/// How the content was stored: the low five bits of the first byte of the meta. The bits above
+/// say whether it has a MIME type, is bucketed, or is shared.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ContentState {
+    Unchanged = 0,
+    /// strings made it smaller
+    Strings = 1,
+    /// there was nothing to store, e.g. for a directory
+    NoContent = 2,
+    /// a compressed leaf, decompressed, then strings'd
+    Expanded = 9,
+    /// text, with the BOM removed, and its CRLFs too, with `--normalize-crlf`
+    Normalised = 10,
+    /// strings'd, but never compared, with `--skip-length-compare`
+    NotCompared = 11,
+    /// the data is the hash of the content, which is in a `--shard-raw` directory
+    Sharded = 13,
+    /// the data is the index of an earlier record with the same content
+    Duplicate = 14,
+    Empty = 15,
+    /// larger than `--max-file-size`, so not read
+    Skipped = 16,
+    /// Latin-1 text, transcoded to UTF-8, then strings'd
+    Transcoded = 17,
+    /// the data is where it points
+    Symlink = 18,
+    /// started like a known binary type, with `--skip-binary`, so not read
+    Binary = 19,
+    /// exactly as it was, with `--raw`
+    Raw = 20,
+}
+
+impl ContentState {
+    pub const ALL: &'static [ContentState] = &[
+        ContentState::Unchanged,
+        ContentState::Strings,
+        ContentState::NoContent,
+        ContentState::Expanded,
+        ContentState::Normalised,
+        ContentState::NotCompared,
+        ContentState::Sharded,
+        ContentState::Duplicate,
+        ContentState::Empty,
+        ContentState::Skipped,
+        ContentState::Transcoded,
+        ContentState::Symlink,
+        ContentState::Binary,
+        ContentState::Raw,
+    ];
+
+    /// The state with this number, without the bits for how it's stored.
+    pub fn from_u8(flag: u8) -> Option<ContentState> {
+        ContentState::ALL
+            .iter()
+            .cloned()
+            .find(|&state| flag == u8::from(state))
+    }
+}
+
+impl From<ContentState> for u8 {
+    fn from(state: ContentState) -> u8 {
+        state as u8
+    }
+}
+
+/// What happened when splayers tried to unpack the content: the second byte of the meta.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChildrenState {
+    Unnecessary = 3,
+    Unrecognised = 4,
+    TooNested = 5,
+    /// followed by why, in the meta
+    Unsupported = 6,
+    /// followed by why, in the meta; also used for content which couldn't be read at all
+    Error = 7,
+    Success = 8,
+    /// could be opened, but isn't in `--descend-formats`
+    NotDescended = 12,
+}
+
+impl ChildrenState {
+    pub const ALL: &'static [ChildrenState] = &[
+        ChildrenState::Unnecessary,
+        ChildrenState::Unrecognised,
+        ChildrenState::TooNested,
+        ChildrenState::Unsupported,
+        ChildrenState::Error,
+        ChildrenState::Success,
+        ChildrenState::NotDescended,
+    ];
+
+    pub fn from_u8(status: u8) -> Option<ChildrenState> {
+        ChildrenState::ALL
+            .iter()
+            .cloned()
+            .find(|&state| status == u8::from(state))
+    }
+
+    /// Is there a reason recorded, after the MIME type?
+    pub fn has_reason(self) -> bool {
+        matches!(self, ChildrenState::Unsupported | ChildrenState::Error)
+    }
+}
+
+impl From<ChildrenState> for u8 {
+    fn from(state: ChildrenState) -> u8 {
+        state as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChildrenState;
+    use super::ContentState;
+
+    #[test]
+    fn numbers() {
+        let content: Vec<u8> = ContentState::ALL.iter().map(|&s| u8::from(s)).collect();
+        assert_eq!(
+            vec![0, 1, 2, 9, 10, 11, 13, 14, 15, 16, 17, 18, 19, 20],
+            content
+        );
+        let children: Vec<u8> = ChildrenState::ALL.iter().map(|&s| u8::from(s)).collect();
+        assert_eq!(vec![3, 4, 5, 6, 7, 8, 12], children);
+
+        for n in 0..=255 {
+            assert_eq!(content.contains(&n), ContentState::from_u8(n).is_some());
+            assert_eq!(children.contains(&n), ChildrenState::from_u8(n).is_some());
+        }
+        assert_eq!(Some(ContentState::Duplicate), ContentState::from_u8(14));
+        assert_eq!(
+            Some(ChildrenState::NotDescended),
+            ChildrenState::from_u8(12)
+        );
+    }
+}
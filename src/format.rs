@@ -0,0 +1,73 @@
+use std::io;
+use std::io::Read;
+
+/// Names accepted by `--descend-formats`.
+pub const KNOWN: &[&str] = &[
+    "7z", "ar", "bzip2", "cpio", "gzip", "rpm", "tar", "xz", "zip", "zstd",
+];
+
+/// Guess the container format of some content from its first few hundred bytes.
+///
+/// This only needs to agree with `splayers` on the things it can open; anything else is `None`.
+pub fn detect<R: Read>(src: R) -> io::Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(262);
+    src.take(262).read_to_end(&mut header)?;
+    Ok(detect_header(&header))
+}
+
+/// Is `src` in one of these `formats`, as named in `KNOWN`?
+pub fn one_of<R: Read>(formats: &[String], src: R) -> io::Result<bool> {
+    Ok(detect(src)?.is_some_and(|found| formats.iter().any(|f| f == found)))
+}
+
+pub fn detect_header(header: &[u8]) -> Option<&'static str> {
+    Some(match header {
+        [0x1f, 0x8b, ..] => "gzip",
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => "xz",
+        [b'B', b'Z', b'h', ..] => "bzip2",
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => "zstd",
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => "zip",
+        [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c, ..] => "7z",
+        [b'!', b'<', b'a', b'r', b'c', b'h', b'>', b'\n', ..] => "ar",
+        [0xed, 0xab, 0xee, 0xdb, ..] => "rpm",
+        [b'0', b'7', b'0', b'7', b'0', b'1', ..]
+        | [b'0', b'7', b'0', b'7', b'0', b'2', ..]
+        | [b'0', b'7', b'0', b'7', b'0', b'7', ..]
+        | [0xc7, 0x71, ..] => "cpio",
+        _ if header.len() >= 262 && header[257..262] == *b"ustar" => "tar",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    fn tar_header() -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[..7].copy_from_slice(b"foo.txt");
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Some("tar"), super::detect(tar_header().as_slice()).unwrap());
+        assert_eq!(
+            Some("zip"),
+            super::detect(&b"PK\x03\x04\x14\x00\x00\x00"[..]).unwrap()
+        );
+        assert_eq!(
+            Some("ar"),
+            super::detect(&b"!<arch>\ndebian-binary   "[..]).unwrap()
+        );
+        assert_eq!(None, super::detect(&b"hello world"[..]).unwrap());
+        assert_eq!(None, super::detect(&b""[..]).unwrap());
+    }
+
+    #[test]
+    fn descend_tar_not_zip() {
+        let allowed = ["tar".to_string()];
+        let descend = |data: &[u8]| super::one_of(&allowed, data).unwrap();
+        assert!(descend(&tar_header()));
+        assert!(!descend(b"PK\x03\x04\x14\x00\x00\x00"));
+    }
+}
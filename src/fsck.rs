@@ -0,0 +1,330 @@
+//! Checks that an `.annul`'s records are well-formed, trusting nothing in them: an fsck for the
+//! format. Unlike `reader`, it doesn't interpret anything, so problems are reported with where
+//! they are, rather than what they'd have broken.
+
+use std::cmp;
+use std::io;
+use std::io::Read;
+
+use byteorder::ByteOrder;
+use byteorder::ReadBytesExt;
+use byteorder::LE;
+use cast::u64;
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+
+use crate::flags::ChildrenState;
+use crate::flags::ContentState;
+use crate::reader;
+use crate::Checksummed;
+
+/// The highest kind of entry; see `proto/annul.proto`.
+const MAX_KIND: u8 = 8;
+
+pub struct Report {
+    /// how many records were entirely well-formed
+    pub records: u64,
+    /// the first thing wrong, if anything was
+    pub problem: Option<Problem>,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    /// where the bad record (or the header) starts, in the decompressed stream
+    pub offset: u64,
+    pub message: String,
+}
+
+/// Walk every record of a decompressed stream, from its header, stopping at the first problem.
+pub fn check<R: Read>(from: R) -> Report {
+    let mut from = Tracked {
+        inner: from,
+        pos: 0,
+    };
+    let mut records = 0;
+    let problem = walk(&mut from, &mut records)
+        .err()
+        .map(|(offset, e)| Problem {
+            offset,
+            message: e.to_string(),
+        });
+    Report { records, problem }
+}
+
+fn walk<R: Read>(from: &mut Tracked<R>, records: &mut u64) -> Result<(), (u64, Error)> {
+    reader::read_header(&mut *from).map_err(|e| (0, e))?;
+
+    let mut starts = Vec::new();
+    loop {
+        let start = from.pos;
+        if !record(from, &starts).map_err(|e| (start, e))? {
+            return Ok(());
+        }
+        starts.push(start);
+        *records += 1;
+    }
+}
+
+/// Check the record at the current position, or return false at a clean end of the stream,
+/// including after an index, which must agree with where the records, at `starts`, were.
+fn record<R: Read>(from: &mut Tracked<R>, starts: &[u64]) -> Result<bool, Error> {
+    let mut len = [0u8; 8];
+    let found = read_up_to(from, &mut len)?;
+    if 0 == found {
+        return Ok(false);
+    }
+    ensure!(8 == found, "{} trailing bytes after the last record", found);
+    let total_len = LE::read_u64(&len);
+
+    if crate::INDEX_MARKER == total_len {
+        let offsets = reader::read_index(&mut *from).map_err(|e| format_err!("index: {}", e))?;
+        if let Some(i) =
+            (0..cmp::max(offsets.len(), starts.len())).find(|&i| offsets.get(i) != starts.get(i))
+        {
+            bail!(
+                "the index says entry {} is at {:?}, but it's at {:?}",
+                i,
+                offsets.get(i),
+                starts.get(i)
+            );
+        }
+        return Ok(false);
+    }
+
+    let meta_len = from
+        .read_u64::<LE>()
+        .map_err(|e| format_err!("reading meta_len: {}", e))?;
+    ensure!(
+        meta_len.checked_add(12).is_some_and(|min| min <= total_len),
+        "total_len ({}) is less than 8 + meta_len ({}) + 4",
+        total_len,
+        meta_len
+    );
+    let data_len = total_len - 12 - meta_len;
+
+    let mut meta = Vec::new();
+    (&mut *from).take(meta_len).read_to_end(&mut meta)?;
+    ensure!(
+        u64(meta.len()) == meta_len,
+        "meta_len ({}) runs past the end of the stream",
+        meta_len
+    );
+    check_meta(&meta)?;
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&meta_len.to_le_bytes());
+    crc.update(&meta);
+    let mut data = Checksummed {
+        inner: io::sink(),
+        crc,
+    };
+    let copied = io::copy(&mut (&mut *from).take(data_len), &mut data)?;
+    ensure!(
+        copied == data_len,
+        "data_len ({}) runs past the end of the stream, after {} bytes",
+        data_len,
+        copied
+    );
+
+    let recorded = from
+        .read_u32::<LE>()
+        .map_err(|e| format_err!("reading CRC: {}", e))?;
+    let calculated = data.crc.finalize();
+    ensure!(
+        recorded == calculated,
+        "recorded CRC {:08x}, but the content has {:08x}",
+        recorded,
+        calculated
+    );
+
+    Ok(true)
+}
+
+fn check_meta(meta: &[u8]) -> Result<(), Error> {
+    ensure!(
+        meta.len() >= 51,
+        "meta is only {} bytes, too short for the flags, mode, length, kind, hash and index",
+        meta.len()
+    );
+
+    let content = meta[0] & 0x1f;
+    ensure!(
+        ContentState::from_u8(content).is_some(),
+        "unknown content flag {}",
+        content
+    );
+    let children = ChildrenState::from_u8(meta[1])
+        .ok_or_else(|| format_err!("unknown children status {}", meta[1]))?;
+    // the top bit says the name was made safe
+    let kind = meta[14] & 0x7f;
+    ensure!(kind <= MAX_KIND, "unknown kind {}", kind);
+
+    let rest = if 0 != meta[0] & 0x20 {
+        let mime = 52 + usize::from(*meta.get(51).ok_or_else(|| format_err!("no MIME length"))?);
+        ensure!(
+            meta.len() >= mime,
+            "the MIME type runs past the end of the meta"
+        );
+        &meta[mime..]
+    } else {
+        &meta[51..]
+    };
+
+    let names = if children.has_reason() {
+        ensure!(rest.len() >= 2, "no reason length");
+        let reason = 2 + usize::from(LE::read_u16(&rest[..2]));
+        ensure!(
+            rest.len() >= reason,
+            "the reason runs past the end of the meta"
+        );
+        &rest[reason..]
+    } else {
+        rest
+    };
+
+    let names = reader::split_names(names)
+        .ok_or_else(|| format_err!("the path runs past the end of the meta"))?;
+    ensure!(!names.is_empty(), "no path");
+    Ok(())
+}
+
+/// Like `read_exact`, but it's fine to find nothing at all, or less than the whole buffer.
+fn read_up_to<R: Read>(from: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match from.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(len) => pos += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+/// Knows how far through the stream it is.
+struct Tracked<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.pos += u64(len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    fn stream(records: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        crate::write_header(&mut data).unwrap();
+        for (meta, content) in records {
+            record(&mut data, meta, content);
+        }
+        data
+    }
+
+    fn file() -> Vec<u8> {
+        meta().original_len(5).name("a.c").build()
+    }
+
+    fn dir() -> Vec<u8> {
+        meta().flags(2).children(8).name("pkg").build()
+    }
+
+    fn problem(data: &[u8]) -> (u64, u64, String) {
+        let report = super::check(data);
+        let problem = report.problem.expect("should be broken");
+        (report.records, problem.offset, problem.message)
+    }
+
+    #[test]
+    fn valid() {
+        let (file, dir) = (file(), dir());
+        let report = super::check(&stream(&[(&file[..], b"hello"), (&dir[..], b"")])[..]);
+        assert!(report.problem.is_none(), "{:?}", report.problem);
+        assert_eq!(2, report.records);
+
+        let mut indexed = stream(&[(&file[..], b"hello"), (&dir[..], b"")]);
+        let second = 5 + 16 + file.len() as u64 + 5 + 4;
+        crate::write_index(&mut indexed, &[5, second]).unwrap();
+        let report = super::check(&indexed[..]);
+        assert!(report.problem.is_none(), "{:?}", report.problem);
+        assert_eq!(2, report.records);
+
+        let mut wrong = stream(&[(&file[..], b"hello"), (&dir[..], b"")]);
+        crate::write_index(&mut wrong, &[5, second + 1]).unwrap();
+        let (_, _, message) = problem(&wrong);
+        assert!(message.contains("entry 1 is at"), "{}", message);
+    }
+
+    #[test]
+    fn broken() {
+        let (file, dir) = (file(), dir());
+        let good = stream(&[(&file[..], b"hello"), (&dir[..], b"")]);
+        // the second record starts after the header, and the first's lengths, meta, data and CRC
+        let second = 5 + 16 + file.len() as u64 + 5 + 4;
+
+        let mut trailing = good.clone();
+        trailing.extend_from_slice(b"\x01\x02");
+        let (records, offset, message) = problem(&trailing);
+        assert_eq!(2, records);
+        assert_eq!(second + 16 + dir.len() as u64 + 4, offset);
+        assert!(message.contains("2 trailing bytes"), "{}", message);
+
+        let mut truncated = good.clone();
+        truncated.truncate(good.len() - 2);
+        let (records, offset, message) = problem(&truncated);
+        assert_eq!((1, second), (records, offset));
+        assert!(message.contains("reading CRC"), "{}", message);
+
+        let (_, offset, message) = problem(&stream(&[(&file[..], b"hello"), (b"\x03\x08", b"")]));
+        assert_eq!(second, offset);
+        assert!(message.contains("too short"), "{}", message);
+
+        let mut flag = dir.clone();
+        flag[0] = 0x05;
+        let (_, _, message) = problem(&stream(&[(&flag, b"")]));
+        assert!(message.contains("unknown content flag 5"), "{}", message);
+
+        let mut children = dir.clone();
+        children[1] = 0x09;
+        let (_, _, message) = problem(&stream(&[(&children, b"")]));
+        assert!(message.contains("unknown children status 9"), "{}", message);
+
+        let mut kind = dir.clone();
+        kind[14] = 0x09;
+        let (_, _, message) = problem(&stream(&[(&kind, b"")]));
+        assert!(message.contains("unknown kind 9"), "{}", message);
+
+        let mut reason = dir.clone();
+        reason[1] = 0x07;
+        reason[51] = 0x09;
+        let (_, _, message) = problem(&stream(&[(&reason, b"")]));
+        assert!(message.contains("reason runs past"), "{}", message);
+
+        let (_, _, message) = problem(&stream(&[(&dir[..dir.len() - 1], b"")]));
+        assert!(message.contains("path runs past"), "{}", message);
+
+        let (_, _, message) = problem(&stream(&[(&dir[..51], b"")]));
+        assert!(message.contains("no path"), "{}", message);
+
+        let mut flipped = good.clone();
+        flipped[5 + 16 + file.len()] ^= 0x20;
+        let (records, offset, message) = problem(&flipped);
+        assert_eq!((0, 5), (records, offset));
+        assert!(message.contains("recorded CRC"), "{}", message);
+
+        let (records, offset, message) = problem(b"ANUL\x01");
+        assert_eq!((0, 0), (records, offset));
+        assert!(message.contains("version 1"), "{}", message);
+    }
+}
@@ -0,0 +1,95 @@
+//! Searches the content of an `.annul`'s records, like `grep -F` over the original members.
+
+use std::io::Read;
+use std::io::Write;
+
+use failure::Error;
+
+use crate::flags::ContentState;
+use crate::reader::AnnulReader;
+
+/// Write `path:line` for each line (or strings run) containing `pattern`, returning the count.
+///
+/// Content compressed with a `--bucket-dict` dictionary, or stored in a `--shard-raw`
+/// directory, isn't in the file, so can't be searched.
+pub fn literal<R: Read, W: Write>(
+    mut entries: AnnulReader<R>,
+    pattern: &[u8],
+    mut out: W,
+) -> Result<u64, Error> {
+    let mut found = 0;
+
+    while let Some(mut entry) = entries.next_entry()? {
+        entries.resolve_duplicate(&mut entry)?;
+        if entry.bucketed() || Some(ContentState::Sharded) == entry.content_state() {
+            continue;
+        }
+
+        // strings separates runs with a 0, and they're just as much lines as the real ones are
+        for line in entry.data.split(|&b| b'\n' == b || 0 == b) {
+            if !contains(line, pattern) {
+                continue;
+            }
+
+            out.write_all(&entry.path_components().join(&b'/'))?;
+            out.write_all(b":")?;
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+            found += 1;
+        }
+    }
+
+    out.flush()?;
+    Ok(found)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::AnnulReader;
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    #[test]
+    fn literal() {
+        let mut data = Vec::new();
+        record(
+            &mut data,
+            &meta().flags(1).name("pkg").name("a.c").build(),
+            b"int main() {\n  return 0;\n}\0main\0",
+        );
+        record(
+            &mut data,
+            &meta().flags(2).children(8).name("pkg").build(),
+            b"",
+        );
+        record(
+            &mut data,
+            &meta().name("pkg").name("README").build(),
+            b"nothing to see\n",
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(
+            2,
+            super::literal(AnnulReader::new(&data[..]), b"main", &mut out).unwrap()
+        );
+        assert_eq!(
+            "pkg/a.c:int main() {\npkg/a.c:main\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(
+            0,
+            super::literal(AnnulReader::new(&data[..]), b"absent", &mut out).unwrap()
+        );
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,4417 @@
+//! Packages a source archive: unpacks it, runs each member through `strings`, and writes the
+//! records into a single zstd stream. `main.rs` is a command line wrapper around this.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::Range;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+use cast::u16;
+use cast::u32;
+use cast::u64;
+use cast::u8;
+use failure::bail;
+use failure::ensure;
+use failure::err_msg;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use log::debug;
+use log::info;
+use log::warn;
+use rayon::prelude::*;
+use sha2::Digest;
+use sha2::Sha256;
+use splayers::Entry;
+use splayers::ItemType;
+use splayers::Ownership;
+use splayers::Status;
+
+use crate::flags::ChildrenState;
+use crate::flags::ContentState;
+
+pub mod buckets;
+pub mod dict;
+mod dsc;
+#[cfg(feature = "proto")]
+pub mod export;
+pub mod flags;
+pub mod format;
+pub mod fsck;
+pub mod grep;
+pub mod ls;
+mod manifest;
+mod mime;
+pub mod reader;
+pub mod recompress;
+mod shard;
+mod skippable;
+pub mod strings;
+#[cfg(test)]
+mod testing;
+mod text;
+
+/// Options for how sources are packaged, matching the command line flags; all off by default.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// decompress gzip/xz/bzip2 leaves that splayers left alone, before the strings pass
+    pub expand_leaf_compression: bool,
+    /// give up on expanding a leaf if it inflates beyond this
+    pub max_member_bytes: u64,
+    /// record members larger than this without their content, instead of reading them
+    pub max_file_size: Option<u64>,
+    /// give up on the source once its records, before they're compressed, would be larger; that's
+    /// not marked as any `Failure`, as it'll be just as large next time
+    pub max_output_size: Option<u64>,
+    /// also write the downloaded files, untouched, into this directory
+    pub tee_raw: Option<PathBuf>,
+    /// what to call the output, with placeholders for `output_name`, if not `DEFAULT_OUTPUT_NAME`
+    pub output_name: Option<String>,
+    /// strip BOMs from members which are entirely text
+    pub normalize_text: bool,
+    /// with `normalize_text`, fold those members' CRLFs to LF, too
+    pub normalize_crlf: bool,
+    /// convert members which look like Latin-1 text to UTF-8 before the strings pass
+    pub transcode_latin1: bool,
+    /// redo existing outputs which weren't compressed with the current dictionary
+    pub overwrite_stale_dicts: bool,
+    /// redo existing outputs, whatever they were compressed with
+    pub force: bool,
+    /// strings each member twice, once to find its length and again straight into the output,
+    /// instead of once into a temporary file; twice the CPU for none of the disk
+    pub skip_length_compare: bool,
+    /// store members exactly as they were, instead of stringsing them
+    pub raw: bool,
+    /// only recurse into members in these formats, if set
+    pub descend_formats: Option<Vec<String>>,
+    /// record members starting with one of these, e.g. `DEFAULT_BINARY_MAGICS`, without their
+    /// content, instead of stringsing them, if set
+    pub skip_binary: Option<Vec<Vec<u8>>>,
+    /// write repeated content once, and refer back to it after that
+    pub dedup_members: bool,
+    /// store leaf members, raw, in this content-addressed directory, instead of in the output
+    pub shard_raw: Option<PathBuf>,
+    /// start the output with a skippable frame saying where it came from
+    pub provenance: bool,
+    /// also write a JSON index of the records, and where their data is, to this file
+    pub manifest: Option<PathBuf>,
+    /// end the stream with where each record starts, so they can be found without reading it all
+    pub index: bool,
+    /// record members whose temp file can't be opened as errors, instead of failing
+    pub skip_unreadable_members: bool,
+    /// compress members in these buckets on their own, with their own dictionary
+    pub buckets: buckets::Registry,
+    /// record a guessed MIME type for leaf members
+    pub detect_mime: bool,
+    /// abandon the source if it's still being worked on at this point, before each member, and
+    /// each write of a download; partial output of the file being worked on is never persisted,
+    /// there's no strict mode to choose otherwise, but any the dsc listed before it, which were
+    /// finished, are kept
+    pub deadline: Option<Instant>,
+    /// the zstd level to compress the output at, if not `DEFAULT_LEVEL`
+    pub level: Option<i32>,
+    /// compress with this dictionary, instead of the embedded one picked for the source
+    pub dictionary: Option<Vec<u8>>,
+    /// how many members to strings, or files a dsc lists to package, at once; one per CPU, if
+    /// not set, and `1` for all of it on the calling thread
+    pub threads: Option<usize>,
+    /// how much of a member to read at once, if not `DEFAULT_READ_CHUNK`
+    pub read_chunk_size: Option<usize>,
+    /// write the output to stdout, using the destination only for temporary files
+    pub stdout: bool,
+    /// print the entries found in the source to stdout, instead of writing an output
+    pub list: bool,
+    /// what the records are compressed with; zstd, with a dictionary, unless told otherwise
+    pub compression: Compression,
+}
+
+/// The container around the records. Only zstd uses a dictionary, or `Config::level`; the others
+/// are at their own default levels, for consumers which can't read zstd.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    Zstd,
+    Xz,
+    Gzip,
+    /// The records as they are, header and all.
+    None,
+}
+
+/// The start of every (decompressed) `.annul`, before the first record.
+pub const FORMAT_MAGIC: [u8; 4] = *b"ANUL";
+
+/// Follows `FORMAT_MAGIC`. Bumped whenever the records change in a way an older reader would
+/// misunderstand; readers must reject versions they don't know, rather than guess.
+///
+/// Each record is a `u64` (LE) length of the rest of the record, a `u64` length of the meta,
+/// the meta, the data, then a `u32` (LE) CRC32 of everything after the total length, up to it.
+/// In version 10, the meta is:
+///  * the content flag, e.g. `1` for changed by strings, with bits for how it's stored,
+///  * the children status, e.g. `8` for unpacked,
+///  * the unix mode bits, as a `u32` (LE), or `UNKNOWN_MODE`,
+///  * the length of the member before strings, as a `u64` (LE), or `0` if it has no content,
+///  * the kind of entry it was in the archive, e.g. `2` for a directory; see `item_kind`, with
+///    `0x80` set if its name had to be made safe to extract; see `contained`,
+///  * the sha256 of the member, as it was in the archive, or 32 `0`s if it wasn't read, e.g. as
+///    it has no content, or was skipped, or is a duplicate, which the earlier record has,
+///  * where it was amongst its siblings in the archive, before they were sorted, as a `u32` (LE),
+///  * a `u8` length then a MIME type, with `--detect-mime`,
+///  * for children statuses `6` and `7`, a `u16` (LE) length then why it couldn't be unpacked,
+///  * the names of the containers it's in, then its own name, each a `u16` (LE) length then the
+///    name, up to the end of the meta, so no byte of a name can be mistaken for the end of it.
+///
+/// A symbolic link has content flag `18`, and its target, the bytes exactly as they were in the
+/// archive, with no terminator, as the data.
+///
+/// With `--index`, the last record is followed by `INDEX_MARKER`, where the next record's total
+/// length would be, the offset of each record from the start of the stream, header included,
+/// then how many records there were, all as `u64`s (LE), then a CRC32 of the offsets and count.
+/// The count's at a fixed distance from the end, so the offsets can be found from there.
+///
+/// Version 1 had no mode, version 2 no original length, version 3 no CRC, version 4 no kind,
+/// version 5 no reason, version 6 no index, version 7 ended each name with a 0, instead of
+/// prefixing its length, version 8 had no hash, and version 9 no archive index.
+pub const FORMAT_VERSION: u8 = 10;
+
+/// Where a record would start, marking the start of the `--index` footer instead.
+pub const INDEX_MARKER: u64 = u64::MAX;
+
+/// The length of what `write_header` writes, which the offsets in the stream include.
+const HEADER_LEN: u64 = 5;
+
+/// Stored in place of the mode bits when the archive didn't say what they were.
+pub const UNKNOWN_MODE: u32 = u32::MAX;
+
+/// Write `FORMAT_MAGIC` and `FORMAT_VERSION`, which `reader::open` checks for.
+pub fn write_header<W: Write>(mut out: W) -> io::Result<()> {
+    out.write_all(&FORMAT_MAGIC)?;
+    out.write_all(&[FORMAT_VERSION])
+}
+
+/// How much of a member is read at once, unless told otherwise.
+pub const DEFAULT_READ_CHUNK: usize = 16 * 1024;
+
+/// What the output is called, in the destination directory, unless told otherwise.
+pub const DEFAULT_OUTPUT_NAME: &str = "{name}.annul";
+
+/// The starts of files which are known to be binary, for `skip_binary`, unless told otherwise:
+/// images, compiled code and fonts, which strings would only find noise in.
+pub const DEFAULT_BINARY_MAGICS: &[&[u8]] = &[
+    b"\x89PNG\r\n\x1a\n",
+    b"\xff\xd8\xff",
+    b"GIF87a",
+    b"GIF89a",
+    b"\x00\x00\x01\x00",
+    b"\x7fELF",
+    b"!<arch>\n",
+    b"\xce\xfa\xed\xfe",
+    b"\xcf\xfa\xed\xfe",
+    b"\xca\xfe\xba\xbe",
+    b"\x00\x01\x00\x00\x00",
+    b"OTTO",
+    b"wOFF",
+    b"wOF2",
+];
+
+/// A magic for `skip_binary`, written in hex, e.g. `89504e47`.
+pub fn parse_magic(hex: &str) -> Result<Vec<u8>, Error> {
+    ensure!(
+        !hex.is_empty() && hex.len().is_multiple_of(2),
+        "magic {:?} must be an even number of hex digits",
+        hex
+    );
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format_err!("magic {:?} isn't hex", hex))
+        })
+        .collect()
+}
+
+/// The zstd level outputs are compressed at, unless told otherwise.
+pub const DEFAULT_LEVEL: i32 = 8;
+
+/// A zstd compression level, checked to be one zstd accepts.
+pub fn zstd_level(level: &str) -> Result<i32, Error> {
+    let parsed = level
+        .parse::<i32>()
+        .with_context(|_| format_err!("zstd level {:?} isn't a number", level))?;
+    ensure!(
+        (1..=22).contains(&parsed),
+        "zstd level {} is out of range: must be 1-22",
+        parsed
+    );
+    Ok(parsed)
+}
+
+/// Roughly what went wrong with a source, so whoever's running a batch can tell what's worth
+/// trying again; marks the chain of an error from `process`, for `Failure::of` to find.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// fetching the dsc, or a file it lists, e.g. the mirror's down
+    Download,
+    /// a fetched file isn't the size, or doesn't have the hash, the dsc says it should
+    Checksum,
+    /// splayers couldn't make sense of the fetched file, so it'll never work
+    Unpack,
+    /// the output couldn't be written, e.g. the disk's full
+    Write,
+    /// `Config::deadline` passed, whatever was being done at the time
+    Timeout,
+}
+
+impl Failure {
+    /// The marker closest to the top of `e`'s chain, if there is one, unless there's a timeout
+    /// anywhere in it: what it interrupted, e.g. a download, or a write, only failed because of it.
+    pub fn of(e: &Error) -> Option<Failure> {
+        let mut markers = e
+            .iter_chain()
+            .filter_map(|cause| cause.downcast_ref::<failure::Context<Failure>>())
+            .map(|context| *context.get_context());
+        let first = markers.next()?;
+        if Failure::Timeout == first || markers.any(|marker| Failure::Timeout == marker) {
+            return Some(Failure::Timeout);
+        }
+        Some(first)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Download => "download",
+            Failure::Checksum => "checksum",
+            Failure::Unpack => "unpack",
+            Failure::Write => "write",
+            Failure::Timeout => "timeout",
+        })
+    }
+}
+
+/// What `process` wrote.
+pub struct Processed {
+    pub package: String,
+    pub version: String,
+    pub out: PathBuf,
+    pub stats: Stats,
+    pub compressed_size: u64,
+}
+
+/// Totals for the records written for a source.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub entries: u64,
+    /// the members, as they were in the archive
+    pub input_bytes: u64,
+    /// the records, before the output is compressed
+    pub stream_bytes: u64,
+    /// containers splayers wouldn't open, as they were too deep, so whose contents are missing
+    pub too_nested: u64,
+    /// entries whose names could have escaped from an extraction, so were recorded without that
+    pub unsafe_paths: u64,
+    /// the records with each content flag, without the shared, bucketed and MIME bits
+    pub by_flag: [u64; 32],
+}
+
+impl Stats {
+    /// The records with content flag `state`.
+    pub fn with_state(&self, state: ContentState) -> u64 {
+        self.by_flag[usize::from(u8::from(state))]
+    }
+
+    /// content flag `1`: strings made them smaller
+    pub fn changed(&self) -> u64 {
+        self.with_state(ContentState::Strings)
+    }
+
+    /// content flag `0`
+    pub fn unchanged(&self) -> u64 {
+        self.with_state(ContentState::Unchanged)
+    }
+
+    /// content flag `2`: there was nothing to store, e.g. for a directory
+    pub fn no_content(&self) -> u64 {
+        self.with_state(ContentState::NoContent)
+    }
+
+    /// content flag `14`: the same as an earlier member, so only a reference to it was stored
+    pub fn duplicates(&self) -> u64 {
+        self.with_state(ContentState::Duplicate)
+    }
+}
+
+/// What processing one of the files a dsc lists involves, worked out before fetching it.
+pub struct Plan {
+    /// as the dsc lists it
+    pub name: String,
+    pub sub_url: url::Url,
+    /// picked by the name, if it says; otherwise, it's picked by the content, once it's fetched
+    pub dictionary: Option<dict::Dictionary>,
+    /// the file to write, in the destination directory
+    pub output_name: String,
+}
+
+/// The plan for the file `name`, which is listed in the dsc at `dsc_url`, so is next to it. The
+/// output's name is `template` filled in, as by `output_name`, with `dsc_hash` as the `{hash}`.
+pub fn plan(dsc_url: &url::Url, name: &str, template: &str, dsc_hash: &str) -> Result<Plan, Error> {
+    ensure!(
+        !name.is_empty() && !name.contains('/') && ".." != name,
+        "the dsc lists {:?}, which isn't a plain file name",
+        name
+    );
+
+    Ok(Plan {
+        output_name: output_name(template, name, dsc_hash)?,
+        dictionary: dict::Dictionary::from_name(name),
+        sub_url: dsc_url.join(name)?,
+        name: name.to_string(),
+    })
+}
+
+/// The names of the files the dsc lists, from `Checksums-Sha256`, or `Files` for an old one,
+/// without their signatures, which aren't worth packaging.
+fn listed_files(dsc: &str) -> Vec<&str> {
+    let mut listed = dsc::listed(dsc, "Checksums-Sha256");
+    if listed.is_empty() {
+        listed = dsc::listed(dsc, "Files");
+    }
+
+    listed
+        .into_iter()
+        .map(|listed| listed.name)
+        .filter(|name| !name.ends_with(".asc"))
+        .collect()
+}
+
+/// Fill in an output name template: `{name}` is the name of the file, as the dsc lists it,
+/// and `{hash}` the sha256 of the dsc. The result can have directories in, but can't leave `dest`.
+pub fn output_name(template: &str, name: &str, hash: &str) -> Result<String, Error> {
+    let mut filled = String::with_capacity(template.len() + name.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let end = start
+            + rest[start..]
+                .find('}')
+                .ok_or_else(|| format_err!("unclosed {{ in output name {:?}", template))?;
+        match &rest[start + 1..end] {
+            "name" => filled.push_str(name),
+            "hash" => filled.push_str(hash),
+            other => bail!("unknown {{{}}} in output name {:?}", other, template),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+
+    ensure!(
+        !filled.is_empty()
+            && Path::new(&filled)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_))),
+        "output name {:?} must be a relative path, without any ..",
+        filled
+    );
+    Ok(filled)
+}
+
+/// How big the dsc says `name` is, if it lists it.
+fn listed_size(dsc: &str, name: &str) -> Option<u64> {
+    ["Checksums-Sha256", "Files"].iter().find_map(|field| {
+        dsc::listed(dsc, field)
+            .into_iter()
+            .find(|listed| name == listed.name)
+            .map(|listed| listed.size)
+    })
+}
+
+/// If the dsc lists `name`, check that `path` has the size and digest it says.
+fn verify(dsc: &str, name: &str, path: &Path) -> Result<(), Error> {
+    let find = |field| {
+        dsc::listed(dsc, field)
+            .into_iter()
+            .find(|listed| name == listed.name)
+    };
+
+    let sha256 = find("Checksums-Sha256");
+    let expected = match sha256.as_ref().or(find("Files").as_ref()) {
+        Some(listed) => listed.size,
+        None => return Ok(()),
+    };
+
+    let actual = fs::metadata(path)?.len();
+    ensure!(
+        expected == actual,
+        "{}: expected {} bytes, but downloaded {}",
+        name,
+        expected,
+        actual
+    );
+
+    if let Some(sha256) = sha256 {
+        let actual = shard::hash(fs::File::open(path)?)?;
+        ensure!(
+            sha256.digest.eq_ignore_ascii_case(&actual),
+            "{}: expected sha256 {}, but downloaded {}",
+            name,
+            sha256.digest,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+/// `src` as a url: it's either one already, with any scheme, or a path to a local file.
+fn source_url(src: &str) -> Result<url::Url, Error> {
+    if let Ok(url) = url::Url::parse(src) {
+        return Ok(url);
+    }
+
+    let path = env::current_dir()?.join(src);
+    url::Url::from_file_path(&path).map_err(|()| format_err!("not a url or a path: {:?}", src))
+}
+
+/// Fetch the dsc at `src`, then package each of the files it lists into an output of its own,
+/// returning those which weren't already done, or only listed.
+pub fn process(src: &str, dest: &Path, config: &Config) -> Result<Vec<Processed>, Error> {
+    let src_url = source_url(src)?;
+    let name = src_url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .ok_or(err_msg("no end path"))?
+        .to_string();
+
+    let tee_raw = config.tee_raw.as_deref();
+    if let Some(tee_raw) = tee_raw {
+        fs::create_dir_all(tee_raw)?;
+    }
+
+    let mut dsc = Vec::new();
+    download(src_url.as_str(), &name, &mut dsc, tee_raw, config.deadline)
+        .context(Failure::Download)
+        .with_context(|_| err_msg("downloading dsc"))?;
+    info!("{}: downloaded dsc, {} bytes", src, dsc.len());
+    let dsc = dsc::decompressed(dsc).with_context(|_| format_err!("{}: decompressing dsc", src))?;
+    ensure!(
+        dsc::looks_valid(&String::from_utf8_lossy(&dsc)),
+        "{}: not a valid dsc, is the url right?",
+        src
+    );
+
+    let listed = String::from_utf8_lossy(&dsc);
+    let template = config.output_name.as_deref().unwrap_or(DEFAULT_OUTPUT_NAME);
+    let hash = shard::hash(&dsc[..])?;
+    let plans = listed_files(&listed)
+        .into_iter()
+        .map(|name| plan(&src_url, name, template, &hash))
+        .collect::<Result<Vec<Plan>, Error>>()?;
+    ensure!(!plans.is_empty(), "{}: the dsc doesn't list any files", src);
+
+    if plans.len() > 1 {
+        ensure!(
+            !config.stdout,
+            "{}: only one file can be written to stdout",
+            src
+        );
+        ensure!(
+            config.manifest.is_none(),
+            "{}: only one file can have a --manifest",
+            src
+        );
+        ensure!(
+            config
+                .output_name
+                .as_ref()
+                .is_none_or(|template| template.contains("{name}")),
+            "{}: the output name needs a {{name}}, to tell the files apart",
+            src
+        );
+    }
+
+    let processed = if Some(1) == config.threads || config.list {
+        // on this thread, one at a time, stopping at the first which fails
+        plans
+            .iter()
+            .map(|plan| process_file(src, dest, config, &dsc, plan))
+            .collect::<Result<Vec<_>, Error>>()?
+    } else {
+        // one pool for the whole source: its files, and the strings pass of each, all share it;
+        // zero is rayon's default: one thread per CPU
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads.unwrap_or(0))
+            .build()?;
+        let results: Vec<Result<Option<Processed>, Error>> = pool.install(|| {
+            plans
+                .par_iter()
+                .map(|plan| {
+                    // a panic is only the end of this file, like any other failure
+                    panic::catch_unwind(AssertUnwindSafe(|| {
+                        process_file(src, dest, config, &dsc, plan)
+                    }))
+                    .map_err(|_| err_msg("panic"))
+                    .with_context(|_| format_err!("processing {}", plan.name))?
+                })
+                .collect()
+        });
+        results.into_iter().collect::<Result<Vec<_>, Error>>()?
+    };
+    Ok(processed.into_iter().flatten().collect())
+}
+
+/// Fetch and package one of the files the `dsc` at `src` lists, or return `None` if it has
+/// already been done, or was only listed.
+fn process_file(
+    src: &str,
+    dest: &Path,
+    config: &Config,
+    dsc: &[u8],
+    plan: &Plan,
+) -> Result<Option<Processed>, Error> {
+    let path = plan.name.as_str();
+    let out = dest.join(&plan.output_name);
+
+    let dictionary: Option<Cow<'static, [u8]>> = match (&config.dictionary, plan.dictionary) {
+        (Some(dictionary), _) => Some(Cow::Owned(dictionary.clone())),
+        (None, Some(named)) => Some(Cow::Borrowed(named.bytes())),
+        (None, None) => None,
+    };
+
+    // we can't know which a nameless file would get without fetching it, so either will do
+    let current: Vec<&[u8]> = match &dictionary {
+        Some(dictionary) => vec![&dictionary[..]],
+        None => vec![dict::ORIG, dict::DIFF],
+    };
+
+    if already_done(&out, &current, config)? {
+        info!("{}: already done, as {:?}", src, out);
+        return Ok(None);
+    }
+
+    let tee_raw = config.tee_raw.as_deref();
+
+    // named for the dsc, too, so two sources with the same file can't write to it at once
+    let partial = dest.join(format!(".{}.{}.partial", &shard::hash(dsc)?[..16], path));
+    let tmp = download_resumable(
+        plan.sub_url.as_str(),
+        path,
+        &partial,
+        listed_size(&String::from_utf8_lossy(dsc), path),
+        dest,
+        tee_raw,
+        config.deadline,
+    )
+    .context(Failure::Download)
+    .with_context(|_| format_err!("downloading {}", path))?;
+    info!(
+        "{}: downloaded {}, {} bytes",
+        src,
+        path,
+        fs::metadata(&tmp)?.len()
+    );
+
+    verify(&String::from_utf8_lossy(dsc), path, &tmp).context(Failure::Checksum)?;
+
+    let dictionary = match dictionary {
+        Some(dictionary) => dictionary,
+        None => Cow::Borrowed(dict::Dictionary::sniff(fs::File::open(&tmp)?)?.bytes()),
+    };
+
+    if config.list {
+        let unpack = splayers::Unpack::unpack_into(&tmp, dest)
+            .context(Failure::Unpack)
+            .with_context(|_| err_msg("unpacking failed"))?;
+        match *unpack.status() {
+            splayers::Status::Success(ref entries) => list(entries, &mut io::stdout().lock())?,
+            ref other => return Err(not_an_archive(other)),
+        };
+        return Ok(None);
+    }
+
+    let provenance = if config.provenance {
+        Some(provenance(src, &dictionary))
+    } else {
+        None
+    };
+
+    let overwrite = out.exists();
+    let out = if config.stdout {
+        PathBuf::from("-")
+    } else {
+        // the template can put the output in directories of its own
+        fs::create_dir_all(out.parent().ok_or(err_msg("root?"))?).context(Failure::Write)?;
+        out
+    };
+
+    let (stats, compressed_size) = if config.stdout {
+        let stdout = io::stdout();
+        let counted = Counted {
+            inner: io::BufWriter::new(stdout.lock()),
+            written: 0,
+        };
+        let (mut counted, stats) = unarchive_into(
+            &tmp,
+            dest,
+            counted,
+            &dictionary,
+            provenance.as_deref(),
+            config,
+        )?;
+        counted.flush()?;
+        (stats, counted.written)
+    } else {
+        let stats = unarchive_with(
+            &tmp,
+            &out,
+            &dictionary,
+            provenance.as_deref(),
+            overwrite,
+            config,
+        )?;
+        (stats, fs::metadata(&out)?.len())
+    };
+    info!(
+        "{}: wrote {} entries to {:?}, {} bytes",
+        src, stats.entries, out, compressed_size
+    );
+
+    let dsc = String::from_utf8_lossy(dsc);
+    Ok(Some(Processed {
+        package: dsc::field(&dsc, "Source").unwrap_or_default().to_string(),
+        version: dsc::field(&dsc, "Version").unwrap_or_default().to_string(),
+        compressed_size,
+        out,
+        stats,
+    }))
+}
+
+/// Is there already an output at `out`, which doesn't need redoing?
+fn already_done(out: &Path, current: &[&[u8]], config: &Config) -> Result<bool, Error> {
+    Ok(out.exists()
+        && !(config.list
+            || config.stdout
+            || config.force
+            || (config.overwrite_stale_dicts
+                && Compression::Zstd == config.compression
+                && stale_dictionary(out, current)?)))
+}
+
+/// How many times to retry a download which fails part way, if `$ANNUL_DOWNLOAD_RETRIES` isn't set.
+const DEFAULT_DOWNLOAD_RETRIES: u32 = 2;
+
+/// How long, in seconds, to wait to connect, or for the next read, if `$ANNUL_DOWNLOAD_TIMEOUT`
+/// isn't set.
+const DEFAULT_DOWNLOAD_TIMEOUT: u64 = 60;
+
+/// Fetch `url` into `out`, and, if asked, simultaneously into a file named `name` in `tee_raw`.
+///
+/// Connection problems are retried, with backoff, starting again from scratch each time, unless
+/// `out` can be carried on from, and the server will send just the rest. Anything else,
+/// including an HTTP error status or a stalled mirror, fails straight away.
+fn download<W: Restart>(
+    url: &str,
+    name: &str,
+    mut out: W,
+    tee_raw: Option<&Path>,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    let retries = match env::var("ANNUL_DOWNLOAD_RETRIES") {
+        Ok(retries) => retries
+            .parse::<u32>()
+            .with_context(|_| format_err!("parsing ANNUL_DOWNLOAD_RETRIES: {:?}", retries))?,
+        Err(_) => DEFAULT_DOWNLOAD_RETRIES,
+    };
+
+    let timeout = Duration::from_secs(match env::var("ANNUL_DOWNLOAD_TIMEOUT") {
+        Ok(timeout) => timeout
+            .parse::<u64>()
+            .with_context(|_| format_err!("parsing ANNUL_DOWNLOAD_TIMEOUT: {:?}", timeout))?,
+        Err(_) => DEFAULT_DOWNLOAD_TIMEOUT,
+    });
+
+    let mut attempt = 0;
+    loop {
+        let kept = out.resume()?;
+        let result = {
+            let mut out = Deadline {
+                inner: &mut out,
+                deadline,
+            };
+
+            match tee_raw {
+                Some(dir) => {
+                    let raw = fs::File::create(dir.join(name))?;
+                    let result = fetch(url, &mut Tee { out, raw }, kept, timeout);
+                    if result.is_err() {
+                        // it'd look like a whole file, to anyone picking through the tee
+                        fs::remove_file(dir.join(name))?;
+                    }
+                    result
+                }
+                None => fetch(url, &mut out, kept, timeout),
+            }
+        };
+
+        match result {
+            Ok(Fetched::Rest) => return Ok(()),
+            Ok(Fetched::All) if 0 == kept => return Ok(()),
+            Ok(Fetched::All) => {
+                info!("{}: can't resume, so downloaded it all again", url);
+                out.drop_kept(kept)?;
+                return Ok(());
+            }
+            Ok(Fetched::Past) => {
+                // what we had must have been for some other version of the file
+                warn!("{}: nothing after {} bytes, starting again", url, kept);
+                out.restart()?;
+            }
+            Err(ref e) if attempt < retries && transient(e) && !expired(deadline) => {
+                attempt += 1;
+                warn!("{}: retrying, attempt {}, after: {}", url, attempt + 1, e);
+                std::thread::sleep(Duration::from_secs(1 << attempt));
+            }
+            Err(e) if expired(deadline) => return Err(e.context(Failure::Timeout).into()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Fetch `url` into `partial`, carrying on after whatever an earlier attempt, or run, left in
+/// it, then, once it's complete, move it to a temporary file in `dest`, for unpacking.
+///
+/// `expected` is the size the dsc says it has, so a finished `partial` isn't fetched again.
+fn download_resumable(
+    url: &str,
+    name: &str,
+    partial: &Path,
+    expected: Option<u64>,
+    dest: &Path,
+    tee_raw: Option<&Path>,
+    deadline: Option<Instant>,
+) -> Result<tempfile::TempPath, Error> {
+    let mut file = Partial(
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(partial)
+            .with_context(|_| format_err!("opening {:?}", partial))?,
+    );
+
+    let len = file.0.metadata()?.len();
+    let downloaded = match expected {
+        Some(expected) if len == expected => {
+            info!("{}: already downloaded", url);
+            Ok(())
+        }
+        Some(expected) if len > expected => file
+            .restart()
+            .map_err(Error::from)
+            .and_then(|()| download(url, name, &mut file, None, deadline)),
+        _ => download(url, name, &mut file, None, deadline),
+    };
+
+    if let Err(e) = downloaded {
+        // something is worth carrying on from next time, but nothing is just litter
+        if 0 == file.0.metadata()?.len() {
+            fs::remove_file(partial)?;
+        }
+        return Err(e);
+    }
+
+    // resuming would leave only the end in a tee, so it's copied whole
+    if let Some(dir) = tee_raw {
+        fs::copy(partial, dir.join(name))?;
+    }
+
+    let tmp = tempfile::NamedTempFile::new_in(dest)?.into_temp_path();
+    fs::rename(partial, &tmp)?;
+    Ok(tmp)
+}
+
+/// Somewhere a download can be written, and started again after a failed attempt.
+trait Restart: Write {
+    fn restart(&mut self) -> io::Result<()>;
+
+    /// Get ready for another attempt, returning how much of what's there can be kept: nothing,
+    /// unless it can be carried on from.
+    fn resume(&mut self) -> io::Result<u64> {
+        self.restart()?;
+        Ok(0)
+    }
+
+    /// The server sent everything, after the `kept` bytes `resume` said were already there.
+    fn drop_kept(&mut self, _kept: u64) -> io::Result<()> {
+        unreachable!("only kept anything if resume said so")
+    }
+}
+
+impl Restart for Vec<u8> {
+    fn restart(&mut self) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl Restart for fs::File {
+    fn restart(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+impl<R: Restart + ?Sized> Restart for &mut R {
+    fn restart(&mut self) -> io::Result<()> {
+        (**self).restart()
+    }
+
+    fn resume(&mut self) -> io::Result<u64> {
+        (**self).resume()
+    }
+
+    fn drop_kept(&mut self, kept: u64) -> io::Result<()> {
+        (**self).drop_kept(kept)
+    }
+}
+
+/// A file which is carried on from, instead of being started again.
+struct Partial(fs::File);
+
+impl Write for Partial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Restart for Partial {
+    fn restart(&mut self) -> io::Result<()> {
+        self.0.restart()
+    }
+
+    fn resume(&mut self) -> io::Result<u64> {
+        self.0.seek(SeekFrom::End(0))
+    }
+
+    fn drop_kept(&mut self, kept: u64) -> io::Result<()> {
+        // shuffle everything after the kept bytes down over them
+        let len = self.0.seek(SeekFrom::End(0))?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut from = kept;
+        while from < len {
+            self.0.seek(SeekFrom::Start(from))?;
+            let read = self.0.read(&mut buf)?;
+            if 0 == read {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.0.seek(SeekFrom::Start(from - kept))?;
+            self.0.write_all(&buf[..read])?;
+            from += u64(read);
+        }
+        self.0.set_len(len - kept)?;
+        self.0.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+/// Is this the kind of network trouble which might go away if we just try again?
+fn transient(e: &Error) -> bool {
+    e.iter_chain().any(|cause| {
+        let io = match cause.downcast_ref::<http_req::error::Error>() {
+            Some(http_req::error::Error::IO(e)) => Some(e),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+        io.is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::Interrupted
+            )
+        })
+    })
+}
+
+/// What a fetch which asked for everything after some bytes it already had got.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Fetched {
+    /// everything, whether asking for only the rest was ignored, or there was nothing to skip
+    All,
+    /// only what came after the bytes it already had
+    Rest,
+    /// nothing, as there's nothing after those bytes
+    Past,
+}
+
+/// `file:` urls are read straight off the disk; anything else goes to `http_req`, which gives up
+/// if connecting, or any read, takes longer than `timeout`. Only what's after the first `from`
+/// bytes is asked for, if any are.
+fn fetch<W: Write>(url: &str, out: &mut W, from: u64, timeout: Duration) -> Result<Fetched, Error> {
+    let parsed = url::Url::parse(url)?;
+    if "file" == parsed.scheme() {
+        let path = parsed
+            .to_file_path()
+            .map_err(|()| format_err!("not a local path: {:?}", url))?;
+        let mut src = fs::File::open(&path).with_context(|_| format_err!("opening {:?}", path))?;
+        if 0 == from {
+            io::copy(&mut src, out)?;
+            return Ok(Fetched::All);
+        }
+        if from >= src.metadata()?.len() {
+            return Ok(Fetched::Past);
+        }
+        src.seek(SeekFrom::Start(from))?;
+        io::copy(&mut src, out)?;
+        return Ok(Fetched::Rest);
+    }
+
+    let uri = url.parse::<http_req::uri::Uri>()?;
+    let mut request = http_req::request::Request::new(&uri);
+    request
+        .connect_timeout(Some(timeout))
+        .read_timeout(Some(timeout));
+    if 0 != from {
+        request.header("Range", &format!("bytes={}-", from));
+    }
+
+    let response = match request.send(out) {
+        Err(http_req::error::Error::IO(ref e)) if timed_out(e) => {
+            bail!("{}: download timed out after {:?}", url, timeout)
+        }
+        other => other?,
+    };
+    let status = u16::from(response.status_code());
+    Ok(match status {
+        206 if 0 != from => Fetched::Rest,
+        416 if 0 != from => Fetched::Past,
+        _ => {
+            ensure!(
+                response.status_code().is_success(),
+                "{}: HTTP {}",
+                url,
+                status
+            );
+            Fetched::All
+        }
+    })
+}
+
+/// Did the socket give up waiting, as opposed to, say, `Deadline` refusing a write?
+fn timed_out(e: &io::Error) -> bool {
+    // unix reports a receive timeout as EAGAIN, windows as a timeout
+    e.raw_os_error().is_some()
+        && matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+}
+
+struct Tee<W, C> {
+    out: W,
+    raw: C,
+}
+
+impl<W: Write, C: Write> Write for Tee<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.out.write(buf)?;
+        self.raw.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.raw.flush()
+    }
+}
+
+/// Fails writes once `deadline` has passed, so a slow download can be abandoned.
+struct Deadline<W> {
+    inner: W,
+    deadline: Option<Instant>,
+}
+
+impl<W: Write> Write for Deadline<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if expired(self.deadline) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "out of time"));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Remembers how much has been written through it.
+struct Counted<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written += u64(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Keeps a CRC32 of everything written through it.
+pub(crate) struct Checksummed<W> {
+    pub(crate) inner: W,
+    pub(crate) crc: crc32fast::Hasher,
+}
+
+impl<W> Checksummed<W> {
+    pub(crate) fn new(inner: W) -> Checksummed<W> {
+        Checksummed {
+            inner,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Notes whether a write through it has failed, so such an error can be told apart from one
+/// reading whatever was being written.
+struct Watched<W> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W> Watched<W> {
+    fn new(inner: W) -> Watched<W> {
+        Watched {
+            inner,
+            failed: false,
+        }
+    }
+}
+
+impl<W: Write> Write for Watched<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf);
+        self.failed |= written.is_err();
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let flushed = self.inner.flush();
+        self.failed |= flushed.is_err();
+        flushed
+    }
+}
+
+/// A sha256, as bytes.
+type Hash = [u8; 32];
+
+/// Keeps a sha256 of everything read through it, so the content can be hashed as it's strings'd.
+struct Hashed<R> {
+    inner: R,
+    sha256: Sha256,
+}
+
+impl<R> Hashed<R> {
+    fn new(inner: R) -> Hashed<R> {
+        Hashed {
+            inner,
+            sha256: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> Hash {
+        digest(self.sha256)
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.sha256.input(&buf[..len]);
+        Ok(len)
+    }
+}
+
+fn digest(sha256: Sha256) -> Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&sha256.result());
+    hash
+}
+
+/// A hash from `shard::store`, as bytes.
+fn unhex(hash: &str) -> Result<Hash, Error> {
+    ensure!(64 == hash.len(), "hash {:?} isn't a sha256", hash);
+    let mut raw = [0; 32];
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16)
+            .with_context(|_| format_err!("hash {:?} isn't hex", hash))?;
+    }
+    Ok(raw)
+}
+
+/// Write a record whose meta and data are already in memory, e.g. one read back by `reader`.
+pub(crate) fn write_record_bytes<W: Write>(out: W, meta: &[u8], data: &[u8]) -> io::Result<()> {
+    let mut out = Checksummed::new(out);
+    out.inner
+        .write_u64::<LE>(8 + u64(meta.len()) + u64(data.len()) + 4)?;
+    out.write_u64::<LE>(u64(meta.len()))?;
+    out.write_all(meta)?;
+    out.write_all(data)?;
+    let crc = out.crc.finalize();
+    out.inner.write_u32::<LE>(crc)
+}
+
+/// Write the `--index` footer, for records which start at `offsets`, after the last of them.
+pub(crate) fn write_index<W: Write>(mut out: W, offsets: &[u64]) -> io::Result<()> {
+    out.write_u64::<LE>(INDEX_MARKER)?;
+    let mut out = Checksummed::new(out);
+    for &offset in offsets {
+        out.write_u64::<LE>(offset)?;
+    }
+    out.write_u64::<LE>(u64(offsets.len()))?;
+    let crc = out.crc.finalize();
+    out.inner.write_u32::<LE>(crc)
+}
+
+/// Has `deadline`, if there is one, passed?
+pub fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Was `existing` compressed with something other than one of `dictionaries`?
+fn stale_dictionary(existing: &Path, dictionaries: &[&[u8]]) -> Result<bool, Error> {
+    // unreadable frames are stale, too; they certainly weren't written by this version
+    let found = dict::frame_id(fs::File::open(existing)?).ok().flatten();
+    Ok(!dictionaries
+        .iter()
+        .any(|&dictionary| found == dict::id(dictionary)))
+}
+
+/// In the style of a dsc, so `dsc::field` can read it back.
+fn provenance(src: &str, dictionary: &[u8]) -> Vec<u8> {
+    let mut provenance = format!(
+        "Source-Url: {}\nAnnul-Version: {}\n",
+        src,
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some(id) = dict::id(dictionary) {
+        provenance.push_str(&format!("Dictionary-Id: {:08x}\n", id));
+    }
+    provenance.into_bytes()
+}
+
+/// Package the archive at `src`, which has already been fetched, into `dest`, which must not
+/// exist, as the command line would without any options, returning what was written.
+pub fn unarchive(src: &Path, dest: &Path, dictionary: &[u8]) -> Result<Stats, Error> {
+    unarchive_with(src, dest, dictionary, None, false, &Config::default())
+}
+
+/// `unarchive`, with options. A `provenance` frame is written first,
+/// if there is one, and an existing `dest` is only replaced if `overwrite` is set.
+pub fn unarchive_with(
+    src: &Path,
+    dest: &Path,
+    dictionary: &[u8],
+    provenance: Option<&[u8]>,
+    overwrite: bool,
+    config: &Config,
+) -> Result<Stats, Error> {
+    let root = dest.parent().ok_or(err_msg("root?"))?;
+
+    let out = tempfile_fast::PersistableTempFile::new_in(root).context(Failure::Write)?;
+    let (out, stats) = unarchive_into(src, root, out, dictionary, provenance, config)?;
+
+    persist(out, dest, overwrite).context(Failure::Write)?;
+
+    Ok(stats)
+}
+
+/// `unarchive_with`, writing the output to `out`, which is handed back once it's finished,
+/// instead of into a file. `root` is where the unpacked members are kept while that happens.
+fn unarchive_into<W: Write>(
+    src: &Path,
+    root: &Path,
+    mut out: W,
+    dictionary: &[u8],
+    provenance: Option<&[u8]>,
+    config: &Config,
+) -> Result<(W, Stats), Error> {
+    debug!("unpacking {:?} into {:?}", src, root);
+    let started = Instant::now();
+    let unpack = splayers::Unpack::unpack_into(src, root)
+        .context(Failure::Unpack)
+        .with_context(|_| err_msg("unpacking failed"))?;
+    info!("unpacked {:?} in {:?}", src, started.elapsed());
+
+    if let Some(provenance) = provenance {
+        ensure!(
+            Compression::Zstd == config.compression,
+            "provenance can only be recorded in zstd output"
+        );
+        skippable::write(&mut out, provenance)?;
+    }
+
+    let mut out = Encoder::new(out, dictionary, config)?;
+    write_header(&mut out).context(Failure::Write)?;
+
+    let stats = match *unpack.status() {
+        splayers::Status::Success(ref entries) => output_marked(entries, &mut out, config)?,
+        ref other => return Err(not_an_archive(other)),
+    };
+
+    Ok((out.finish().context(Failure::Write)?, stats))
+}
+
+/// `output_with`, for a whole archive, with an error marked as a `Failure::Write` only if it was
+/// writing to `out` which failed: a member which can't be read, or a limit which was exceeded,
+/// won't be any different next time.
+fn output_marked<W: Write>(
+    entries: &[Entry],
+    out: &mut W,
+    config: &Config,
+) -> Result<Stats, Error> {
+    let mut watched = Watched::new(out);
+    match output_with(entries, &[], &mut watched, config) {
+        Ok(stats) => Ok(stats),
+        Err(e) if watched.failed => Err(e.context(Failure::Write).into()),
+        Err(e) => Err(e),
+    }
+}
+
+/// What splayers made of a source which wasn't an archive after all.
+fn not_an_archive(status: &Status) -> Error {
+    format_err!("expecting top level archive, not: {:?}", status)
+        .context(Failure::Unpack)
+        .into()
+}
+
+/// Whichever encoder `Config::compression` asked for.
+enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(out: W, dictionary: &[u8], config: &Config) -> io::Result<Encoder<W>> {
+        Ok(match config.compression {
+            Compression::Zstd => {
+                let level = config.level.unwrap_or(DEFAULT_LEVEL);
+                Encoder::Zstd(zstd::Encoder::with_dictionary(out, level, dictionary)?)
+            }
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(out, 6)),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                out,
+                flate2::Compression::default(),
+            )),
+            Compression::None => Encoder::None(out),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::None(out) => Ok(out),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::None(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::None(out) => out.flush(),
+        }
+    }
+}
+
+/// Move the finished `out` to `dest`, only once its content, and then its name, are on disk,
+/// so a crash can't leave a partial output which looks done.
+fn persist(
+    mut out: tempfile_fast::PersistableTempFile,
+    dest: &Path,
+    overwrite: bool,
+) -> Result<(), Error> {
+    out.flush()?;
+    out.sync_all()?;
+
+    if overwrite {
+        out.persist_by_rename(dest)
+    } else {
+        out.persist_noclobber(dest)
+    }
+    .map_err(|e| e.error)?;
+
+    if let Some(dir) = dest.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Write the records for `entries`, which were found inside the containers named by `paths`,
+/// returning totals for what was written.
+///
+/// Each container's entries are written sorted by path, so the same tree always gives the
+/// same output, regardless of the order splayers found things in.
+pub fn output<W: Write>(
+    entries: &[Entry],
+    paths: &[Box<[u8]>],
+    out: &mut W,
+) -> Result<Stats, Error> {
+    output_with(entries, paths, out, &Config::default())
+}
+
+/// `output`, with options.
+pub fn output_with<W: Write>(
+    entries: &[Entry],
+    paths: &[Box<[u8]>],
+    out: &mut W,
+    config: &Config,
+) -> Result<Stats, Error> {
+    let mut prefixes = Vec::with_capacity(paths.len() * 128);
+    for path in paths {
+        push_name(&mut prefixes, path)?;
+    }
+
+    let dedup = if config.dedup_members {
+        let mut dedup = Dedup::default();
+        dedup.count(entries, config)?;
+        Some(dedup)
+    } else {
+        None
+    };
+
+    let mut scratch = Scratch {
+        meta: Vec::with_capacity(4096),
+        root_prefix: 0..prefixes.len(),
+        prefixes,
+        index: 0,
+        dedup,
+        stats: Stats::default(),
+        manifest: config.manifest.as_ref().map(|_| Vec::new()),
+        offsets: if config.index { Some(Vec::new()) } else { None },
+    };
+
+    output_into(entries, &mut scratch, out, config)?;
+
+    if let Some(offsets) = &scratch.offsets {
+        write_index(&mut *out, offsets)?;
+    }
+
+    if let (Some(path), Some(mut json)) = (config.manifest.as_ref(), scratch.manifest) {
+        manifest::finish(&mut json);
+        let root = path.parent().ok_or(err_msg("root?"))?;
+        let mut file = tempfile_fast::PersistableTempFile::new_in(root)?;
+        file.write_all(&json)?;
+        persist(file, path, true).with_context(|_| format_err!("writing manifest {:?}", path))?;
+    }
+
+    Ok(scratch.stats)
+}
+
+/// Write the path of every entry in the tree, and what splayers made of it, a line each,
+/// returning how many there were. Nothing is strings'd, or even opened.
+pub fn list<W: Write>(entries: &[Entry], out: &mut W) -> Result<u64, Error> {
+    let mut listed = 0;
+    let mut name_prefix = Vec::new();
+    let mut stack = vec![Frame::new(entries, 0)];
+
+    while let Some(frame) = stack.last_mut() {
+        let entry = match frame.entries.next() {
+            Some((_, entry)) => entry,
+            None => {
+                name_prefix.truncate(frame.parent_len);
+                stack.pop();
+                continue;
+            }
+        };
+
+        out.write_all(&name_prefix)?;
+        out.write_all(&entry.local.path)?;
+        match &entry.children {
+            Status::Unnecessary => writeln!(out, "\tUnnecessary")?,
+            Status::Unrecognised => writeln!(out, "\tUnrecognised")?,
+            Status::TooNested => writeln!(out, "\tTooNested")?,
+            Status::Unsupported(kind) => writeln!(out, "\tUnsupported: {:?}", kind)?,
+            Status::Error(msg) => writeln!(out, "\tError: {}", msg)?,
+            Status::Success(entries) => writeln!(out, "\tSuccess: {} entries", entries.len())?,
+        }
+
+        listed += 1;
+
+        if let Status::Success(entries) = &entry.children {
+            let parent_len = name_prefix.len();
+            name_prefix.extend_from_slice(&entry.local.path);
+            name_prefix.push(b'/');
+            stack.push(Frame::new(entries, parent_len));
+        }
+    }
+
+    Ok(listed)
+}
+
+/// Buffers shared by every entry in the tree, instead of being allocated for each one.
+struct Scratch {
+    meta: Vec<u8>,
+    /// the paths of the containers each planned entry is inside, each prefixed with its length,
+    /// once for every container, not every entry, which the entries have ranges of
+    prefixes: Vec<u8>,
+    /// the range of `prefixes` for the containers named by `output_with`'s `paths`
+    root_prefix: Range<usize>,
+    /// the number of records written so far, i.e. the index of the next one
+    index: u64,
+    dedup: Option<Dedup>,
+    stats: Stats,
+    /// the JSON for `--manifest`, so far
+    manifest: Option<Vec<u8>>,
+    /// where each record written so far starts, for `--index`
+    offsets: Option<Vec<u64>>,
+}
+
+/// Content hashes for `--dedup-members`, for every member in the tree.
+#[derive(Default)]
+struct Dedup {
+    hashes: HashMap<PathBuf, String>,
+    /// by hash, and whether it's a leaf, as leaves and containers are written out differently
+    counts: HashMap<(String, bool), u64>,
+    /// the index of the record which holds the content, once it's been written
+    written: HashMap<(String, bool), u64>,
+}
+
+impl Dedup {
+    /// The index of an earlier record with the same content as `temp`, at `index`, or whether
+    /// later records will refer back to this one.
+    fn check(&mut self, temp: &Path, leaf: bool, index: u64) -> (Option<u64>, bool) {
+        let key = match self.hashes.get(temp) {
+            Some(hash) => (hash.clone(), leaf),
+            None => return (None, false),
+        };
+
+        if let Some(&earlier) = self.written.get(&key) {
+            return (Some(earlier), false);
+        }
+
+        if self.counts.get(&key).cloned().unwrap_or(0) > 1 {
+            self.written.insert(key, index);
+            return (None, true);
+        }
+
+        (None, false)
+    }
+
+    fn count(&mut self, entries: &[Entry], config: &Config) -> Result<(), Error> {
+        // the order doesn't matter, only that we see everything `plan_records` will
+        let mut pending: Vec<&Entry> = entries.iter().collect();
+        while let Some(entry) = pending.pop() {
+            let descend = descends(entry, config)?;
+            if let Some(temp) = entry.local.temp.as_ref() {
+                match fs::File::open(temp) {
+                    Ok(file) => {
+                        let hash = shard::hash(file)?;
+                        *self.counts.entry((hash.clone(), !descend)).or_insert(0) += 1;
+                        self.hashes.insert(temp.to_path_buf(), hash);
+                    }
+                    // never a duplicate, then; `transform` will find out what's wrong with it
+                    Err(_) if config.skip_unreadable_members => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            match &entry.children {
+                Status::Success(children) if descend => pending.extend(children),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Are the entries splayers found in `entry` written, after it? If not, it's a leaf, even if it
+/// could be opened, e.g. for `--dedup-members`.
+fn descends(entry: &Entry, config: &Config) -> Result<bool, Error> {
+    Ok(
+        match (&entry.children, &config.descend_formats, &entry.local.temp) {
+            (Status::Success(_), Some(formats), Some(temp)) => match fs::File::open(temp) {
+                Ok(file) => format::one_of(formats, file)?,
+                // a leaf, then, which `transform` will record as unreadable
+                Err(_) if config.skip_unreadable_members => false,
+                Err(e) => return Err(e.into()),
+            },
+            (Status::Success(_), _, _) => true,
+            _ => false,
+        },
+    )
+}
+
+/// The rest of the entries of one container, while we're inside one of its children.
+struct Frame<'e> {
+    /// each with where it was in the container, before they were sorted
+    entries: std::vec::IntoIter<(usize, &'e Entry)>,
+    /// how long `name_prefix` was before this container's name was added to it
+    parent_len: usize,
+}
+
+impl<'e> Frame<'e> {
+    fn new(entries: &'e [Entry], parent_len: usize) -> Frame<'e> {
+        let mut entries: Vec<(usize, &Entry)> = entries.iter().enumerate().collect();
+        entries.sort_by_cached_key(|&(_, e)| order(e));
+        Frame {
+            entries: entries.into_iter(),
+            parent_len,
+        }
+    }
+}
+
+/// Where an entry goes amongst its siblings: by path, then, as splayers can produce several
+/// with the same path, by what else is known about it, so the output is the same for the same
+/// tree, whatever order the entries came in, except for the archive index recorded with each.
+/// Only exact copies are left as they were.
+fn order(entry: &Entry) -> (&[u8], u8, u32, u64, Option<u64>) {
+    let meta = &entry.local.meta;
+    (
+        entry.local.path.as_ref(),
+        item_kind(&meta.item_type),
+        match meta.ownership {
+            Ownership::Posix { mode, .. } => mode,
+            Ownership::Unknown => UNKNOWN_MODE,
+        },
+        meta.mtime,
+        entry
+            .local
+            .temp
+            .as_ref()
+            .map(|temp| fs::metadata(temp).map_or(0, |meta| meta.len())),
+    )
+}
+
+/// How many members are transformed at once, which bounds how many temporary files are open.
+const BATCH: usize = 256;
+
+/// What can be decided about an entry's record by walking the tree, before its content is read.
+struct Planned<'e> {
+    entry: &'e Entry,
+    /// where `Scratch::prefixes` has the paths of the containers it's inside
+    name_prefix: Range<usize>,
+    descend: bool,
+    /// the index of an earlier record with the same content
+    duplicate_of: Option<u64>,
+    /// later records will refer back to this one
+    shared: bool,
+    /// where it was amongst its siblings, as splayers found them
+    archive_index: u32,
+}
+
+fn output_into<W: Write>(
+    entries: &[Entry],
+    scratch: &mut Scratch,
+    out: &mut W,
+    config: &Config,
+) -> Result<(), Error> {
+    let planned = plan_records(entries, scratch, config)?;
+
+    let inline = Some(1) == config.threads;
+    // already on a pool, e.g. `process`'s, the strings pass shares it, instead of starting another;
+    // zero is rayon's default: one thread per CPU
+    let pool = if inline || rayon::current_thread_index().is_some() {
+        None
+    } else {
+        Some(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(config.threads.unwrap_or(0))
+                .build()?,
+        )
+    };
+
+    for batch in planned.chunks(BATCH) {
+        // the strings pass is spread over the pool, but the records are still written in order
+        let spread = || {
+            batch
+                .par_iter()
+                .map(|planned| transform(planned, config))
+                .collect()
+        };
+        let transformed: Vec<_> = match &pool {
+            _ if inline => batch
+                .iter()
+                .map(|planned| transform(planned, config))
+                .collect(),
+            Some(pool) => pool.install(spread),
+            None => spread(),
+        };
+
+        for (planned, data) in batch.iter().zip(transformed) {
+            // the output is only persisted once it's complete, so there's nothing to clean up
+            out_of_time(config)?;
+            write_record(planned, data?, scratch, out, config)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Walk the tree, in output order, deciding which entries to descend into, and which repeat
+/// the content of an earlier one.
+fn plan_records<'e>(
+    entries: &'e [Entry],
+    scratch: &mut Scratch,
+    config: &Config,
+) -> Result<Vec<Planned<'e>>, Error> {
+    let mut planned = Vec::new();
+
+    // an explicit stack, instead of recursion, so deep nesting can't overflow the real one,
+    // with the range of `prefixes` for each container on it
+    let mut stack = vec![(Frame::new(entries, 0), scratch.root_prefix.clone())];
+
+    while let Some((frame, name_prefix)) = stack.last_mut() {
+        let (archive_index, entry) = match frame.entries.next() {
+            Some(entry) => entry,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        let name_prefix = name_prefix.clone();
+
+        let descend = descends(entry, config)?;
+
+        let index = scratch.index + u64(planned.len());
+        let (duplicate_of, shared) = match (scratch.dedup.as_mut(), entry.local.temp.as_ref()) {
+            (Some(dedup), Some(temp)) => dedup.check(temp, !descend, index),
+            _ => (None, false),
+        };
+
+        planned.push(Planned {
+            entry,
+            name_prefix: name_prefix.clone(),
+            descend,
+            duplicate_of,
+            shared,
+            archive_index: u32(archive_index)?,
+        });
+
+        match &entry.children {
+            Status::Success(entries) if descend => {
+                let start = scratch.prefixes.len();
+                scratch.prefixes.extend_from_within(name_prefix);
+                let path = contained(&entry.local.path);
+                push_name(
+                    &mut scratch.prefixes,
+                    path.as_deref().unwrap_or(&entry.local.path),
+                )?;
+                stack.push((Frame::new(entries, 0), start..scratch.prefixes.len()));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(planned)
+}
+
+/// What `transform` made of an entry with content of its own.
+enum Transformed<'p> {
+    /// the content flag, data, then its length, and the hash of the content
+    Read(u8, Data<'p>, u64, Option<Hash>),
+    /// its temp file couldn't be opened, even on a second try, with `--skip-unreadable-members`
+    Unreadable,
+}
+
+/// Fail, as a `Failure::Timeout`, if the deadline has passed.
+fn out_of_time(config: &Config) -> Result<(), Error> {
+    if expired(config.deadline) {
+        return Err(err_msg("out of time").context(Failure::Timeout).into());
+    }
+    Ok(())
+}
+
+/// The content flag and data for a record with content of its own; the slow part.
+fn transform<'e>(planned: &Planned<'e>, config: &Config) -> Result<Option<Transformed<'e>>, Error> {
+    // the rest of the batch is abandoned, too, however big it is
+    out_of_time(config)?;
+
+    let temp = match (&planned.entry.local.temp, planned.duplicate_of) {
+        (Some(temp), None) => temp,
+        _ => return Ok(None),
+    };
+
+    let file = match fs::File::open(temp) {
+        Ok(file) => file,
+        // an unpacker quirk can leave a member missing for a moment, so once more, then give up
+        Err(_) if config.skip_unreadable_members => match fs::File::open(temp) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!(
+                    "couldn't open {:?}, so it's recorded as an error: {}",
+                    String::from_utf8_lossy(&planned.entry.local.path),
+                    e
+                );
+                return Ok(Some(Transformed::Unreadable));
+            }
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    let (flag, data, len, hash) = content(file, temp, !planned.descend, config)?;
+
+    Ok(Some(
+        match config.buckets.for_name(&planned.entry.local.path) {
+            Some(dictionary) if !data.is_inline() => {
+                // compressed on its own, with a dictionary for this kind of content
+                let level = config.level.unwrap_or(DEFAULT_LEVEL);
+                let (data, len) = compress_alone(data, temp, dictionary, level)?;
+                Transformed::Read(u8::from(flag) | 0x40, data, len, hash)
+            }
+            _ => Transformed::Read(u8::from(flag), data, len, hash),
+        },
+    ))
+}
+
+fn write_record<W: Write>(
+    planned: &Planned,
+    data: Option<Transformed>,
+    scratch: &mut Scratch,
+    out: &mut W,
+    config: &Config,
+) -> Result<(), Error> {
+    let entry = planned.entry;
+    let unreadable = matches!(data, Some(Transformed::Unreadable));
+    let descend = planned.descend;
+
+    let meta = &mut scratch.meta;
+    meta.clear();
+
+    let mut hash = None;
+    let data = match (planned.duplicate_of, data) {
+        (Some(index), _) => {
+            // the same as the content of an earlier record
+            meta.push(ContentState::Duplicate.into());
+            Some((Data::Inline(index.to_le_bytes().to_vec()), 8))
+        }
+        (None, Some(Transformed::Read(flag, data, len, hashed))) => {
+            meta.push(flag);
+            if planned.shared {
+                // later records will refer back to this one
+                meta[0] |= 0x80;
+            }
+            hash = hashed;
+            Some((data, len))
+        }
+        (None, Some(Transformed::Unreadable)) => {
+            meta.push(ContentState::NoContent.into());
+            None
+        }
+        (None, None) => match &entry.local.meta.item_type {
+            ItemType::SymbolicLink(target) => {
+                // so it's visible where it points, e.g. outside of the package
+                meta.push(ContentState::Symlink.into());
+                Some((Data::Inline(target.to_vec()), u64(target.len())))
+            }
+            _ => {
+                meta.push(ContentState::NoContent.into());
+                None
+            }
+        },
+    };
+
+    meta.push(u8::from(match &entry.children {
+        _ if unreadable => ChildrenState::Error,
+        Status::Unnecessary => ChildrenState::Unnecessary,
+        Status::Unrecognised => ChildrenState::Unrecognised,
+        Status::TooNested => ChildrenState::TooNested,
+        Status::Unsupported(_) => ChildrenState::Unsupported,
+        Status::Error(_) => ChildrenState::Error,
+        Status::Success(_) if descend => ChildrenState::Success,
+        Status::Success(_) => ChildrenState::NotDescended,
+    }));
+
+    meta.write_u32::<LE>(match entry.local.meta.ownership {
+        Ownership::Posix { mode, .. } => mode,
+        Ownership::Unknown => UNKNOWN_MODE,
+    })?;
+
+    let original_len = match &entry.local.temp {
+        Some(temp) if !unreadable => fs::metadata(temp)?.len(),
+        _ => 0,
+    };
+
+    // so consumers can see how much strings saved, without comparing with the source
+    meta.write_u64::<LE>(original_len)?;
+
+    let path = contained(&entry.local.path);
+
+    // the content flag can't say this: directories aren't the only entries with no content
+    meta.push(item_kind(&entry.local.meta.item_type) | if path.is_some() { 0x80 } else { 0 });
+
+    // so records can be matched up with the files they came from, across outputs
+    meta.extend_from_slice(&hash.unwrap_or([0; 32]));
+
+    // the records are sorted, but the order in the archive can matter, e.g. to tar quirks
+    meta.write_u32::<LE>(planned.archive_index)?;
+
+    let mime = match (
+        &entry.local.temp,
+        config.detect_mime && !descend && !unreadable,
+    ) {
+        (Some(temp), true) => Some(mime::detect(fs::File::open(temp)?)?),
+        _ => None,
+    };
+
+    if let Some(mime) = mime {
+        // a length-prefixed MIME type follows the children status
+        meta[0] |= 0x20;
+        meta.push(u8(mime.len())?);
+        meta.extend_from_slice(mime.as_bytes());
+    }
+
+    let reason = match &entry.children {
+        _ if unreadable => Some(Cow::Borrowed("couldn't be read")),
+        Status::Unsupported(kind) => Some(Cow::Owned(format!("unsupported: {:?}", kind))),
+        Status::Error(msg) => Some(Cow::Borrowed(msg.as_str())),
+        _ => None,
+    };
+
+    if let Some(reason) = reason {
+        // otherwise there's no telling why a container wasn't unpacked, short of trying again
+        let mut len = reason.len().min(usize::from(u16::MAX));
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        meta.write_u16::<LE>(u16(len)?)?;
+        meta.extend_from_slice(&reason.as_bytes()[..len]);
+    }
+
+    let names_start = meta.len();
+    meta.extend_from_slice(&scratch.prefixes[planned.name_prefix.clone()]);
+    push_name(meta, path.as_deref().unwrap_or(&entry.local.path))?;
+
+    let data_len = data.as_ref().map(|(_data, size)| *size).unwrap_or(0);
+
+    if let Some(max) = config.max_output_size {
+        // before any of the record is written, so the output never goes over; unmarked, as more
+        // space won't make the input any smaller
+        let total = scratch.stats.stream_bytes + 20 + u64(meta.len()) + data_len;
+        ensure!(
+            total <= max,
+            "output size limit exceeded: {} bytes, with a limit of {}",
+            total,
+            max
+        );
+    }
+
+    if let Some(offsets) = scratch.offsets.as_mut() {
+        offsets.push(HEADER_LEN + scratch.stats.stream_bytes);
+    }
+
+    out.write_u64::<LE>(8 + data_len + u64(meta.len()) + 4)?;
+    let mut out = Checksummed::new(out);
+    out.write_u64::<LE>(u64(meta.len()))?;
+    out.write_all(meta)?;
+
+    if let Some((data, _)) = data {
+        let written = io::copy(&mut data.into_read()?, &mut out)?;
+        ensure!(
+            written == data_len,
+            "short write: expected: {}, actual: {}",
+            data_len,
+            written
+        );
+    }
+
+    let crc = out.crc.finalize();
+    out.inner.write_u32::<LE>(crc)?;
+
+    if let Some(json) = scratch.manifest.as_mut() {
+        manifest::push(
+            json,
+            &manifest::Entry {
+                names: &meta[names_start..],
+                content: meta[0] & 0x1f,
+                children: meta[1],
+                original_len,
+                data_offset: HEADER_LEN + scratch.stats.stream_bytes + 16 + u64(meta.len()),
+                data_len,
+                mime,
+            },
+        );
+    }
+
+    let stats = &mut scratch.stats;
+    stats.entries += 1;
+    stats.by_flag[usize::from(meta[0] & 0x1f)] += 1;
+    stats.input_bytes += original_len;
+    stats.stream_bytes += 20 + u64(meta.len()) + data_len;
+
+    if planned.name_prefix == scratch.root_prefix {
+        debug!(
+            "top-level entry {}: {} bytes of data",
+            String::from_utf8_lossy(&entry.local.path),
+            data_len
+        );
+    }
+
+    if path.is_some() {
+        // it won't be where the archive said, so whoever's looking should be told
+        stats.unsafe_paths += 1;
+        warn!(
+            "recorded without the parts which could escape: {:?}",
+            String::from_utf8_lossy(&entry.local.path)
+        );
+    }
+
+    if let Status::TooNested = entry.children {
+        // splayers has no way to raise its limit, so at least don't lose things quietly
+        stats.too_nested += 1;
+        let mut path =
+            reader::split_names(&scratch.prefixes[planned.name_prefix.clone()]).unwrap_or_default();
+        path.push(&entry.local.path);
+        warn!(
+            "too nested to unpack, so contents missing: {}",
+            String::from_utf8_lossy(&path.join(&b'/'))
+        );
+    }
+
+    scratch.index += 1;
+
+    Ok(())
+}
+
+/// `path`, without the leading `/`s and `..` components which could take an extractor outside
+/// of wherever it's extracting to, or `None` if it has none, and can be recorded as it is.
+fn contained(path: &[u8]) -> Option<Vec<u8>> {
+    let mut components = path.split(|&b| b'/' == b);
+    if !path.starts_with(b"/") && !components.any(|component| b".." == component) {
+        return None;
+    }
+
+    let kept: Vec<&[u8]> = path
+        .split(|&b| b'/' == b)
+        .filter(|component| !component.is_empty() && b".." != *component)
+        .collect();
+    Some(kept.join(&b'/'))
+}
+
+/// Add a name to the end of the names in a meta, prefixed with its length.
+fn push_name(names: &mut Vec<u8>, name: &[u8]) -> Result<(), Error> {
+    names.write_u16::<LE>(u16(name.len())?)?;
+    names.extend_from_slice(name);
+    Ok(())
+}
+
+/// The kind of entry, as recorded in the meta; the targets of links aren't kept.
+fn item_kind(item_type: &ItemType) -> u8 {
+    match item_type {
+        ItemType::Unknown => 0,
+        ItemType::RegularFile => 1,
+        ItemType::Directory => 2,
+        ItemType::SymbolicLink(_) => 3,
+        ItemType::HardLink(_) => 4,
+        ItemType::Fifo => 5,
+        ItemType::Socket => 6,
+        ItemType::CharacterDevice { .. } => 7,
+        ItemType::BlockDevice { .. } => 8,
+    }
+}
+
+/// Strings (or otherwise transforms) `temp`, already open as `file`, returning the content flag
+/// describing what happened.
+fn content<'p>(
+    file: fs::File,
+    temp: &'p Path,
+    leaf: bool,
+    config: &Config,
+) -> Result<(ContentState, Data<'p>, u64, Option<Hash>), Error> {
+    let len = file.metadata()?.len();
+    let chunk = config.read_chunk_size.unwrap_or(DEFAULT_READ_CHUNK);
+    if 0 == len {
+        // empty, which would otherwise look like it came through strings unchanged
+        let hash = Hashed::new(io::empty()).finish();
+        Ok((ContentState::Empty, Data::Inline(Vec::new()), 0, Some(hash)))
+    } else if config.max_file_size.is_some_and(|max| len > max) {
+        // skipped, as it's too large
+        Ok((ContentState::Skipped, Data::Inline(Vec::new()), 0, None))
+    } else if known_binary(temp, config.skip_binary.as_deref())? {
+        // a known binary type, which strings would only find noise in
+        Ok((ContentState::Binary, Data::Inline(Vec::new()), 0, None))
+    } else if let (true, Some(shards)) = (leaf, config.shard_raw.as_ref()) {
+        // raw content stored elsewhere; we just have its hash
+        let hash = shard::store(shards, temp)?;
+        let len = u64(hash.len());
+        let raw = unhex(&hash)?;
+        Ok((
+            ContentState::Sharded,
+            Data::Inline(hash.into_bytes()),
+            len,
+            Some(raw),
+        ))
+    } else if config.raw {
+        // the original bytes, for whoever wants to do their own thing with them
+        let (data, hash) = raw_of(file, len)?;
+        Ok((ContentState::Raw, data, len, Some(hash)))
+    } else if config.skip_length_compare
+        && !config.normalize_text
+        && !config.transcode_latin1
+        && !leaf_expandable(leaf, config)
+    {
+        // unknown whether strings changed it; we never look
+        let mut hashed = Hashed::new(file);
+        let len = io::copy(
+            &mut strings::strings_reader(
+                io::BufReader::with_capacity(chunk, &mut hashed),
+                strings::StringsConfig::default(),
+            ),
+            &mut io::sink(),
+        )?;
+        Ok((
+            ContentState::NotCompared,
+            Data::Streamed(temp, chunk),
+            len,
+            Some(hashed.finish()),
+        ))
+    } else {
+        let mut stringed = scratch_file_near(temp)?;
+
+        let expanded = match leaf_expandable(leaf, config) {
+            true => expand_leaf(temp, &mut stringed, config.max_member_bytes, chunk)?,
+            false => None,
+        };
+
+        let hash;
+        let flag = if let Some(expanded) = expanded {
+            // only the decompressed content went through strings
+            hash = expanded;
+            ContentState::Expanded
+        } else if config.transcode_latin1 && text::looks_latin1(fs::File::open(temp)?)? {
+            // Latin-1 text, which strings would otherwise drop the accented letters of
+            let mut hashed = Hashed::new(fs::File::open(temp)?);
+            stringify(text::FromLatin1::new(&mut hashed), &mut stringed, chunk)?;
+            hash = hashed.finish();
+            ContentState::Transcoded
+        } else {
+            hash = stringify_file(&file, &mut stringed, chunk)?;
+            // the same length isn't enough: a binary byte can be swapped for a 0 separator
+            if file.metadata()?.len() != stringed.metadata()?.len()
+                || !identical(temp, &mut stringed)?
+            {
+                ContentState::Strings
+            } else if config.normalize_text
+                && normalize_text(temp, &mut stringed, config.normalize_crlf)?
+            {
+                ContentState::Normalised
+            } else {
+                ContentState::Unchanged
+            }
+        };
+
+        let new_len = stringed.metadata()?.len();
+
+        stringed.seek(SeekFrom::Start(0))?;
+
+        Ok((flag, Data::Stringed(stringed), new_len, Some(hash)))
+    }
+}
+
+/// How big a `--raw` member can be and still be held in memory, so it's only read the once.
+const RAW_HELD: u64 = 1024 * 1024;
+
+/// The content of `file`, which is `len` long, and its hash. The hash goes in the meta, ahead of
+/// the data, so a member too big to hold on to is read again as it's written out, through the
+/// same handle.
+fn raw_of(mut file: fs::File, len: u64) -> io::Result<(Data<'static>, Hash)> {
+    let mut hashed = Hashed::new(&mut file);
+    if len <= RAW_HELD {
+        let mut data = Vec::with_capacity(len as usize);
+        hashed.read_to_end(&mut data)?;
+        return Ok((Data::Held(data), hashed.finish()));
+    }
+
+    io::copy(&mut hashed, &mut io::sink())?;
+    let hash = hashed.finish();
+    file.seek(SeekFrom::Start(0))?;
+    Ok((Data::Stringed(file), hash))
+}
+
+/// Does `temp` start with any of `magics`, if there are any?
+fn known_binary(temp: &Path, magics: Option<&[Vec<u8>]>) -> io::Result<bool> {
+    let magics = match magics {
+        Some(magics) => magics,
+        None => return Ok(false),
+    };
+
+    let longest = magics.iter().map(Vec::len).max().unwrap_or(0);
+    let mut start = Vec::with_capacity(longest);
+    fs::File::open(temp)?
+        .take(u64(longest))
+        .read_to_end(&mut start)?;
+    Ok(magics.iter().any(|magic| start.starts_with(magic)))
+}
+
+/// Where an entry's (already strings'd) data comes from.
+enum Data<'p> {
+    /// read from the start; only not strings'd with `--raw`, or if it's been compressed since
+    Stringed(fs::File),
+    /// strings'd again as it's written out, so it never needs to be stored, this much at a time
+    Streamed(&'p Path, usize),
+    /// the content itself, kept from when it was hashed, with `--raw`
+    Held(Vec<u8>),
+    Inline(Vec<u8>),
+}
+
+impl<'p> Data<'p> {
+    fn is_inline(&self) -> bool {
+        matches!(self, Data::Inline(_))
+    }
+
+    fn into_read(self) -> Result<Box<dyn Read + 'p>, Error> {
+        Ok(match self {
+            Data::Stringed(file) => Box::new(file),
+            Data::Streamed(temp, chunk) => Box::new(strings_of(temp, chunk)?),
+            Data::Held(bytes) | Data::Inline(bytes) => Box::new(io::Cursor::new(bytes)),
+        })
+    }
+}
+
+/// A temporary file in the same directory as `temp`, so it's on the same disk, or in the system
+/// temporary directory if `temp` doesn't have one, e.g. it's a bare file name.
+fn scratch_file_near(temp: &Path) -> Result<fs::File, Error> {
+    let dir = match temp.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => env::temp_dir(),
+    };
+    Ok(tempfile::tempfile_in(&dir)
+        .with_context(|_| format_err!("creating a temporary file for {:?} in {:?}", temp, dir))?)
+}
+
+/// Compress `data` into a zstd frame of its own, next to `temp`, at the output's `level`.
+fn compress_alone<'p>(
+    data: Data<'p>,
+    temp: &Path,
+    dictionary: &[u8],
+    level: i32,
+) -> Result<(Data<'p>, u64), Error> {
+    let compressed = scratch_file_near(temp)?;
+    let mut encoder = zstd::Encoder::with_dictionary(compressed, level, dictionary)?;
+    io::copy(&mut data.into_read()?, &mut encoder)?;
+    let mut compressed = encoder.finish()?;
+    let len = compressed.seek(SeekFrom::End(0))?;
+    compressed.seek(SeekFrom::Start(0))?;
+    Ok((Data::Stringed(compressed), len))
+}
+
+fn leaf_expandable(leaf: bool, config: &Config) -> bool {
+    leaf && config.expand_leaf_compression
+}
+
+fn strings_of(temp: &Path, chunk: usize) -> io::Result<impl Read> {
+    Ok(strings::strings_reader(
+        io::BufReader::with_capacity(chunk, fs::File::open(temp)?),
+        strings::StringsConfig::default(),
+    ))
+}
+
+fn stringify<R: Read>(mut src: R, dest: &mut fs::File, chunk: usize) -> io::Result<u64> {
+    let mut stringer = strings::StringBuf::new(io::BufWriter::new(dest));
+    let mut read = 0;
+    let mut buf = vec![0u8; chunk];
+    loop {
+        let len = src.read(&mut buf)?;
+        if 0 == len {
+            break;
+        }
+        let buf = &buf[..len];
+        stringer.accept(buf)?;
+        read += u64(len);
+    }
+    stringer.finish()?.flush()?;
+    Ok(read)
+}
+
+/// Members at least this big are strings'd straight out of a memory map, with `--features mmap`.
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// `stringify`, but without the reads, if `file` is big enough to be worth mapping, returning
+/// the sha256 of what was in it.
+#[cfg(feature = "mmap")]
+fn stringify_file(file: &fs::File, dest: &mut fs::File, chunk: usize) -> io::Result<Hash> {
+    if file.metadata()?.len() >= MMAP_THRESHOLD {
+        if let Some((_, hash)) = stringify_mapped(file, dest, chunk)? {
+            return Ok(hash);
+        }
+    }
+    let mut hashed = Hashed::new(file);
+    stringify(&mut hashed, dest, chunk)?;
+    Ok(hashed.finish())
+}
+
+/// `stringify`, returning the sha256 of what was in `file`.
+#[cfg(not(feature = "mmap"))]
+fn stringify_file(file: &fs::File, dest: &mut fs::File, chunk: usize) -> io::Result<Hash> {
+    let mut hashed = Hashed::new(file);
+    stringify(&mut hashed, dest, chunk)?;
+    Ok(hashed.finish())
+}
+
+/// `stringify`, handing the strings pass the same `chunk`s of the file a read would have, or
+/// `None`, having done nothing, if `file` can't be mapped, e.g. as it's not a regular file.
+#[cfg(feature = "mmap")]
+fn stringify_mapped(
+    file: &fs::File,
+    dest: &mut fs::File,
+    chunk: usize,
+) -> io::Result<Option<(u64, Hash)>> {
+    // the unpacked members are ours, so nothing should be changing them underneath the map
+    let map = match unsafe { memmap2::Mmap::map(file) } {
+        Ok(map) => map,
+        Err(_) => return Ok(None),
+    };
+
+    let mut stringer = strings::StringBuf::new(io::BufWriter::new(dest));
+    let mut sha256 = Sha256::new();
+    for buf in map.chunks(chunk) {
+        sha256.input(buf);
+        stringer.accept(buf)?;
+    }
+    stringer.finish()?.flush()?;
+    Ok(Some((u64(map.len()), digest(sha256))))
+}
+
+/// Does `stringed` hold exactly the content of `temp`?
+fn identical(temp: &Path, stringed: &mut fs::File) -> io::Result<bool> {
+    stringed.seek(SeekFrom::Start(0))?;
+    let mut ours = io::BufReader::new(fs::File::open(temp)?);
+    let mut theirs = io::BufReader::new(stringed);
+
+    loop {
+        let (a, b) = (ours.fill_buf()?, theirs.fill_buf()?);
+        if a.is_empty() || b.is_empty() {
+            return Ok(a.is_empty() && b.is_empty());
+        }
+
+        let len = a.len().min(b.len());
+        if a[..len] != b[..len] {
+            return Ok(false);
+        }
+
+        ours.consume(len);
+        theirs.consume(len);
+    }
+}
+
+/// `stringed` currently holds an unchanged copy of `temp`; replace it with a normalized copy.
+fn normalize_text(temp: &Path, stringed: &mut fs::File, crlf: bool) -> Result<bool, Error> {
+    stringed.set_len(0)?;
+    stringed.seek(SeekFrom::Start(0))?;
+    let mut dest = io::BufWriter::new(stringed);
+    let changed = text::normalize(fs::File::open(temp)?, &mut dest, crlf)?;
+    dest.flush()?;
+    Ok(changed)
+}
+
+/// If `temp` starts with a compression magic we can undo, strings the decompressed content into
+/// `stringed` and return the hash of `temp`, taken as it's decompressed. On anything unexpected,
+/// `stringed` is left empty and we return `None`, so the caller can fall back to the raw bytes.
+fn expand_leaf(
+    temp: &Path,
+    stringed: &mut fs::File,
+    limit: u64,
+    chunk: usize,
+) -> Result<Option<Hash>, Error> {
+    let mut file = fs::File::open(temp)?;
+    let mut magic = Vec::with_capacity(6);
+    (&mut file).take(6).read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut hashed = Hashed::new(file);
+    let expanded = {
+        let decoder: Box<dyn Read> = match magic.as_slice() {
+            [0x1f, 0x8b, ..] => Box::new(flate2::read::GzDecoder::new(&mut hashed)),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00] => {
+                Box::new(xz2::read::XzDecoder::new(&mut hashed))
+            }
+            [b'B', b'Z', b'h', ..] => Box::new(bzip2::read::BzDecoder::new(&mut hashed)),
+            _ => return Ok(None),
+        };
+        stringify(decoder.take(limit + 1), stringed, chunk)
+    };
+
+    match expanded {
+        Ok(len) if len <= limit => {
+            // the decoder can stop before the end, e.g. at anything after the compressed stream
+            io::copy(&mut hashed, &mut io::sink())?;
+            Ok(Some(hashed.finish()))
+        }
+        _ => {
+            stringed.set_len(0)?;
+            stringed.seek(SeekFrom::Start(0))?;
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::Write;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use sha2::Digest;
+    use sha2::Sha256;
+    use splayers::Entry;
+    use splayers::FileEntry;
+    use splayers::ItemType;
+    use splayers::Meta;
+    use splayers::Ownership;
+    use splayers::Status;
+
+    use super::testing::meta;
+    use super::testing::record;
+    use super::Config;
+    use super::DEFAULT_OUTPUT_NAME;
+
+    fn entry(path: &str, temp: Option<PathBuf>, children: Status) -> Entry {
+        Entry {
+            local: FileEntry {
+                path: path.as_bytes().to_vec().into_boxed_slice(),
+                temp,
+                meta: Meta {
+                    atime: 0,
+                    mtime: 0,
+                    ctime: 0,
+                    btime: 0,
+                    item_type: ItemType::RegularFile,
+                    ownership: Ownership::Unknown,
+                    xattrs: HashMap::new(),
+                },
+            },
+            children,
+        }
+    }
+
+    #[test]
+    fn plan() {
+        let dsc = url::Url::parse("http://example.com/pool/f/foo/foo_1.2-3.dsc").unwrap();
+        let plan = super::plan(&dsc, "foo_1.2-3.debian.tar.xz", DEFAULT_OUTPUT_NAME, "").unwrap();
+        assert_eq!("foo_1.2-3.debian.tar.xz", plan.name);
+        assert_eq!(
+            "http://example.com/pool/f/foo/foo_1.2-3.debian.tar.xz",
+            plan.sub_url.as_str()
+        );
+        assert_eq!(Some(crate::dict::Dictionary::Debian), plan.dictionary);
+        assert_eq!("foo_1.2-3.debian.tar.xz.annul", plan.output_name);
+
+        let hashed = super::plan(&dsc, "foo_1.2-3.debian.tar.xz", "{hash}/{name}", "abc").unwrap();
+        assert_eq!("abc/foo_1.2-3.debian.tar.xz", hashed.output_name);
+
+        let plan = |name| super::plan(&dsc, name, DEFAULT_OUTPUT_NAME, "");
+        assert!(plan("../../../etc/passwd").is_err());
+        assert!(plan("..").is_err());
+        assert!(super::plan(&dsc, "foo.dsc", "{suite}", "").is_err());
+    }
+
+    #[test]
+    fn listed_files() {
+        let dsc = "Format: 3.0 (quilt)
+Source: foo
+Checksums-Sha256:
+ 0123 1234 foo_1.2.orig.tar.gz
+ 4567 833 foo_1.2.orig.tar.gz.asc
+ 89ab 56 foo_1.2-3.debian.tar.xz
+Files:
+ cdef 1234 foo_1.2.orig.tar.gz
+";
+        assert_eq!(
+            vec!["foo_1.2.orig.tar.gz", "foo_1.2-3.debian.tar.xz"],
+            super::listed_files(dsc)
+        );
+        assert_eq!(
+            vec!["foo_1.2.diff.gz"],
+            super::listed_files("Source: foo\nFiles:\n cdef 56 foo_1.2.diff.gz\n")
+        );
+        assert!(super::listed_files("Source: foo\n").is_empty());
+    }
+
+    #[test]
+    fn resumable() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("foo_1.2.orig.tar.gz");
+        fs::write(&src, b"the whole of the file").unwrap();
+        let url = super::source_url(src.to_str().unwrap()).unwrap();
+        let partial = dir.path().join(".foo.partial");
+        let fetch = |expected| {
+            let tmp = super::download_resumable(
+                url.as_str(),
+                "foo_1.2.orig.tar.gz",
+                &partial,
+                expected,
+                dir.path(),
+                None,
+                None,
+            )
+            .unwrap();
+            assert!(!partial.exists());
+            fs::read(&tmp).unwrap()
+        };
+
+        // carried on from, so what was already there is kept, even if it's wrong
+        fs::write(&partial, b"THE WHOLE").unwrap();
+        assert_eq!(b"THE WHOLE of the file".to_vec(), fetch(None));
+
+        // complete, by the dsc
+        fs::write(&partial, b"ALL OF IT, ALREADY...").unwrap();
+        assert_eq!(b"ALL OF IT, ALREADY...".to_vec(), fetch(Some(21)));
+
+        // longer than the dsc says, or than there is
+        fs::write(&partial, b"more than the whole of the file").unwrap();
+        assert_eq!(b"the whole of the file".to_vec(), fetch(Some(21)));
+        fs::write(&partial, b"more than the whole of the file").unwrap();
+        assert_eq!(b"the whole of the file".to_vec(), fetch(None));
+
+        assert_eq!(b"the whole of the file".to_vec(), fetch(None));
+
+        // nothing to carry on from, so nothing's left behind
+        let missing = super::source_url(dir.path().join("missing").to_str().unwrap()).unwrap();
+        let tee = dir.path().join("tee");
+        fs::create_dir(&tee).unwrap();
+        assert!(super::download_resumable(
+            missing.as_str(),
+            "missing",
+            &partial,
+            None,
+            dir.path(),
+            Some(&tee),
+            None,
+        )
+        .is_err());
+        assert!(!partial.exists());
+        assert!(
+            super::download(missing.as_str(), "missing", Vec::new(), Some(&tee), None).is_err()
+        );
+        assert_eq!(0, fs::read_dir(&tee).unwrap().count());
+    }
+
+    #[test]
+    fn iter_annul() {
+        let dir = tempfile::tempdir().unwrap();
+        let code = dir.path().join("code");
+        fs::write(&code, b"int main() {}\n").unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world").unwrap();
+
+        let entries = vec![
+            entry(
+                "z.tar",
+                None,
+                Status::Success(vec![
+                    entry("a.c", Some(code.clone()), Status::Unrecognised),
+                    entry("b", Some(binary), Status::Unrecognised),
+                ]),
+            ),
+            entry("copy.c", Some(code), Status::Unrecognised),
+        ];
+        let config = Config {
+            dedup_members: true,
+            ..Config::default()
+        };
+        let mut out = super::Encoder::new(Vec::new(), crate::dict::ORIG, &config).unwrap();
+        super::write_header(&mut out).unwrap();
+        super::output_with(
+            &entries,
+            &[b"outer".to_vec().into_boxed_slice()],
+            &mut out,
+            &config,
+        )
+        .unwrap();
+        let path = dir.path().join("out.annul");
+        fs::write(&path, out.finish().unwrap()).unwrap();
+
+        let found: Vec<crate::reader::Record> = crate::reader::iter_annul(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let found: Vec<(String, &[u8])> = found
+            .iter()
+            .map(|(path, content)| {
+                (
+                    String::from_utf8(path.join(&b'/')).unwrap(),
+                    content.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("outer/copy.c".to_string(), &b"int main() {}\n"[..]),
+                ("outer/z.tar".to_string(), b""),
+                ("outer/z.tar/a.c".to_string(), b"int main() {}\n"),
+                ("outer/z.tar/b".to_string(), b"hello\0world"),
+            ],
+            found
+        );
+
+        let truncated = dir.path().join("truncated.annul");
+        let mut whole = Vec::new();
+        crate::reader::decompress(&path)
+            .unwrap()
+            .read_to_end(&mut whole)
+            .unwrap();
+        fs::write(&truncated, &whole[..whole.len() - 2]).unwrap();
+        let found: Vec<_> = crate::reader::iter_annul(&truncated).unwrap().collect();
+        assert_eq!(4, found.len());
+        assert!(found[..3].iter().all(Result::is_ok));
+        assert!(found[3].is_err());
+
+        assert!(crate::reader::iter_annul(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn compression() {
+        use super::Compression;
+
+        let dir = tempfile::tempdir().unwrap();
+        let meta = meta().children(3).hash_of(b"hello").name("a.c").build();
+        for &compression in &[
+            Compression::Zstd,
+            Compression::Xz,
+            Compression::Gzip,
+            Compression::None,
+        ] {
+            let config = Config {
+                compression,
+                ..Config::default()
+            };
+            let mut out = super::Encoder::new(Vec::new(), crate::dict::ORIG, &config).unwrap();
+            super::write_header(&mut out).unwrap();
+            super::write_record_bytes(&mut out, &meta, b"hello").unwrap();
+            let path = dir.path().join(format!("{:?}.annul", compression));
+            fs::write(&path, out.finish().unwrap()).unwrap();
+
+            let mut reader = crate::reader::open(&path).unwrap();
+            let entry = reader.next_entry().unwrap().unwrap();
+            assert_eq!(vec![&b"a.c"[..]], entry.path_components());
+            assert_eq!(b"hello", entry.data.as_slice());
+            assert!(reader.next_entry().unwrap().is_none());
+        }
+    }
+
+    /// Takes `remaining` bytes, then fails, like a disc filling up.
+    struct Failing {
+        remaining: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if 0 == self.remaining {
+                return Err(io::Error::other("no space left"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_writes_marked() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![entry("a", Some(text), Status::Unrecognised)];
+        let err =
+            super::output_marked(&entries, &mut Failing { remaining: 10 }, &Config::default())
+                .unwrap_err();
+        assert_eq!(Some(super::Failure::Write), super::Failure::of(&err));
+
+        // opens, but can't be read, which won't be any different next time
+        let entries = vec![entry(
+            "a",
+            Some(dir.path().to_path_buf()),
+            Status::Unrecognised,
+        )];
+        let err = super::output_marked(&entries, &mut Vec::new(), &Config::default()).unwrap_err();
+        assert_eq!(None, super::Failure::of(&err), "{}", err);
+    }
+
+    #[test]
+    fn failed_write_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world".repeat(100)).unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n".repeat(100)).unwrap();
+        let listing = || {
+            let mut names: Vec<_> = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = listing();
+
+        let entries = vec![
+            entry("binary", Some(binary.clone()), Status::Unrecognised),
+            entry("text", Some(text.clone()), Status::Unrecognised),
+        ];
+        let config = Config {
+            manifest: Some(dir.path().join("manifest.json")),
+            ..Config::default()
+        };
+        for remaining in &[0, 100, 1000] {
+            let mut out = Failing {
+                remaining: *remaining,
+            };
+            let err = super::output_with(&entries, &[], &mut out, &config).unwrap_err();
+            assert!(err.to_string().contains("no space left"), "{}", err);
+            assert_eq!(before, listing());
+        }
+    }
+
+    #[test]
+    fn drop_kept() {
+        use super::Restart;
+
+        let mut file = super::Partial(tempfile::tempfile().unwrap());
+        file.write_all(b"abc").unwrap();
+        assert_eq!(3, file.resume().unwrap());
+        file.write_all(&b"abcdefgh".repeat(10_000)).unwrap();
+        file.drop_kept(3).unwrap();
+        file.write_all(b"!").unwrap();
+
+        let mut written = Vec::new();
+        file.0.seek(io::SeekFrom::Start(0)).unwrap();
+        file.0.read_to_end(&mut written).unwrap();
+        let mut expected = b"abcdefgh".repeat(10_000);
+        expected.push(b'!');
+        assert_eq!(expected, written);
+    }
+
+    #[test]
+    fn local_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo_1.2.orig.tar.gz");
+        fs::write(&path, b"not really a tarball").unwrap();
+
+        let dsc = super::source_url(dir.path().join("foo_1.2.dsc").to_str().unwrap()).unwrap();
+        assert_eq!("file", dsc.scheme());
+        let plan = super::plan(&dsc, "foo_1.2.orig.tar.gz", DEFAULT_OUTPUT_NAME, "").unwrap();
+        assert_eq!(Some(crate::dict::Dictionary::Orig), plan.dictionary);
+
+        let mut fetched = Vec::new();
+        let src = super::source_url(path.to_str().unwrap()).unwrap();
+        assert_eq!(src, plan.sub_url);
+        super::download(src.as_str(), "", &mut fetched, None, None).unwrap();
+        assert_eq!(b"not really a tarball", fetched.as_slice());
+
+        fetched.clear();
+        super::download(plan.sub_url.as_str(), "", &mut fetched, None, None).unwrap();
+        assert_eq!(b"not really a tarball", fetched.as_slice());
+
+        let missing = super::plan(&dsc, "missing.tar.gz", DEFAULT_OUTPUT_NAME, "").unwrap();
+        assert!(super::download(missing.sub_url.as_str(), "", &mut fetched, None, None).is_err());
+    }
+
+    #[test]
+    fn force() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("foo_1.2.dsc");
+        fs::write(
+            &src,
+            "Source: foo\nFiles:\n 0123 5 foo_1.2.orig.tar.gz\n 4567 6 foo_1.2.diff.gz\n",
+        )
+        .unwrap();
+        for existing in &["foo_1.2.orig.tar.gz.annul", "foo_1.2.diff.gz.annul"] {
+            fs::write(dir.path().join(existing), b"from last time").unwrap();
+        }
+
+        // the listed files aren't there, so only an attempt to redo them fails
+        let src = src.to_str().unwrap();
+        assert!(super::process(src, dir.path(), &Config::default())
+            .unwrap()
+            .is_empty());
+
+        let force = Config {
+            force: true,
+            ..Config::default()
+        };
+        assert!(super::process(src, dir.path(), &force).is_err());
+
+        // both files would be written to the same place
+        let unnamed = Config {
+            output_name: Some("{hash}.annul".to_string()),
+            ..Config::default()
+        };
+        assert!(super::process(src, dir.path(), &unnamed).is_err());
+    }
+
+    #[test]
+    fn files_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("foo_1.2.dsc");
+        fs::write(
+            &src,
+            "Source: foo\nFiles:\n 0123 5 foo_1.2.orig.tar.gz\n 4567 6 foo_1.2.diff.gz\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("foo_1.2.orig.tar.gz.annul"),
+            b"from last time",
+        )
+        .unwrap();
+        let src = src.to_str().unwrap();
+
+        for &threads in &[None, Some(1), Some(2)] {
+            // the first is done, so it's only the second which isn't there
+            let config = Config {
+                threads,
+                ..Config::default()
+            };
+            match super::process(src, dir.path(), &config) {
+                Err(e) => assert_eq!(Some(super::Failure::Download), super::Failure::of(&e)),
+                Ok(_) => panic!("processed a missing file"),
+            }
+            assert!(!dir.path().join("foo_1.2.diff.gz.annul").exists());
+        }
+    }
+
+    #[test]
+    fn output_name() {
+        assert_eq!(
+            "sid/foo.dsc.abc.annul",
+            super::output_name("sid/{name}.{hash}.annul", "foo.dsc", "abc").unwrap()
+        );
+        assert_eq!(
+            "foo.dsc.annul",
+            super::output_name(super::DEFAULT_OUTPUT_NAME, "foo.dsc", "").unwrap()
+        );
+        assert!(super::output_name("{suite}.annul", "foo.dsc", "").is_err());
+        assert!(super::output_name("{name", "foo.dsc", "").is_err());
+        assert!(super::output_name("../{name}", "foo.dsc", "").is_err());
+        assert!(super::output_name("/tmp/{name}", "foo.dsc", "").is_err());
+
+        // the hash is of the dsc, so an output named by it is only found once that's fetched
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("foo_1.2.dsc");
+        let dsc = b"Source: foo\nFiles:\n 0123 5 foo_1.2.orig.tar.gz\n";
+        fs::write(&src, dsc).unwrap();
+        let hash = crate::shard::hash(&dsc[..]).unwrap();
+        fs::create_dir(dir.path().join("out")).unwrap();
+        fs::write(
+            dir.path().join(format!("out/foo_1.2.orig.tar.gz.{}", hash)),
+            b"from last time",
+        )
+        .unwrap();
+
+        let hashed = Config {
+            output_name: Some("out/{name}.{hash}".to_string()),
+            ..Config::default()
+        };
+        let src = src.to_str().unwrap();
+        assert!(super::process(src, dir.path(), &hashed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compressed_dsc() {
+        let dir = tempfile::tempdir().unwrap();
+        let dsc = b"Source: foo\nFiles:\n 0123 5 foo_1.2.orig.tar.gz\n";
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(dsc).unwrap();
+        fs::write(dir.path().join("foo_1.2.dsc"), dsc).unwrap();
+        fs::write(dir.path().join("foo_1.2.dsc.gz"), gzip.finish().unwrap()).unwrap();
+
+        // named by the hash of the dsc itself, however it was fetched
+        let hash = crate::shard::hash(&dsc[..]).unwrap();
+        fs::write(
+            dir.path().join(format!("foo_1.2.orig.tar.gz.{}", hash)),
+            b"from last time",
+        )
+        .unwrap();
+        let hashed = Config {
+            output_name: Some("{name}.{hash}".to_string()),
+            ..Config::default()
+        };
+        for name in &["foo_1.2.dsc", "foo_1.2.dsc.gz"] {
+            let src = dir.path().join(name);
+            assert!(super::process(src.to_str().unwrap(), dir.path(), &hashed)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_dsc() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("foo_1.2.dsc");
+        fs::write(&src, b"<html><body>404 Not Found</body></html>\n").unwrap();
+
+        let err = match super::process(src.to_str().unwrap(), dir.path(), &Config::default()) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("processed a 404 page"),
+        };
+        assert!(err.contains("not a valid dsc"), "{}", err);
+    }
+
+    #[test]
+    fn failures() {
+        use super::Failure;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("foo_1.2.orig.tar.gz"), b"hello").unwrap();
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let failure = |dsc: Option<String>| {
+            let src = dir.path().join("foo_1.2.dsc");
+            match dsc {
+                Some(dsc) => fs::write(&src, dsc).unwrap(),
+                None => fs::remove_file(&src).unwrap(),
+            }
+            match super::process(src.to_str().unwrap(), dir.path(), &Config::default()) {
+                Err(e) => Failure::of(&e),
+                Ok(_) => panic!("processed a broken source"),
+            }
+        };
+        let listing = |digest: &str, name: &str| {
+            Some(format!(
+                "Source: foo\nChecksums-Sha256:\n {} 5 {}\n",
+                digest, name
+            ))
+        };
+
+        assert_eq!(
+            Some(Failure::Unpack),
+            failure(listing(hello, "foo_1.2.orig.tar.gz"))
+        );
+        assert_eq!(
+            Some(Failure::Checksum),
+            failure(listing(&"00".repeat(32), "foo_1.2.orig.tar.gz"))
+        );
+        assert_eq!(
+            Some(Failure::Download),
+            failure(listing(hello, "missing.tar.gz"))
+        );
+        assert_eq!(None, failure(Some("<html>".to_string())));
+        assert_eq!(Some(Failure::Download), failure(None));
+
+        let src = dir.path().join("foo_1.2.dsc");
+        fs::write(&src, listing(hello, "foo_1.2.orig.tar.gz").unwrap()).unwrap();
+        let config = Config {
+            deadline: Some(Instant::now()),
+            ..Config::default()
+        };
+        match super::process(src.to_str().unwrap(), dir.path(), &config) {
+            Err(e) => assert_eq!(Some(Failure::Timeout), Failure::of(&e), "{}", e),
+            Ok(_) => panic!("processed a source after its deadline"),
+        }
+
+        let late = failure::err_msg("out of time").context(Failure::Timeout);
+        let late: failure::Error = failure::Error::from(late).context(Failure::Write).into();
+        assert_eq!(Some(Failure::Timeout), Failure::of(&late));
+    }
+
+    #[test]
+    fn verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo_1.2.orig.tar.gz");
+        fs::write(&path, b"hello").unwrap();
+
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let dsc = |digest: &str, size| {
+            format!(
+                "Source: foo\nChecksums-Sha256:\n {} {} foo_1.2.orig.tar.gz\n",
+                digest, size
+            )
+        };
+
+        super::verify(&dsc(hello, 5), "foo_1.2.orig.tar.gz", &path).unwrap();
+        super::verify(&dsc(hello, 5), "unlisted.tar.gz", &path).unwrap();
+        super::verify("Source: foo\n", "foo_1.2.orig.tar.gz", &path).unwrap();
+
+        let short = super::verify(&dsc(hello, 6), "foo_1.2.orig.tar.gz", &path).unwrap_err();
+        assert!(short.to_string().contains("expected 6 bytes"), "{}", short);
+
+        let wrong = "00".repeat(32);
+        let corrupt = super::verify(&dsc(&wrong, 5), "foo_1.2.orig.tar.gz", &path)
+            .unwrap_err()
+            .to_string();
+        assert!(corrupt.contains("foo_1.2.orig.tar.gz"), "{}", corrupt);
+        assert!(corrupt.contains(&wrong), "{}", corrupt);
+        assert!(corrupt.contains(hello), "{}", corrupt);
+
+        let files = "Files:\n 5d41402abc4b2a76b9719d911017c592 4 foo_1.2.orig.tar.gz\n";
+        assert!(super::verify(files, "foo_1.2.orig.tar.gz", &path).is_err());
+    }
+
+    #[test]
+    fn timed_out() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stalled = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stalled
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let e = stalled.read(&mut [0; 1]).unwrap_err();
+        assert!(super::timed_out(&e), "{:?}", e);
+
+        let refused = io::Error::new(io::ErrorKind::TimedOut, "out of time");
+        assert!(!super::timed_out(&refused));
+    }
+
+    #[test]
+    fn retries() {
+        use super::Restart;
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(super::transient(&reset.into()));
+        let http = http_req::error::Error::IO(io::ErrorKind::UnexpectedEof.into());
+        assert!(super::transient(&http.into()));
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert!(!super::transient(&missing.into()));
+        assert!(!super::transient(&failure::err_msg("HTTP 404")));
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"a partial download").unwrap();
+        file.restart().unwrap();
+        file.write_all(b"whole").unwrap();
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(b"whole", content.as_slice());
+
+        // a missing file isn't worth retrying, so doesn't wait around
+        let dir = tempfile::tempdir().unwrap();
+        let missing = super::source_url(dir.path().join("missing").to_str().unwrap()).unwrap();
+        let started = Instant::now();
+        assert!(super::download(missing.as_str(), "", Vec::new(), None, None).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        fs::write(&first, b"same\n").unwrap();
+        fs::write(&second, b"same\n").unwrap();
+
+        let entries = vec![
+            entry("a", Some(first), Status::Unrecognised),
+            entry("b", Some(second), Status::Unrecognised),
+        ];
+
+        let config = Config {
+            dedup_members: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        let stats = super::output_with(&entries, &[], &mut out, &config).unwrap();
+        assert_eq!(2, stats.entries);
+        assert_eq!(1, stats.duplicates());
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert!(a.shared());
+        assert_eq!(b"same\n", a.data.as_slice());
+
+        let mut b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(14), b.content_flag());
+        assert_eq!(8, b.data.len());
+        reader.resolve_duplicate(&mut b).unwrap();
+        assert_eq!(b"same\n", b.data.as_slice());
+    }
+
+    #[test]
+    fn dedup_undescended() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        fs::write(&inner, b"inside\n").unwrap();
+        let mut gzip = b"\x1f\x8b\x08\x00".to_vec();
+        gzip.extend_from_slice(&[0; 16]);
+        let first = dir.path().join("first.gz");
+        let second = dir.path().join("second.gz");
+        fs::write(&first, &gzip).unwrap();
+        fs::write(&second, &gzip).unwrap();
+
+        let container = |name: &str, temp: &Path| {
+            entry(
+                name,
+                Some(temp.to_path_buf()),
+                Status::Success(vec![entry("x", Some(inner.clone()), Status::Unrecognised)]),
+            )
+        };
+        let entries = vec![container("a.gz", &first), container("b.gz", &second)];
+
+        // opened by splayers, but not descended into, so they're just two of the same leaf
+        let config = Config {
+            dedup_members: true,
+            descend_formats: Some(vec!["zip".to_string()]),
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        let stats = super::output_with(&entries, &[], &mut out, &config).unwrap();
+        assert_eq!(2, stats.entries);
+        assert_eq!(1, stats.duplicates());
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert!(a.shared());
+        assert_eq!(Some(12), a.children_status());
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(14), b.content_flag());
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn unreadable_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present");
+        fs::write(&present, b"here\n").unwrap();
+
+        let entries = vec![
+            entry("a", Some(dir.path().join("missing")), Status::Unrecognised),
+            entry("b", Some(present), Status::Unrecognised),
+        ];
+
+        let mut out = Vec::new();
+        assert!(super::output(&entries, &[], &mut out).is_err());
+
+        let config = Config {
+            skip_unreadable_members: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(
+            2,
+            super::output_with(&entries, &[], &mut out, &config)
+                .unwrap()
+                .entries
+        );
+
+        let mut expected = Vec::new();
+        record(
+            &mut expected,
+            &meta()
+                .flags(2)
+                .children(7)
+                .reason("couldn't be read")
+                .name("a")
+                .build(),
+            b"",
+        );
+        record(
+            &mut expected,
+            &meta().hash_of(b"here\n").archive_index(1).name("b").build(),
+            b"here\n",
+        );
+        assert_eq!(expected, out);
+        // hashing everything up front for dedup doesn't trip over it either
+        let dedup = Config {
+            dedup_members: true,
+            ..config
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &dedup).unwrap();
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn no_parent() {
+        // splayers shouldn't hand these out, but they mustn't panic if it does
+        assert!(super::scratch_file_near(Path::new("bare")).is_ok());
+        assert!(super::scratch_file_near(Path::new("/")).is_ok());
+
+        let entries = vec![entry(
+            "root",
+            Some(PathBuf::from("/")),
+            Status::Unrecognised,
+        )];
+        assert!(super::output(&entries, &[], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let code = dir.path().join("code");
+        let source = b"#include <stdio.h>\nint main() { return 0; }\n".repeat(20);
+        fs::write(&code, &source).unwrap();
+
+        let entries = vec![
+            entry("main.c", Some(code.clone()), Status::Unrecognised),
+            entry("COPYING", Some(code), Status::Unrecognised),
+        ];
+
+        let registry = crate::buckets::Registry::default()
+            .with("c", crate::dict::DIFF.to_vec())
+            .unwrap();
+        let config = Config {
+            buckets: registry.clone(),
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &config).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+
+        let copying = reader.next_entry().unwrap().unwrap();
+        assert!(!copying.bucketed());
+        assert_eq!(source, copying.data);
+
+        let mut main = reader.next_entry().unwrap().unwrap();
+        assert!(main.bucketed());
+        assert!(main.data.len() < source.len());
+        main.decompress(&registry).unwrap();
+        assert_eq!(source, main.data);
+        assert!(!main.bucketed());
+    }
+
+    #[test]
+    fn same_length_but_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let sneaky = dir.path().join("sneaky");
+        // strings turns the \x02 into a 0 separator, without changing the length
+        fs::write(&sneaky, b"abcd\x01e\x02").unwrap();
+
+        let entries = vec![entry("a", Some(sneaky), Status::Unrecognised)];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut expected = Vec::new();
+        record(
+            &mut expected,
+            &meta().flags(1).hash_of(b"abcd\x01e\x02").name("a").build(),
+            b"abcd\x01e\x00",
+        );
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("script");
+        fs::write(&script, b"#!/bin/sh\n").unwrap();
+
+        let mut executable = entry("configure", Some(script.clone()), Status::Unrecognised);
+        executable.local.meta.ownership = Ownership::Posix {
+            user: None,
+            group: None,
+            mode: 0o104_755,
+        };
+        let entries = vec![
+            executable,
+            entry("unknown", Some(script), Status::Unrecognised),
+        ];
+
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let configure = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(0o104_755), configure.mode());
+        assert_eq!(vec![&b"configure"[..]], configure.path_components());
+        let unknown = reader.next_entry().unwrap().unwrap();
+        assert_eq!(None, unknown.mode());
+        assert_eq!(vec![&b"unknown"[..]], unknown.path_components());
+    }
+
+    #[test]
+    fn archive_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![
+            entry("c", Some(text.clone()), Status::Unrecognised),
+            entry(
+                "b.tar",
+                None,
+                Status::Success(vec![
+                    entry("z", Some(text.clone()), Status::Unrecognised),
+                    entry("y", Some(text.clone()), Status::Unrecognised),
+                    entry("x", Some(text.clone()), Status::Unrecognised),
+                ]),
+            ),
+            entry("a", Some(text), Status::Unrecognised),
+        ];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let mut found = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            let path = String::from_utf8(entry.path_components().join(&b'/')).unwrap();
+            found.push((path, entry.archive_index().unwrap()));
+        }
+        assert_eq!(
+            vec![
+                ("a".to_string(), 2),
+                ("b.tar".to_string(), 1),
+                ("b.tar/x".to_string(), 2),
+                ("b.tar/y".to_string(), 1),
+                ("b.tar/z".to_string(), 0),
+                ("c".to_string(), 0),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn colliding_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let short = dir.path().join("short");
+        fs::write(&short, b"one\n").unwrap();
+        let long = dir.path().join("long");
+        fs::write(&long, b"one\ntwo\n").unwrap();
+
+        let entries = || {
+            let mut directory = entry("a", None, Status::Unrecognised);
+            directory.local.meta.item_type = ItemType::Directory;
+            vec![
+                entry("a", Some(long.clone()), Status::Unrecognised),
+                directory,
+                entry("a", Some(short.clone()), Status::Unrecognised),
+            ]
+        };
+        let reversed: Vec<Entry> = entries().into_iter().rev().collect();
+
+        let records = |entries: &[Entry]| {
+            let mut out = Vec::new();
+            super::output(entries, &[], &mut out).unwrap();
+            let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+            let mut records = Vec::new();
+            while let Some(entry) = reader.next_entry().unwrap() {
+                records.push((entry.kind(), entry.data.clone(), entry.archive_index()));
+            }
+            records
+        };
+
+        // files before directories, then the shorter first, whatever order they came in
+        assert_eq!(
+            vec![
+                (Some(1), b"one\n".to_vec(), Some(2)),
+                (Some(1), b"one\ntwo\n".to_vec(), Some(0)),
+                (Some(2), Vec::new(), Some(1)),
+            ],
+            records(&entries())
+        );
+        assert_eq!(
+            vec![
+                (Some(1), b"one\n".to_vec(), Some(0)),
+                (Some(1), b"one\ntwo\n".to_vec(), Some(2)),
+                (Some(2), Vec::new(), Some(1)),
+            ],
+            records(&reversed)
+        );
+    }
+
+    #[test]
+    fn raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"\x7fELF\x02\x01\x01\x00\x00 hello\r\n").unwrap();
+
+        let raw = Config {
+            raw: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        let entries = vec![entry("a.out", Some(binary), Status::Unrecognised)];
+        super::output_with(&entries, &[], &mut out, &raw).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(crate::flags::ContentState::Raw), entry.content_state());
+        assert_eq!(Some(17), entry.original_len());
+        assert_eq!(
+            b"\x7fELF\x02\x01\x01\x00\x00 hello\r\n",
+            entry.data.as_slice()
+        );
+        let mut sha256 = Sha256::new();
+        sha256.input(b"\x7fELF\x02\x01\x01\x00\x00 hello\r\n");
+        assert_eq!(Some(&super::digest(sha256)[..]), entry.content_hash());
+    }
+
+    #[test]
+    fn raw_too_big_to_hold() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = dir.path().join("big");
+        let data = (0..super::RAW_HELD + 7)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        fs::write(&big, &data).unwrap();
+        let mut out = Vec::new();
+        let entries = vec![entry("big", Some(big), Status::Unrecognised)];
+        let raw = Config {
+            raw: true,
+            ..Config::default()
+        };
+        super::output_with(&entries, &[], &mut out, &raw).unwrap();
+
+        // read again, through the same handle, after it was hashed
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!(data, entry.data);
+        let mut sha256 = Sha256::new();
+        sha256.input(&data);
+        assert_eq!(Some(&super::digest(sha256)[..]), entry.content_hash());
+    }
+
+    #[test]
+    fn expanded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"hello\x01\x02\x03\x04world\n").unwrap();
+        let mut compressed = gzip.finish().unwrap();
+        // not part of the gzip stream, but still part of the member, so of its hash
+        compressed.extend_from_slice(b"trailing");
+        let leaf = dir.path().join("leaf");
+        fs::write(&leaf, &compressed).unwrap();
+
+        let expand = Config {
+            expand_leaf_compression: true,
+            max_member_bytes: 1024,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        let entries = vec![entry("a.gz", Some(leaf), Status::Unrecognised)];
+        super::output_with(&entries, &[], &mut out, &expand).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!(
+            Some(crate::flags::ContentState::Expanded),
+            entry.content_state()
+        );
+        assert_eq!(b"hello\0world\n", entry.data.as_slice());
+        let mut sha256 = Sha256::new();
+        sha256.input(&compressed);
+        assert_eq!(Some(&super::digest(sha256)[..]), entry.content_hash());
+    }
+
+    #[test]
+    fn skip_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("png");
+        fs::write(&png, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR tEXt some words").unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"hello\n").unwrap();
+
+        let entries = || {
+            vec![
+                entry("a.png", Some(png.clone()), Status::Unrecognised),
+                entry("b.txt", Some(text.clone()), Status::Unrecognised),
+            ]
+        };
+        let flags = |config: &Config| {
+            let mut out = Vec::new();
+            super::output_with(&entries(), &[], &mut out, config).unwrap();
+            let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+            let mut flags = Vec::new();
+            while let Some(entry) = reader.next_entry().unwrap() {
+                flags.push((entry.content_flag().unwrap(), entry.data.is_empty()));
+            }
+            flags
+        };
+
+        assert_eq!(vec![(1, false), (0, false)], flags(&Config::default()));
+
+        let defaults = Config {
+            skip_binary: Some(
+                super::DEFAULT_BINARY_MAGICS
+                    .iter()
+                    .map(|magic| magic.to_vec())
+                    .collect(),
+            ),
+            ..Config::default()
+        };
+        assert_eq!(vec![(19, true), (0, false)], flags(&defaults));
+
+        let hello = Config {
+            skip_binary: Some(vec![super::parse_magic("68656c6c6f").unwrap()]),
+            ..Config::default()
+        };
+        assert_eq!(vec![(1, false), (19, true)], flags(&hello));
+    }
+
+    #[test]
+    fn parse_magic() {
+        assert_eq!(b"\x89PNG".to_vec(), super::parse_magic("89504E47").unwrap());
+        assert!(super::parse_magic("").is_err());
+        assert!(super::parse_magic("895").is_err());
+        assert!(super::parse_magic("zz").is_err());
+        assert!(super::parse_magic("\u{e9}a").is_err());
+    }
+
+    #[test]
+    fn mime() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("png");
+        fs::write(&png, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"hello\n").unwrap();
+
+        let entries = vec![
+            entry("a.png", Some(png), Status::Unrecognised),
+            entry("b.txt", Some(text), Status::Unrecognised),
+        ];
+        let config = Config {
+            detect_mime: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &config).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some("image/png"), a.mime());
+        assert_eq!(Some(1), a.content_flag());
+        assert_eq!(vec![&b"a.png"[..]], a.path_components());
+
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some("text/plain"), b.mime());
+        assert_eq!(Some(0), b.content_flag());
+        assert_eq!(b"hello\n", b.data.as_slice());
+    }
+
+    #[test]
+    fn content_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        let original = b"hello\x01\x02\x03\x04world".repeat(3);
+        fs::write(&binary, &original).unwrap();
+
+        let mut directory = entry("d", None, Status::Unnecessary);
+        directory.local.meta.item_type = ItemType::Directory;
+        let entries = vec![
+            entry("a", Some(binary.clone()), Status::Unrecognised),
+            entry("b", Some(binary), Status::Unrecognised),
+            directory,
+        ];
+        let dedup = Config {
+            dedup_members: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &dedup).unwrap();
+        let too_large = Config {
+            max_file_size: Some(10),
+            ..Config::default()
+        };
+        let mut skipped = Vec::new();
+        super::output_with(&entries[..1], &[], &mut skipped, &too_large).unwrap();
+
+        let mut sha256 = Sha256::new();
+        sha256.input(&original);
+        let expected = super::digest(sha256);
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(1), a.content_flag());
+        assert_ne!(original, a.data);
+        assert_eq!(Some(&expected[..]), a.content_hash());
+
+        // only the first copy is read
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(14), b.content_flag());
+        assert_eq!(None, b.content_hash());
+
+        let d = reader.next_entry().unwrap().unwrap();
+        assert_eq!(None, d.content_hash());
+
+        let mut reader = crate::reader::AnnulReader::new(skipped.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(16), a.content_flag());
+        assert_eq!(None, a.content_hash());
+    }
+
+    #[test]
+    fn deadline() {
+        let mut out = Vec::new();
+        let mut late = super::Deadline {
+            inner: &mut out,
+            deadline: Some(Instant::now()),
+        };
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            late.write_all(b"hello").unwrap_err().kind()
+        );
+
+        let mut early = super::Deadline {
+            inner: &mut out,
+            deadline: Some(Instant::now() + Duration::from_secs(3600)),
+        };
+        early.write_all(b"hello").unwrap();
+        assert_eq!(b"hello", out.as_slice());
+
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"hello\n").unwrap();
+        let entries = vec![entry("a.txt", Some(text), Status::Unrecognised)];
+
+        let late = Config {
+            deadline: Some(Instant::now()),
+            ..Config::default()
+        };
+        assert!(super::output_with(&entries, &[], &mut Vec::new(), &late).is_err());
+        assert_eq!(
+            1,
+            super::output(&entries, &[], &mut Vec::new())
+                .unwrap()
+                .entries
+        );
+    }
+
+    #[test]
+    fn original_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world").unwrap();
+
+        let entries = vec![
+            entry("b", Some(binary), Status::Unrecognised),
+            entry("c", None, Status::Unnecessary),
+        ];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(14), b.original_len());
+        assert_eq!(11, b.data.len());
+        let c = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(0), c.original_len());
+    }
+
+    #[test]
+    fn kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty");
+        fs::write(&empty, b"").unwrap();
+
+        let mut directory = entry("d", None, Status::Unnecessary);
+        directory.local.meta.item_type = ItemType::Directory;
+        let entries = vec![
+            directory,
+            entry("e", Some(empty), Status::Unrecognised),
+            entry("f", None, Status::Unnecessary),
+        ];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let d = reader.next_entry().unwrap().unwrap();
+        assert_eq!((Some(2), Some(2)), (d.content_flag(), d.kind()));
+        let e = reader.next_entry().unwrap().unwrap();
+        assert_eq!((Some(15), Some(1)), (e.content_flag(), e.kind()));
+        // no content either, but not a directory
+        let f = reader.next_entry().unwrap().unwrap();
+        assert_eq!((Some(2), Some(1)), (f.content_flag(), f.kind()));
+    }
+
+    #[test]
+    fn contained() {
+        assert_eq!(None, super::contained(b"src/main.c"));
+        assert_eq!(None, super::contained(b"./configure"));
+        assert_eq!(None, super::contained(b"a..b/..c"));
+        assert_eq!(
+            Some(b"etc/passwd".to_vec()),
+            super::contained(b"../../etc/passwd")
+        );
+        assert_eq!(
+            Some(b"etc/shadow".to_vec()),
+            super::contained(b"/etc/shadow")
+        );
+        assert_eq!(Some(b"a/b".to_vec()), super::contained(b"a/../b/.."));
+        assert_eq!(Some(Vec::new()), super::contained(b".."));
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        let entries = vec![
+            entry("../../etc/passwd", None, Status::Unnecessary),
+            entry(
+                "/srv/evil.tar",
+                None,
+                Status::Success(vec![entry("../x", None, Status::Unnecessary)]),
+            ),
+            entry("fine", None, Status::Unnecessary),
+        ];
+        let mut out = Vec::new();
+        let stats = super::output_with(&entries, &[], &mut out, &Config::default()).unwrap();
+        assert_eq!(3, stats.unsafe_paths);
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let mut found = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            found.push((
+                entry.path_components().join(&b'/'),
+                entry.unsafe_path(),
+                entry.kind(),
+            ));
+        }
+        assert_eq!(
+            vec![
+                (b"etc/passwd".to_vec(), true, Some(1)),
+                (b"srv/evil.tar".to_vec(), true, Some(1)),
+                (b"srv/evil.tar/x".to_vec(), true, Some(1)),
+                (b"fine".to_vec(), false, Some(1)),
+            ],
+            found
+        );
+        assert!(crate::fsck::check(
+            &[&crate::FORMAT_MAGIC[..], &[crate::FORMAT_VERSION], &out].concat()[..]
+        )
+        .problem
+        .is_none());
+    }
+
+    #[test]
+    fn symlink() {
+        let mut link = entry("l", None, Status::Unnecessary);
+        link.local.meta.item_type = ItemType::SymbolicLink(b"../../etc/passwd".to_vec().into());
+        let entries = vec![entry("d", None, Status::Unnecessary), link];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let d = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(2), d.content_flag());
+        assert!(d.data.is_empty());
+        let l = reader.next_entry().unwrap().unwrap();
+        assert_eq!((Some(18), Some(3)), (l.content_flag(), l.kind()));
+        assert_eq!(b"../../etc/passwd", l.data.as_slice());
+    }
+
+    #[test]
+    fn reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let broken = dir.path().join("broken");
+        fs::write(&broken, b"not really a tarball\n").unwrap();
+
+        let entries = vec![
+            entry(
+                "a.tar",
+                Some(broken),
+                Status::Error("bad header".to_string()),
+            ),
+            entry("b", None, Status::Unnecessary),
+        ];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(7), a.children_status());
+        assert_eq!(Some("bad header"), a.reason());
+        assert_eq!(vec![&b"a.tar"[..]], a.path_components());
+        assert_eq!(b"not really a tarball\n", a.data.as_slice());
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(None, b.reason());
+        assert_eq!(vec![&b"b"[..]], b.path_components());
+    }
+
+    #[test]
+    fn index() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries: Vec<_> = (0..5)
+            .map(|i| {
+                let path = dir.path().join(format!("m{}", i));
+                fs::write(&path, format!("member {}\n", i).repeat(i + 1)).unwrap();
+                entry(&format!("m{}", i), Some(path), Status::Unrecognised)
+            })
+            .collect();
+
+        let config = Config {
+            index: true,
+            ..Config::default()
+        };
+        let mut stream = Vec::new();
+        super::write_header(&mut stream).unwrap();
+        assert_eq!(super::HEADER_LEN, stream.len() as u64);
+        super::output_with(&entries, &[], &mut stream, &config).unwrap();
+
+        // read through, the index is just the end
+        let mut from = &stream[..];
+        crate::reader::read_header(&mut from).unwrap();
+        let mut reader = crate::reader::AnnulReader::new(from);
+        assert_eq!(5, reader.entries().count());
+        let offsets = reader.index().expect("an index").to_vec();
+        assert_eq!(5, offsets.len());
+        assert_eq!(super::HEADER_LEN, offsets[0]);
+
+        // or it can be found first, and used to go straight to an entry
+        let mut from = io::Cursor::new(&stream[..]);
+        crate::reader::read_header(&mut from).unwrap();
+        let mut reader = crate::reader::AnnulReader::new(from);
+        assert!(reader.load_index().unwrap());
+        assert_eq!(Some(&offsets[..]), reader.index());
+        reader.seek_to(3).unwrap();
+        let m3 = reader.next_entry().unwrap().unwrap();
+        assert_eq!(vec![&b"m3"[..]], m3.path_components());
+        assert_eq!("member 3\n".repeat(4).as_bytes(), m3.data.as_slice());
+        assert!(reader.seek_to(5).is_err());
+
+        let mut unindexed = Vec::new();
+        super::write_header(&mut unindexed).unwrap();
+        super::output(&entries, &[], &mut unindexed).unwrap();
+        let mut reader = crate::reader::AnnulReader::new(io::Cursor::new(&unindexed[..]));
+        assert!(!reader.load_index().unwrap());
+        assert!(reader.seek_to(0).is_err());
+        assert!(crate::fsck::check(&stream[..]).problem.is_none());
+    }
+
+    #[test]
+    fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![
+            entry("a", None, Status::Unnecessary),
+            entry(
+                "z.tar",
+                None,
+                Status::Success(vec![entry("inner", Some(text), Status::Unrecognised)]),
+            ),
+        ];
+        let manifest = dir.path().join("manifest.json");
+        let config = Config {
+            manifest: Some(manifest.clone()),
+            detect_mime: true,
+            ..Config::default()
+        };
+        let mut stream = Vec::new();
+        super::write_header(&mut stream).unwrap();
+        super::output_with(&entries, &[], &mut stream, &config).unwrap();
+
+        let json = fs::read_to_string(&manifest).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(5, lines.len(), "{}", json);
+        assert_eq!(("[", "]"), (lines[0], lines[4]));
+        assert!(lines[1].starts_with(r#"{"path":"a","content":2,"children":3,"#));
+        assert!(lines[3].starts_with(r#"{"path":"z.tar/inner","content":0,"#));
+        assert!(!lines[1].contains("mime"), "{}", lines[1]);
+        assert!(
+            lines[3].ends_with(r#","mime":"text/plain"}"#),
+            "{}",
+            lines[3]
+        );
+
+        // the offsets are into the stream, header and all
+        let field = |line: &str, name: &str| -> usize {
+            let start = line.find(name).unwrap() + name.len() + 1;
+            let len = line[start..].find([',', '}']).unwrap();
+            line[start..start + len].parse().unwrap()
+        };
+        let offset = field(lines[3], "\"data_offset\"");
+        let len = field(lines[3], "\"data_len\"");
+        assert_eq!(b"plain text\n", &stream[offset..offset + len]);
+    }
+
+    #[test]
+    fn stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world").unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![
+            entry("a", Some(text), Status::Unrecognised),
+            entry("b", Some(binary), Status::Unrecognised),
+            entry("c", None, Status::TooNested),
+        ];
+        let mut out = Vec::new();
+        let stats = super::output_with(&entries, &[], &mut out, &Config::default()).unwrap();
+        let mut by_flag = [0; 32];
+        by_flag[0] = 1;
+        by_flag[1] = 1;
+        by_flag[2] = 1;
+        assert_eq!(
+            super::Stats {
+                entries: 3,
+                input_bytes: 11 + 14,
+                stream_bytes: out.len() as u64,
+                too_nested: 1,
+                unsafe_paths: 0,
+                by_flag,
+            },
+            stats
+        );
+        assert_eq!(
+            (1, 1, 1, 0),
+            (
+                stats.changed(),
+                stats.unchanged(),
+                stats.no_content(),
+                stats.duplicates()
+            )
+        );
+    }
+
+    #[test]
+    fn list() {
+        let entries = vec![
+            entry(
+                "z.tar",
+                None,
+                Status::Success(vec![
+                    entry("inner", None, Status::Error("bad header".to_string())),
+                    entry("b", None, Status::Unrecognised),
+                ]),
+            ),
+            entry("a", None, Status::TooNested),
+        ];
+
+        let mut out = Vec::new();
+        assert_eq!(4, super::list(&entries, &mut out).unwrap());
+        assert_eq!(
+            "a\tTooNested\n\
+             z.tar\tSuccess: 2 entries\n\
+             z.tar/b\tUnrecognised\n\
+             z.tar/inner\tError: bad header\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries: Vec<Entry> = (0..super::BATCH + 20)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                fs::write(&path, format!("member {}\x01\x02\x03{}\n", i % 7, i % 3)).unwrap();
+                entry(&format!("m{:04}", i), Some(path), Status::Unrecognised)
+            })
+            .collect();
+
+        let serial = Config {
+            threads: Some(1),
+            dedup_members: true,
+            ..Config::default()
+        };
+        let mut expected = Vec::new();
+        super::output_with(&entries, &[], &mut expected, &serial).unwrap();
+
+        let parallel = Config {
+            threads: Some(4),
+            ..serial
+        };
+        let mut actual = Vec::new();
+        super::output_with(&entries, &[], &mut actual, &parallel).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty");
+        fs::write(&empty, b"").unwrap();
+
+        let entries = vec![entry("a", Some(empty), Status::Unrecognised)];
+        let mut out = Vec::new();
+        super::output(&entries, &[], &mut out).unwrap();
+
+        let mut expected = Vec::new();
+        record(
+            &mut expected,
+            &meta().flags(15).hash_of(b"").name("a").build(),
+            b"",
+        );
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("out.annul");
+        let written = |content: &[u8]| {
+            let mut out = tempfile_fast::PersistableTempFile::new_in(dir.path()).unwrap();
+            out.write_all(content).unwrap();
+            out
+        };
+
+        super::persist(written(b"first"), &dest, false).unwrap();
+        assert_eq!(b"first", fs::read(&dest).unwrap().as_slice());
+
+        assert!(super::persist(written(b"second"), &dest, false).is_err());
+        assert_eq!(b"first", fs::read(&dest).unwrap().as_slice());
+
+        super::persist(written(b"third"), &dest, true).unwrap();
+        assert_eq!(b"third", fs::read(&dest).unwrap().as_slice());
+    }
+
+    #[test]
+    fn checksummed() {
+        let mut out = super::Checksummed::new(Vec::new());
+        out.write_all(b"1234").unwrap();
+        out.write_all(b"56789").unwrap();
+        assert_eq!(0xcbf4_3926, out.crc.finalize());
+        assert_eq!(b"123456789", out.inner.as_slice());
+    }
+
+    #[test]
+    fn counted() {
+        let mut counted = super::Counted {
+            inner: Vec::new(),
+            written: 0,
+        };
+        counted.write_all(b"hello").unwrap();
+        counted.write_all(b" world").unwrap();
+        assert_eq!(11, counted.written);
+        assert_eq!(b"hello world", counted.inner.as_slice());
+    }
+
+    #[test]
+    fn max_file_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small");
+        fs::write(&small, b"tiny\n").unwrap();
+        let large = dir.path().join("large");
+        fs::write(&large, b"rather larger\n").unwrap();
+
+        let entries = vec![
+            entry("a", Some(small), Status::Unrecognised),
+            entry("b", Some(large), Status::Unrecognised),
+        ];
+        let config = Config {
+            max_file_size: Some(5),
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &config).unwrap();
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let a = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(0), a.content_flag());
+        assert_eq!(b"tiny\n", a.data.as_slice());
+        let b = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(16), b.content_flag());
+        assert_eq!(Some(14), b.original_len());
+        assert!(b.data.is_empty());
+    }
+
+    #[test]
+    fn max_output_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small");
+        fs::write(&small, b"tiny\n").unwrap();
+        let large = dir.path().join("large");
+        fs::write(&large, b"rather larger\n").unwrap();
+
+        let entries = vec![
+            entry("a", Some(small), Status::Unrecognised),
+            entry("b", Some(large), Status::Unrecognised),
+        ];
+        let mut whole = Vec::new();
+        super::output(&entries, &[], &mut whole).unwrap();
+
+        let config = Config {
+            max_output_size: Some(whole.len() as u64),
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &config).unwrap();
+        assert_eq!(whole, out);
+
+        let config = Config {
+            max_output_size: Some(whole.len() as u64 - 1),
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        let err = super::output_marked(&entries, &mut out, &config).unwrap_err();
+        assert!(
+            err.to_string().contains("output size limit exceeded"),
+            "{}",
+            err
+        );
+        // more space wouldn't help, so it's not worth trying again
+        assert_eq!(None, super::Failure::of(&err));
+        // the first record fits, but none of the second is written
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        assert_eq!(
+            b"tiny\n",
+            reader.next_entry().unwrap().unwrap().data.as_slice()
+        );
+        assert!(reader.next_entry().unwrap().is_none());
+        assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn transcode_latin1() {
+        let dir = tempfile::tempdir().unwrap();
+        let changelog = dir.path().join("changelog");
+        fs::write(&changelog, b"Ren\xe9 M\xfcller\n").unwrap();
+        let entries = vec![entry("changelog", Some(changelog), Status::Unrecognised)];
+
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &Config::default()).unwrap();
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let plain = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(1), plain.content_flag());
+        assert_eq!(b"ller\n", plain.data.as_slice());
+
+        let config = Config {
+            transcode_latin1: true,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        super::output_with(&entries, &[], &mut out, &config).unwrap();
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let transcoded = reader.next_entry().unwrap().unwrap();
+        assert_eq!(Some(17), transcoded.content_flag());
+        assert_eq!(Some(12), transcoded.original_len());
+        assert_eq!(
+            "Ren\u{e9} M\u{fc}ller\n".as_bytes(),
+            transcoded.data.as_slice()
+        );
+    }
+
+    #[test]
+    fn read_chunk_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mixed = dir.path().join("mixed");
+        let mut data = Vec::new();
+        for i in 0..5000u32 {
+            data.extend_from_slice(format!("line {} caf\u{e9} \u{1f600}\n", i).as_bytes());
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        fs::write(&mixed, &data).unwrap();
+        let entries = vec![entry("mixed", Some(mixed), Status::Unrecognised)];
+
+        let with_chunk = |read_chunk_size, skip_length_compare| {
+            let config = Config {
+                read_chunk_size,
+                skip_length_compare,
+                ..Config::default()
+            };
+            let mut out = Vec::new();
+            super::output_with(&entries, &[], &mut out, &config).unwrap();
+            out
+        };
+
+        let expected = with_chunk(None, false);
+        for &size in &[1, 3, 7, 4096, 1024 * 1024] {
+            assert_eq!(expected, with_chunk(Some(size), false), "{}", size);
+        }
+
+        let streamed = with_chunk(None, true);
+        for &size in &[1, 7, 1024 * 1024] {
+            assert_eq!(streamed, with_chunk(Some(size), true), "{}", size);
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mixed = dir.path().join("mixed");
+        let mut data = Vec::new();
+        for i in 0..5000u32 {
+            data.extend_from_slice(format!("line {} caf\u{e9} \u{1f600}\n", i).as_bytes());
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        fs::write(&mixed, &data).unwrap();
+
+        let strings = |mapped: bool, chunk| {
+            let file = fs::File::open(&mixed).unwrap();
+            let mut out = tempfile::tempfile().unwrap();
+            let read = if mapped {
+                let (read, hash) = super::stringify_mapped(&file, &mut out, chunk)
+                    .unwrap()
+                    .expect("a regular file");
+                let mut sha256 = Sha256::new();
+                sha256.input(&data);
+                assert_eq!(super::digest(sha256), hash);
+                read
+            } else {
+                super::stringify(&file, &mut out, chunk).unwrap()
+            };
+            assert_eq!(data.len() as u64, read);
+            out.seek(io::SeekFrom::Start(0)).unwrap();
+            let mut stringed = Vec::new();
+            out.read_to_end(&mut stringed).unwrap();
+            stringed
+        };
+
+        for &chunk in &[1, 7, 16 * 1024] {
+            assert_eq!(strings(false, chunk), strings(true, chunk), "{}", chunk);
+        }
+
+        // empty files can't be mapped, so are left to the read loop
+        let empty = dir.path().join("empty");
+        fs::write(&empty, b"").unwrap();
+        let file = fs::File::open(&empty).unwrap();
+        let mut out = tempfile::tempfile().unwrap();
+        assert!(super::stringify_mapped(&file, &mut out, 1024)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world").unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![
+            entry(
+                "z.tar",
+                None,
+                Status::Success(vec![entry("b", Some(binary), Status::Unrecognised)]),
+            ),
+            entry("a", Some(text), Status::TooNested),
+        ];
+
+        let mut out = Vec::new();
+        assert_eq!(
+            3,
+            super::output(&entries, &[b"outer".to_vec().into_boxed_slice()], &mut out)
+                .unwrap()
+                .entries
+        );
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let mut read = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            read.push((
+                entry.content_flag().unwrap(),
+                entry.children_status().unwrap(),
+                entry.path_components().join(&b'/'),
+                entry.data.clone(),
+            ));
+        }
+
+        assert_eq!(
+            vec![
+                (0, 5, b"outer/a".to_vec(), b"plain text\n".to_vec()),
+                (2, 8, b"outer/z.tar".to_vec(), Vec::new()),
+                (1, 4, b"outer/z.tar/b".to_vec(), b"hello\0world".to_vec()),
+            ],
+            read
+        );
+    }
+
+    #[test]
+    fn deeply_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let depth = 2000;
+        let mut tree = entry("leaf", Some(text), Status::Unrecognised);
+        for _ in 0..depth {
+            tree = entry("d", None, Status::Success(vec![tree]));
+        }
+        let entries = vec![tree];
+
+        let config = Config {
+            dedup_members: true,
+            ..Config::default()
+        };
+
+        // a small stack, so this would fail if output recursed
+        let (written, out, _entries) = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || {
+                let mut out = Vec::new();
+                let written = super::output_with(&entries, &[], &mut out, &config)
+                    .unwrap()
+                    .entries;
+                // dropping the tree recurses, too, so leave that to this thread
+                (written, out, entries)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(depth + 1, written);
+
+        let mut reader = crate::reader::AnnulReader::new(out.as_slice());
+        let mut last = None;
+        while let Some(entry) = reader.next_entry().unwrap() {
+            last = Some(entry);
+        }
+        let last = last.unwrap();
+        assert_eq!(depth as usize + 1, last.path_components().len());
+        assert_eq!(b"plain text\n", last.data.as_slice());
+    }
+
+    #[test]
+    fn zstd_level() {
+        assert_eq!(1, super::zstd_level("1").unwrap());
+        assert_eq!(19, super::zstd_level("19").unwrap());
+        assert_eq!(22, super::zstd_level("22").unwrap());
+        assert!(super::zstd_level("0").is_err());
+        assert!(super::zstd_level("23").is_err());
+        assert!(super::zstd_level("-1").is_err());
+        assert!(super::zstd_level("fast").is_err());
+    }
+
+    #[test]
+    fn golden() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"hello\x01\x02\x03\x04world").unwrap();
+        let text = dir.path().join("text");
+        fs::write(&text, b"plain text\n").unwrap();
+
+        let entries = vec![
+            entry(
+                "z.tar",
+                None,
+                Status::Success(vec![
+                    entry(
+                        "inner",
+                        None,
+                        Status::Success(vec![entry(
+                            "deep",
+                            Some(text.clone()),
+                            Status::Unrecognised,
+                        )]),
+                    ),
+                    entry("b", Some(binary), Status::Unrecognised),
+                ]),
+            ),
+            entry("a", Some(text), Status::TooNested),
+        ];
+
+        let mut actual = Vec::new();
+        let written = super::output(
+            &entries,
+            &[b"outer".to_vec().into_boxed_slice()],
+            &mut actual,
+        )
+        .unwrap()
+        .entries;
+        assert_eq!(5, written);
+
+        let mut expected = Vec::new();
+        record(
+            &mut expected,
+            &meta()
+                .children(5)
+                .hash_of(b"plain text\n")
+                .archive_index(1)
+                .name("outer")
+                .name("a")
+                .build(),
+            b"plain text\n",
+        );
+        record(
+            &mut expected,
+            &meta()
+                .flags(2)
+                .children(8)
+                .name("outer")
+                .name("z.tar")
+                .build(),
+            b"",
+        );
+        record(
+            &mut expected,
+            &meta()
+                .flags(1)
+                .hash_of(b"hello\x01\x02\x03\x04world")
+                .archive_index(1)
+                .name("outer")
+                .name("z.tar")
+                .name("b")
+                .build(),
+            b"hello\0world",
+        );
+        record(
+            &mut expected,
+            &meta()
+                .flags(2)
+                .children(8)
+                .name("outer")
+                .name("z.tar")
+                .name("inner")
+                .build(),
+            b"",
+        );
+        record(
+            &mut expected,
+            &meta()
+                .hash_of(b"plain text\n")
+                .name("outer")
+                .name("z.tar")
+                .name("inner")
+                .name("deep")
+                .build(),
+            b"plain text\n",
+        );
+
+        assert_eq!(
+            String::from_utf8_lossy(&expected),
+            String::from_utf8_lossy(&actual)
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Lists the records of an `.annul`, like `ls -R` over the original members.
+
+use std::io::Read;
+use std::io::Write;
+
+use failure::Error;
+
+use crate::reader::AnnulReader;
+
+/// Write the path of each record, a line each, returning how many there were.
+///
+/// With `long`, each path is preceded by how its content was stored, how long it originally
+/// was, and its `--detect-mime` type, tab-separated, with a `-` for anything not recorded.
+pub fn list<R: Read, W: Write>(
+    mut entries: AnnulReader<R>,
+    long: bool,
+    mut out: W,
+) -> Result<u64, Error> {
+    let mut listed = 0;
+
+    while let Some(entry) = entries.next_entry()? {
+        if long {
+            match (entry.content_state(), entry.content_flag()) {
+                (Some(state), _) => write!(out, "{:?}\t", state)?,
+                (None, Some(flag)) => write!(out, "{}\t", flag)?,
+                (None, None) => out.write_all(b"-\t")?,
+            }
+            match entry.original_len() {
+                Some(len) => write!(out, "{}\t", len)?,
+                None => out.write_all(b"-\t")?,
+            }
+            write!(out, "{}\t", entry.mime().unwrap_or("-"))?;
+        }
+
+        out.write_all(&entry.path_components().join(&b'/'))?;
+        out.write_all(b"\n")?;
+        listed += 1;
+    }
+
+    out.flush()?;
+    Ok(listed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::AnnulReader;
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    #[test]
+    fn list() {
+        let mut data = Vec::new();
+        record(
+            &mut data,
+            &meta().flags(2).children(8).kind(2).name("pkg").build(),
+            b"",
+        );
+        record(
+            &mut data,
+            &meta()
+                .original_len(6)
+                .mime("text/plain")
+                .name("pkg")
+                .name("README")
+                .build(),
+            b"hello\n",
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(
+            2,
+            super::list(AnnulReader::new(&data[..]), false, &mut out).unwrap()
+        );
+        assert_eq!("pkg\npkg/README\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        super::list(AnnulReader::new(&data[..]), true, &mut out).unwrap();
+        assert_eq!(
+            "NoContent\t0\t-\tpkg\nUnchanged\t6\ttext/plain\tpkg/README\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -1,180 +1,965 @@
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io;
-use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Component;
 use std::path::Path;
-
-use byteorder::WriteBytesExt;
-use byteorder::LE;
-use cast::u64;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+
+use annul::buckets;
+use annul::dict;
+use annul::expired;
+#[cfg(feature = "proto")]
+use annul::export;
+use annul::format;
+use annul::fsck;
+use annul::grep;
+use annul::ls;
+use annul::process;
+use annul::reader;
+use annul::recompress;
+use annul::strings;
+use annul::Config;
+use annul::Failure;
+use clap::value_t;
+use clap::App;
+use clap::AppSettings;
+use clap::Arg;
+use clap::ArgMatches;
+use clap::SubCommand;
 use failure::bail;
-use failure::ensure;
 use failure::err_msg;
 use failure::format_err;
+use failure::Context;
 use failure::Error;
 use failure::ResultExt;
-use splayers::Entry;
-use splayers::Status;
+use rayon::prelude::*;
+
+mod catalog;
+mod checkpoint;
+
+/// What the process exits with if it fails, by `Failure`, so a batch driver can tell which
+/// sources are worth trying again. Success is `0`, including for sources which were already
+/// done, or skipped by the `--checkpoint`. Anything else, e.g. a page which isn't a dsc, is `1`.
+const EXIT_OTHER: i32 = 1;
+/// the dsc, or a file it lists, couldn't be fetched; probably worth trying again, later
+const EXIT_DOWNLOAD: i32 = 3;
+/// a file didn't match the dsc; maybe worth trying again, once the mirror's finished syncing
+const EXIT_CHECKSUM: i32 = 4;
+/// a file couldn't be unpacked; it never will be
+const EXIT_UNPACK: i32 = 5;
+/// the output couldn't be written; worth trying again, once there's space
+const EXIT_WRITE: i32 = 6;
+/// the source took longer than `--per-source-timeout`; worth trying again, with longer
+const EXIT_TIMEOUT: i32 = 7;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", describe(&e));
+        std::process::exit(match Failure::of(&e) {
+            Some(Failure::Download) => EXIT_DOWNLOAD,
+            Some(Failure::Checksum) => EXIT_CHECKSUM,
+            Some(Failure::Unpack) => EXIT_UNPACK,
+            Some(Failure::Write) => EXIT_WRITE,
+            Some(Failure::Timeout) => EXIT_TIMEOUT,
+            None => EXIT_OTHER,
+        });
+    }
+}
+
+/// Every message in `e`'s chain, outermost first, without the `Failure` markers.
+fn describe(e: &Error) -> String {
+    e.iter_chain()
+        .filter(|cause| cause.downcast_ref::<Context<Failure>>().is_none())
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
 
-mod strings;
+fn run() -> Result<(), Error> {
+    // only warnings, unless RUST_LOG asks for more
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let matches = App::new("annul")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("write the entries of an existing output in another format, to stdout")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["proto"])
+                        .required(true),
+                )
+                .arg(Arg::with_name("file").required(true).help("an .annul file")),
+        )
+        .subcommand(
+            SubCommand::with_name("recompress")
+                .about("rewrite an existing output with different compression settings")
+                .arg(
+                    Arg::with_name("level")
+                        .long("level")
+                        .value_name("LEVEL")
+                        .default_value("8")
+                        .help("zstd compression level"),
+                )
+                .arg(
+                    Arg::with_name("dictionary")
+                        .long("dictionary")
+                        .value_name("DICTIONARY")
+                        .possible_values(&["orig", "diff", "debian"])
+                        .help("use this embedded dictionary, instead of the original's"),
+                )
+                .arg(Arg::with_name("src").required(true).help("an .annul file"))
+                .arg(
+                    Arg::with_name("dst")
+                        .required(true)
+                        .help("where to write the new .annul; must not exist"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("print the lines of an existing output's members which contain PATTERN")
+                .arg(
+                    Arg::with_name("pattern")
+                        .required(true)
+                        .help("a literal string, not a regex"),
+                )
+                .arg(Arg::with_name("file").required(true).help("an .annul file")),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("print the path of each of an existing output's records")
+                .arg(
+                    Arg::with_name("long")
+                        .long("long")
+                        .short("l")
+                        .help("also print how it was stored, its original length, and MIME type"),
+                )
+                .arg(Arg::with_name("file").required(true).help("an .annul file")),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("check every record of an existing output is well-formed")
+                .arg(Arg::with_name("file").required(true).help("an .annul file")),
+        )
+        .subcommand(
+            SubCommand::with_name("train-dict")
+                .about("train a dictionary for --bucket-dict from sample files, after strings")
+                .after_help(
+                    "Use a few hundred samples at the very least, like the members one bucket \
+                     would get, totalling around a hundred times the dictionary size.",
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .value_name("BYTES")
+                        .help("the most the dictionary can be [default: 112640, i.e. 110KiB]"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .required(true)
+                        .help("where to write the .zstd-dictionary"),
+                )
+                .arg(
+                    Arg::with_name("samples")
+                        .multiple(true)
+                        .required(true)
+                        .help("local files like the ones it'll be used for"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("strings")
+                .about("write the runs annul would keep from a local file, each ending in a 0")
+                .arg(
+                    Arg::with_name("min-len")
+                        .long("min-len")
+                        .value_name("BYTES")
+                        .default_value("4")
+                        .help("drop runs shorter than this"),
+                )
+                .arg(
+                    Arg::with_name("max-embedded-binary")
+                        .long("max-embedded-binary")
+                        .value_name("BYTES")
+                        .default_value("2")
+                        .help("let a run carry on through this many binary bytes in a row"),
+                )
+                .arg(
+                    Arg::with_name("max-run")
+                        .long("max-run")
+                        .value_name("BYTES")
+                        .help("split runs once they're this long"),
+                )
+                .arg(
+                    Arg::with_name("strip-bom")
+                        .long("strip-bom")
+                        .help("drop a UTF-8 byte order mark from the start of the file"),
+                )
+                .arg(
+                    Arg::with_name("whitespace")
+                        .long("whitespace")
+                        .value_name("MODE")
+                        .possible_values(&["preserve", "collapse", "drop"])
+                        .default_value("preserve")
+                        .help("keep whitespace as it is, squash each run of it to a space, or drop it"),
+                )
+                .arg(Arg::with_name("file").required(true).help("any file")),
+        )
+        .arg(
+            Arg::with_name("src")
+                .multiple(true)
+                .required_unless("from-file")
+                .help("urls, or local paths, of the dscs to fetch, along with the files they list"),
+        )
+        .arg(
+            Arg::with_name("dest")
+                .required(true)
+                .help("directory to write an .annul for each listed file into, or - for stdout"),
+        )
+        .arg(
+            Arg::with_name("from-file")
+                .long("from-file")
+                .value_name("FILE")
+                .help("also fetch each source listed in FILE, one per line"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .value_name("N")
+                .default_value("1")
+                .help("with many sources, work on this many at once"),
+        )
+        .arg(
+            Arg::with_name("expand-leaf-compression")
+                .long("expand-leaf-compression")
+                .help("decompress gzip/xz/bzip2 members that splayers didn't expand"),
+        )
+        .arg(
+            Arg::with_name("max-member-bytes")
+                .long("max-member-bytes")
+                .value_name("BYTES")
+                .default_value("268435456")
+                .help("give up expanding a member if it inflates beyond this"),
+        )
+        .arg(
+            Arg::with_name("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .help("record members larger than this without their content [default: no limit]"),
+        )
+        .arg(
+            Arg::with_name("max-output-size")
+                .long("max-output-size")
+                .value_name("BYTES")
+                .help("give up on a source whose records, before compression, are larger than this [default: no limit]"),
+        )
+        .arg(
+            Arg::with_name("skip-binary")
+                .long("skip-binary")
+                .help("record images, compiled code and fonts without their content"),
+        )
+        .arg(
+            Arg::with_name("binary-magic")
+                .long("binary-magic")
+                .value_name("HEX")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("skip-binary")
+                .help("what --skip-binary looks for at the start, instead of the usual list"),
+        )
+        .arg(
+            Arg::with_name("tee-raw")
+                .long("tee-raw")
+                .value_name("DIR")
+                .help("also save the exact downloaded bytes into this directory"),
+        )
+        .arg(
+            Arg::with_name("output-name")
+                .long("output-name")
+                .value_name("TEMPLATE")
+                .help(
+                    "what to call the output, in dest; {name} is the listed file's name, \
+                     and {hash} the sha256 of the dsc; default: {name}.annul",
+                ),
+        )
+        .arg(
+            Arg::with_name("catalog-csv")
+                .long("catalog-csv")
+                .value_name("FILE")
+                .help("append a summary row for this source to a csv file"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("skip sources already listed in FILE, and add this one once it's done"),
+        )
+        .arg(
+            Arg::with_name("normalize-text")
+                .long("normalize-text")
+                .help("drop byte order marks from text members"),
+        )
+        .arg(
+            Arg::with_name("normalize-crlf")
+                .long("normalize-crlf")
+                .requires("normalize-text")
+                .help("with --normalize-text, fold CRLF to LF in text members, too"),
+        )
+        .arg(
+            Arg::with_name("transcode-latin1")
+                .long("transcode-latin1")
+                .help("convert members which look like Latin-1 text to UTF-8, instead of dropping accents"),
+        )
+        .arg(
+            Arg::with_name("overwrite-stale-dicts")
+                .long("overwrite-stale-dicts")
+                .help("reprocess existing outputs compressed with a different dictionary"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("reprocess and replace existing outputs"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .conflicts_with_all(&[
+                    "expand-leaf-compression",
+                    "normalize-text",
+                    "transcode-latin1",
+                    "skip-length-compare",
+                ])
+                .help("store members exactly as they were, instead of only their strings"),
+        )
+        .arg(
+            Arg::with_name("skip-length-compare")
+                .long("skip-length-compare")
+                .help(
+                    "don't record whether strings changed a member, saving a temporary file \
+                     each, by running strings over it twice: once for its length, once to write it",
+                ),
+        )
+        .arg(
+            Arg::with_name("descend-formats")
+                .long("descend-formats")
+                .value_name("FORMATS")
+                .use_delimiter(true)
+                .multiple(true)
+                .possible_values(format::KNOWN)
+                .help("only look inside members in these formats, e.g. tar,zip"),
+        )
+        .arg(
+            Arg::with_name("shard-raw")
+                .long("shard-raw")
+                .value_name("DIR")
+                .help(
+                    "store raw leaf members in DIR by hash, and only reference them in the output",
+                ),
+        )
+        .arg(
+            Arg::with_name("dedup-members")
+                .long("dedup-members")
+                .help("only store the first of identical members in a source, referring back to it"),
+        )
+        .arg(
+            Arg::with_name("provenance")
+                .long("provenance")
+                .help("record the source url and tool version in a frame zstd itself skips"),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .value_name("FILE")
+                .help("also write a JSON index of the records, and where their data is, to FILE"),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .help("end the output with where each record starts, for seeking straight to one"),
+        )
+        .arg(
+            Arg::with_name("skip-unreadable-members")
+                .long("skip-unreadable-members")
+                .help("carry on past members which can't be opened, marking them as errors"),
+        )
+        .arg(
+            Arg::with_name("bucket-dict")
+                .long("bucket-dict")
+                .value_name("BUCKET=FILE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("compress each member in BUCKET (e.g. c, text) alone, with this dictionary"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .help("print the entries found in the source, and their status, without writing"),
+        )
+        .arg(
+            Arg::with_name("detect-mime")
+                .long("detect-mime")
+                .help("guess and record the MIME type of each leaf member from its content"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .value_name("FORMAT")
+                .possible_values(&["zstd", "xz", "gzip", "none"])
+                .default_value("zstd")
+                .help("compress the output with this; only zstd uses a dictionary, or --level"),
+        )
+        .arg(
+            Arg::with_name("level")
+                .long("level")
+                .value_name("LEVEL")
+                .help("zstd compression level, 1-22; default: $ANNUL_ZSTD_LEVEL, or 8"),
+        )
+        .arg(
+            Arg::with_name("dictionary")
+                .long("dictionary")
+                .value_name("FILE")
+                .help("compress with this zstd dictionary, instead of an embedded one"),
+        )
+        .arg(
+            Arg::with_name("per-source-timeout")
+                .long("per-source-timeout")
+                .value_name("SECS")
+                .help("give up on the source, as a timeout, if it takes longer than this; partial output is never persisted, for the file being worked on, but any finished before it are kept; there's no --strict to choose otherwise"),
+        )
+        .arg(
+            Arg::with_name("read-chunk-size")
+                .long("read-chunk-size")
+                .value_name("BYTES")
+                .help("read members this much at a time [default: 16384]"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("strings this many members, or package this many of a source's files, at once; 1 for no threads [default: one per CPU]"),
+        )
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("export") {
+        return export(matches);
+    }
 
-fn main() -> Result<(), Error> {
-    let src = env::args().nth(1).ok_or(err_msg("first arg: src"))?;
-    let dest = env::args_os().nth(2).ok_or(err_msg("second arg: dest"))?;
-    let mut cwd = env::current_dir()?;
-    cwd.push(dest);
-    let dest = cwd;
-    fs::create_dir_all(&dest)?;
+    if let Some(matches) = matches.subcommand_matches("grep") {
+        let pattern = matches.value_of("pattern").expect("required");
+        let file = Path::new(matches.value_of_os("file").expect("required"));
+        let stdout = io::stdout();
+        grep::literal(
+            reader::open(file)?,
+            pattern.as_bytes(),
+            io::BufWriter::new(stdout.lock()),
+        )
+        .with_context(|_| format_err!("searching {:?}", file))?;
+        return Ok(());
+    }
 
-    let src_url = url::Url::parse(&src)?;
+    if let Some(matches) = matches.subcommand_matches("ls") {
+        let file = Path::new(matches.value_of_os("file").expect("required"));
+        let stdout = io::stdout();
+        ls::list(
+            reader::open(file)?,
+            matches.is_present("long"),
+            io::BufWriter::new(stdout.lock()),
+        )
+        .with_context(|_| format_err!("listing {:?}", file))?;
+        return Ok(());
+    }
 
-    let path = src_url
-        .path_segments()
-        .ok_or(err_msg("not path"))?
-        .last()
-        .ok_or(err_msg("no end path"))?;
+    if let Some(matches) = matches.subcommand_matches("strings") {
+        return strings(matches);
+    }
 
-    let out = dest.join(&format!("{}.annul", path));
+    if let Some(matches) = matches.subcommand_matches("train-dict") {
+        return train_dict(matches);
+    }
 
-    if out.exists() {
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let file = Path::new(matches.value_of_os("file").expect("required"));
+        let report = fsck::check(reader::decompress(file)?);
+        if let Some(problem) = report.problem {
+            bail!(
+                "{:?}: after {} good records, at offset {}: {}",
+                file,
+                report.records,
+                problem.offset,
+                problem.message
+            );
+        }
+        println!("{:?}: {} records, all ok", file, report.records);
         return Ok(());
     }
 
-    let mut dsc = Vec::new();
-    http_req::request::get(&src, &mut dsc).with_context(|_| err_msg("downloading dsc"))?;
+    if let Some(matches) = matches.subcommand_matches("recompress") {
+        let opts = recompress::RecompressOpts {
+            level: annul::zstd_level(matches.value_of("level").expect("default"))?,
+            dictionary: matches.value_of("dictionary").map(|name| match name {
+                "diff" => dict::Dictionary::Diff,
+                "debian" => dict::Dictionary::Debian,
+                _ => dict::Dictionary::Orig,
+            }),
+        };
+        let src = Path::new(matches.value_of_os("src").expect("required"));
+        let dst = Path::new(matches.value_of_os("dst").expect("required"));
+        return recompress::recompress(src, dst, opts);
+    }
+
+    let mut registry = buckets::Registry::default();
+    for spec in matches.values_of("bucket-dict").into_iter().flatten() {
+        let (bucket, file) = spec
+            .split_once('=')
+            .ok_or_else(|| format_err!("expected BUCKET=FILE, not {:?}", spec))?;
+        let dictionary =
+            fs::read(file).with_context(|_| format_err!("reading {} dictionary", bucket))?;
+        registry = registry.with(bucket, dictionary)?;
+    }
+
+    let timeout = matches
+        .value_of("per-source-timeout")
+        .map(str::parse::<u64>)
+        .transpose()
+        .with_context(|_| err_msg("parsing --per-source-timeout"))?
+        .map(Duration::from_secs);
+
+    let threads = matches
+        .value_of("threads")
+        .map(str::parse::<usize>)
+        .transpose()
+        .with_context(|_| err_msg("parsing --threads"))?;
+
+    let read_chunk_size = matches
+        .value_of("read-chunk-size")
+        .map(str::parse::<usize>)
+        .transpose()
+        .with_context(|_| err_msg("parsing --read-chunk-size"))?;
+    if Some(0) == read_chunk_size {
+        bail!("--read-chunk-size must be at least one byte");
+    }
+
+    if let Some(template) = matches.value_of("output-name") {
+        // mistakes in the template would otherwise only show up once each source is fetched
+        annul::output_name(template, "name", "hash").with_context(|_| err_msg("--output-name"))?;
+    }
 
-    let sub_url = src_url.join(&path)?;
+    let mut config = Config {
+        expand_leaf_compression: matches.is_present("expand-leaf-compression"),
+        max_member_bytes: value_t!(matches, "max-member-bytes", u64)?,
+        max_file_size: matches
+            .value_of("max-file-size")
+            .map(str::parse::<u64>)
+            .transpose()
+            .with_context(|_| err_msg("parsing --max-file-size"))?,
+        max_output_size: matches
+            .value_of("max-output-size")
+            .map(str::parse::<u64>)
+            .transpose()
+            .with_context(|_| err_msg("parsing --max-output-size"))?,
+        tee_raw: matches.value_of_os("tee-raw").map(PathBuf::from),
+        output_name: matches.value_of("output-name").map(str::to_string),
+        normalize_text: matches.is_present("normalize-text"),
+        normalize_crlf: matches.is_present("normalize-crlf"),
+        transcode_latin1: matches.is_present("transcode-latin1"),
+        overwrite_stale_dicts: matches.is_present("overwrite-stale-dicts"),
+        force: matches.is_present("force"),
+        skip_length_compare: matches.is_present("skip-length-compare"),
+        raw: matches.is_present("raw"),
+        descend_formats: matches
+            .values_of("descend-formats")
+            .map(|formats| formats.map(str::to_string).collect()),
+        skip_binary: match matches.values_of("binary-magic") {
+            _ if !matches.is_present("skip-binary") => None,
+            Some(magics) => Some(magics.map(annul::parse_magic).collect::<Result<_, _>>()?),
+            None => Some(
+                annul::DEFAULT_BINARY_MAGICS
+                    .iter()
+                    .map(|magic| magic.to_vec())
+                    .collect(),
+            ),
+        },
+        shard_raw: matches.value_of_os("shard-raw").map(PathBuf::from),
+        dedup_members: matches.is_present("dedup-members"),
+        provenance: matches.is_present("provenance"),
+        manifest: match matches.value_of_os("manifest") {
+            Some(manifest) => Some(env::current_dir()?.join(manifest)),
+            None => None,
+        },
+        skip_unreadable_members: matches.is_present("skip-unreadable-members"),
+        buckets: registry,
+        detect_mime: matches.is_present("detect-mime"),
+        index: matches.is_present("index"),
+        list: matches.is_present("list"),
+        compression: match matches.value_of("compression") {
+            Some("xz") => annul::Compression::Xz,
+            Some("gzip") => annul::Compression::Gzip,
+            Some("none") => annul::Compression::None,
+            _ => annul::Compression::Zstd,
+        },
+        threads,
+        read_chunk_size,
+        stdout: false,
+        deadline: None,
+        dictionary: match matches.value_of_os("dictionary") {
+            Some(file) => Some(
+                fs::read(file).with_context(|_| format_err!("reading dictionary {:?}", file))?,
+            ),
+            None => None,
+        },
+        level: match matches.value_of("level") {
+            Some(level) => Some(annul::zstd_level(level)?),
+            None => match env::var("ANNUL_ZSTD_LEVEL") {
+                Ok(level) => Some(
+                    annul::zstd_level(&level).with_context(|_| err_msg("in ANNUL_ZSTD_LEVEL"))?,
+                ),
+                Err(_) => None,
+            },
+        },
+    };
 
-    let mut tmp = tempfile::NamedTempFile::new_in(dest)?;
-    http_req::request::get(sub_url, &mut tmp).with_context(|_| err_msg("downloading"))?;
+    let mut sources: Vec<String> = matches
+        .values_of("src")
+        .into_iter()
+        .flatten()
+        .map(str::to_string)
+        .collect();
+    if let Some(list) = matches.value_of_os("from-file") {
+        let list = fs::read_to_string(list)
+            .with_context(|_| format_err!("reading source list {:?}", list))?;
+        sources.extend(
+            list.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
 
-    let dictionary = if path.contains(".diff.") {
-        &include_bytes!("../dicts/diff.zstd-dictionary")[..]
-    } else if path.contains(".debian.") {
-        &include_bytes!("../dicts/debian.tar.zstd-dictionary")[..]
+    let jobs = value_t!(matches, "jobs", usize)?;
+    let dest = matches.value_of_os("dest").expect("required");
+    let catalog_csv = matches.value_of_os("catalog-csv").map(Path::new);
+    let checkpoint = matches
+        .value_of_os("checkpoint")
+        .map(Path::new)
+        // listing a source doesn't package it
+        .filter(|_| !config.list);
+
+    // the output goes straight out, but the source is still fetched and unpacked somewhere
+    config.stdout = "-" == dest;
+    if config.stdout && 1 != sources.len() {
+        bail!("only one source can be written to stdout");
+    }
+    if config.provenance && annul::Compression::Zstd != config.compression {
+        bail!("--provenance is a zstd frame, so can't be used with other --compression");
+    }
+    if config.manifest.is_some() && 1 != sources.len() {
+        bail!("only one source can have a --manifest");
+    }
+    let dest = if config.stdout {
+        env::temp_dir()
     } else {
-        &include_bytes!("../dicts/orig.zstd-dictionary")[..]
+        PathBuf::from(dest)
+    };
+    let dest = prepare_dest(&env::current_dir()?, &dest)?;
+
+    let catalog = match catalog_csv {
+        Some(catalog_csv) => Some(Mutex::new(
+            catalog::open(catalog_csv)
+                .with_context(|_| format_err!("opening catalog {:?}", catalog_csv))?,
+        )),
+        None => None,
+    };
+
+    // read once, up front: it's only added to while the batch runs, by sources which aren't in it
+    let done = match checkpoint {
+        Some(checkpoint) => checkpoint::load(checkpoint)
+            .with_context(|_| format_err!("reading checkpoint {:?}", checkpoint))?,
+        None => HashSet::new(),
     };
 
-    std::thread::Builder::new()
-        .name(path.to_string())
-        .spawn(move || unarchive(tmp.path(), &out, dictionary))?
-        .join()
-        .map_err(|_| err_msg("panic"))
-        .with_context(|_| format_err!("processing {}", path))??;
+    let batch = Batch {
+        dest: &dest,
+        config: &config,
+        timeout,
+        catalog,
+        checkpoint,
+        done,
+    };
+
+    if let [src] = sources.as_slice() {
+        return batch.package(src);
+    }
+
+    // a failure is only the end of that source, not of the batch
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let results: Vec<(&String, Result<(), Error>)> = pool.install(|| {
+        sources
+            .par_iter()
+            .map(|src| {
+                // a panic is only the end of that source, too
+                let result = panic::catch_unwind(AssertUnwindSafe(|| batch.package(src)))
+                    .unwrap_or_else(|_| Err(err_msg("panic")));
+                (src, result)
+            })
+            .collect()
+    });
+
+    let mut failed = Vec::new();
+    for (src, result) in results {
+        if let Err(e) = result {
+            let failure = Failure::of(&e);
+            match failure {
+                Some(failure) => eprintln!("{}: failed, {}: {}", src, failure, describe(&e)),
+                None => eprintln!("{}: failed: {}", src, describe(&e)),
+            }
+            failed.push(failure);
+        }
+    }
+
+    if let Some(&first) = failed.first() {
+        let summary = format_err!("{} of {} sources failed", failed.len(), sources.len());
+        // the exit code can only say what went wrong if it was the same for all of them
+        return Err(match first {
+            Some(first) if failed.iter().all(|&failure| Some(first) == failure) => {
+                summary.context(first).into()
+            }
+            _ => summary,
+        });
+    }
 
     Ok(())
 }
 
-fn unarchive(src: &Path, dest: &Path, dictionary: &[u8]) -> Result<(), Error> {
-    let root = dest.parent().ok_or(err_msg("root?"))?;
+/// Everything about packaging a source except the source itself, shared by a whole batch.
+struct Batch<'a> {
+    dest: &'a Path,
+    config: &'a Config,
+    timeout: Option<Duration>,
+    /// opened, with the header written if it was new, before the batch started
+    catalog: Option<Mutex<fs::File>>,
+    checkpoint: Option<&'a Path>,
+    /// the sources the checkpoint listed when the batch started
+    done: HashSet<String>,
+}
+
+impl<'a> Batch<'a> {
+    fn package(&self, src: &str) -> Result<(), Error> {
+        if self.done.contains(src) {
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        let mut config = self.config.clone();
+        config.deadline = self.timeout.map(|timeout| started + timeout);
+        let result = process(src, self.dest, &config);
+
+        if self.catalog.is_some() {
+            let elapsed = started.elapsed();
+            match &result {
+                Ok(processed) => {
+                    for processed in processed {
+                        self.catalogue(
+                            src,
+                            &catalog::Row {
+                                url: src,
+                                package: &processed.package,
+                                version: &processed.version,
+                                output: Some(&processed.out),
+                                entries: processed.stats.entries,
+                                compressed_size: processed.compressed_size,
+                                status: "ok",
+                                elapsed,
+                            },
+                        );
+                    }
+                }
+                Err(e) => self.catalogue(
+                    src,
+                    &catalog::Row {
+                        url: src,
+                        // the error itself is buried under whatever it interrupted
+                        status: &if expired(config.deadline) {
+                            "timeout".to_string()
+                        } else {
+                            e.to_string()
+                        },
+                        elapsed,
+                        ..catalog::Row::default()
+                    },
+                ),
+            }
+        }
 
-    let unpack =
-        splayers::Unpack::unpack_into(src, &root).with_context(|_| err_msg("unpacking failed"))?;
+        for processed in result.iter().flatten() {
+            let stats = &processed.stats;
+            eprintln!(
+                "{}: {} entries ({} changed, {} unchanged, {} without content, {} duplicates, \
+                 {} too nested, {} unsafe paths), {} bytes in, {} bytes of records, \
+                 {} bytes on disk",
+                processed.out.display(),
+                stats.entries,
+                stats.changed(),
+                stats.unchanged(),
+                stats.no_content(),
+                stats.duplicates(),
+                stats.too_nested,
+                stats.unsafe_paths,
+                stats.input_bytes,
+                stats.stream_bytes,
+                processed.compressed_size,
+            );
+        }
 
-    let out = tempfile_fast::PersistableTempFile::new_in(&root)?;
+        result?;
 
-    let mut out = zstd::Encoder::with_dictionary(out, 8, dictionary)?;
+        if let Some(checkpoint) = self.checkpoint {
+            checkpoint::record(checkpoint, src)?;
+        }
 
-    match *unpack.status() {
-        splayers::Status::Success(ref entries) => output(entries, &[], &mut out)?,
-        ref other => bail!("expecting top level archive, not: {:?}", other),
+        Ok(())
     }
 
-    let out = out.finish()?;
+    /// Add `row` to the catalog, if there is one. The source is done, or has failed, either way,
+    /// so not being able to say so is only worth a mention.
+    fn catalogue(&self, src: &str, row: &catalog::Row) {
+        if let Some(catalog) = &self.catalog {
+            // a panic elsewhere doesn't leave the file any different
+            let mut file = catalog.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = catalog::append(&mut *file, row) {
+                eprintln!("{}: couldn't be added to the catalog: {}", src, e);
+            }
+        }
+    }
+}
 
-    out.persist_noclobber(dest).map_err(|e| e.error)?;
+fn export(matches: &ArgMatches) -> Result<(), Error> {
+    let file = Path::new(matches.value_of_os("file").expect("required"));
+
+    #[cfg(feature = "proto")]
+    match matches.value_of("format").expect("required") {
+        "proto" => {
+            let stdout = io::stdout();
+            let entries = reader::open(file)?;
+            export::proto(entries, io::BufWriter::new(stdout.lock()))
+                .with_context(|_| format_err!("exporting {:?}", file))?;
+            Ok(())
+        }
+        other => bail!("unsupported format: {:?}", other),
+    }
 
-    Ok(())
+    #[cfg(not(feature = "proto"))]
+    bail!("can't export {:?}: built without the 'proto' feature", file)
 }
 
-fn output<W: Write>(entries: &[Entry], paths: &[Box<[u8]>], out: &mut W) -> Result<(), Error> {
-    let mut entries: Vec<&Entry> = entries.iter().collect();
+fn strings(matches: &ArgMatches) -> Result<(), Error> {
+    let file = Path::new(matches.value_of_os("file").expect("required"));
+    let config = strings::StringsConfig {
+        min_len: value_t!(matches, "min-len", usize)?,
+        max_embedded_binary: value_t!(matches, "max-embedded-binary", usize)?,
+        max_run: matches
+            .value_of("max-run")
+            .map(str::parse::<usize>)
+            .transpose()
+            .with_context(|_| err_msg("parsing --max-run"))?,
+        strip_bom: matches.is_present("strip-bom"),
+        whitespace: match matches.value_of("whitespace") {
+            Some("collapse") => strings::Whitespace::Collapse,
+            Some("drop") => strings::Whitespace::Drop,
+            _ => strings::Whitespace::Preserve,
+        },
+    };
 
-    let mut name_prefix = Vec::with_capacity(paths.len() * 128);
-    for path in paths {
-        name_prefix.extend_from_slice(path);
-        name_prefix.push(0);
-    }
+    let src = fs::File::open(file).with_context(|_| format_err!("opening {:?}", file))?;
+    let stdout = io::stdout();
+    io::copy(
+        &mut strings::strings_reader(io::BufReader::new(src), config),
+        &mut io::BufWriter::new(stdout.lock()),
+    )?;
+    Ok(())
+}
 
-    entries.sort_by_key(|e| e.local.path.as_ref());
+fn train_dict(matches: &ArgMatches) -> Result<(), Error> {
+    let out = Path::new(matches.value_of_os("out").expect("required"));
+    let size = matches
+        .value_of("size")
+        .map(str::parse::<usize>)
+        .transpose()
+        .with_context(|_| err_msg("parsing --size"))?
+        .unwrap_or(dict::DEFAULT_TRAINED_SIZE);
+
+    let mut samples = Vec::new();
+    for sample in matches.values_of_os("samples").expect("required") {
+        let sample = Path::new(sample);
+        samples.push(io::BufReader::new(
+            fs::File::open(sample).with_context(|_| format_err!("opening {:?}", sample))?,
+        ));
+    }
 
-    for entry in entries {
-        let mut meta = Vec::with_capacity(1 + name_prefix.len() + entry.local.path.len());
+    let count = samples.len();
+    let dictionary = dict::train(samples, size)
+        .with_context(|_| format_err!("training from {} samples", count))?;
+    fs::write(out, &dictionary).with_context(|_| format_err!("writing {:?}", out))?;
+    println!("{:?}: {} bytes", out, dictionary.len());
+    Ok(())
+}
 
-        let file = if let Some(temp) = entry.local.temp.as_ref() {
-            let mut file = fs::File::open(temp)?;
-            let mut stringed = tempfile::tempfile_in(temp.parent().unwrap())?;
-            {
-                let mut stringer = strings::StringBuf::new(io::BufWriter::new(&mut stringed));
-                loop {
-                    let mut buf = [0u8; 16 * 1024];
-                    let len = file.read(&mut buf)?;
-                    if 0 == len {
-                        break;
-                    }
-                    let buf = &buf[..len];
-                    stringer.accept(buf)?;
-                }
-                stringer.finish()?.flush()?;
-            }
-            let original_len = file.metadata()?.len();
-            let new_len = stringed.metadata()?.len();
-            if original_len == new_len {
-                meta.push(0);
-            } else {
-                meta.push(1);
+/// Resolve `dest` against `cwd`, create it, and check we'll be able to write into it.
+///
+/// `..` is folded away before anything is created, so `a/../b` doesn't leave an `a` behind.
+/// The result is canonical, so its parent is never surprising.
+fn prepare_dest(cwd: &Path, dest: &Path) -> Result<PathBuf, Error> {
+    let mut resolved = PathBuf::new();
+    for component in cwd.join(dest).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
             }
+            Component::CurDir => (),
+            other => resolved.push(other.as_os_str()),
+        }
+    }
 
-            stringed.seek(SeekFrom::Start(0))?;
+    fs::create_dir_all(&resolved).with_context(|_| format_err!("creating {:?}", resolved))?;
+    let resolved = fs::canonicalize(&resolved)?;
 
-            Some((stringed, new_len))
-        } else {
-            meta.push(2);
-            None
-        };
+    tempfile::tempfile_in(&resolved)
+        .with_context(|_| format_err!("dest {:?} isn't writable", resolved))?;
 
-        match &entry.children {
-            Status::Unnecessary => meta.push(3),
-            Status::Unrecognised => meta.push(4),
-            Status::TooNested => meta.push(5),
-            Status::Unsupported(_) => meta.push(6),
-            Status::Error(_) => meta.push(7),
-            Status::Success(_) => meta.push(8),
-        }
-        meta.extend_from_slice(&name_prefix);
-        meta.extend_from_slice(&entry.local.path);
+    Ok(resolved)
+}
 
-        // hmm, trying to make the name distinct from the content, for grepping
-        meta.push(0);
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
 
-        let data_len = file.as_ref().map(|(_file, size)| *size).unwrap_or(0);
+    #[test]
+    fn dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = fs::canonicalize(dir.path()).unwrap().join("cwd");
+        fs::create_dir(&cwd).unwrap();
 
-        out.write_u64::<LE>(8 + data_len + u64(meta.len()))?;
-        out.write_u64::<LE>(u64(meta.len()))?;
-        out.write_all(&meta)?;
+        let relative = super::prepare_dest(&cwd, Path::new("out")).unwrap();
+        assert_eq!(cwd.join("out"), relative);
+        assert!(relative.is_dir());
 
-        if let Some((mut file, _)) = file {
-            let written = io::copy(&mut file, out)?;
-            ensure!(
-                written == data_len,
-                "short write: expected: {}, actual: {}",
-                data_len,
-                written
-            );
-        }
+        let absolute = cwd.parent().unwrap().join("elsewhere");
+        assert_eq!(absolute, super::prepare_dest(&cwd, &absolute).unwrap());
 
-        match &entry.children {
-            Status::Success(entries) => {
-                let mut paths = paths.to_vec();
-                paths.push(entry.local.path.clone());
-                output(&entries, &paths, out)?;
-            }
-            _ => (),
-        }
+        let dotted = super::prepare_dest(&cwd, Path::new("./a/../../b")).unwrap();
+        assert_eq!(cwd.parent().unwrap().join("b"), dotted);
+        assert!(!cwd.join("a").exists());
+
+        let file = cwd.join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(super::prepare_dest(&cwd, Path::new("file")).is_err());
     }
-    Ok(())
 }
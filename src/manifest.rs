@@ -0,0 +1,117 @@
+//! A JSON index of the records in an `.annul`, for `--manifest`, so consumers can find out
+//! what's in it, and seek straight to a member's data, without reading through the records.
+//!
+//! It's an array of objects, one per record, in order, e.g.
+//! `{"path":"foo.tar/README","content":1,"children":4,"original_len":120,"data_offset":321,
+//! "data_len":96}`. The offset is from the start of the decompressed `.annul`, header included.
+//! A member with a type from `--detect-mime` also has it, e.g. `"mime":"image/png"`.
+
+use crate::reader;
+
+/// What's recorded about each record.
+pub struct Entry<'a> {
+    /// the names of the containers it's in, then its own name, each prefixed with its length
+    pub names: &'a [u8],
+    pub content: u8,
+    pub children: u8,
+    pub original_len: u64,
+    pub data_offset: u64,
+    /// the content after strings, unless it's stored some other way, e.g. as a reference
+    pub data_len: u64,
+    /// recorded with `--detect-mime`
+    pub mime: Option<&'a str>,
+}
+
+/// Add `entry` to the (so far unterminated) array in `json`, starting it if it's empty.
+pub fn push(json: &mut Vec<u8>, entry: &Entry) {
+    json.extend_from_slice(if json.is_empty() { b"[\n" } else { b",\n" });
+
+    let path = reader::split_names(entry.names)
+        .unwrap_or_default()
+        .join(&b'/');
+
+    json.extend_from_slice(b"{\"path\":");
+    string(json, &String::from_utf8_lossy(&path));
+    json.extend_from_slice(
+        format!(
+            ",\"content\":{},\"children\":{},\"original_len\":{},\"data_offset\":{},\"data_len\":{}",
+            entry.content, entry.children, entry.original_len, entry.data_offset, entry.data_len
+        )
+        .as_bytes(),
+    );
+    if let Some(mime) = entry.mime {
+        json.extend_from_slice(b",\"mime\":");
+        string(json, mime);
+    }
+    json.push(b'}');
+}
+
+/// Close the array `push` has been adding to, which might not have anything in it.
+pub fn finish(json: &mut Vec<u8>) {
+    json.extend_from_slice(if json.is_empty() { b"[" } else { b"\n" });
+    json.extend_from_slice(b"]\n");
+}
+
+fn string(json: &mut Vec<u8>, value: &str) {
+    json.push(b'"');
+    for c in value.chars() {
+        match c {
+            '"' => json.extend_from_slice(b"\\\""),
+            '\\' => json.extend_from_slice(b"\\\\"),
+            c if c < ' ' => json.extend_from_slice(format!("\\u{:04x}", u32::from(c)).as_bytes()),
+            c => json.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    json.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Entry;
+
+    #[test]
+    fn json() {
+        let mut json = Vec::new();
+        super::finish(&mut json);
+        assert_eq!("[]\n", String::from_utf8(json).unwrap());
+
+        let mut json = Vec::new();
+        super::push(
+            &mut json,
+            &Entry {
+                names: b"\x07\x00foo.tar\x06\x00a \"b\"\n",
+                content: 1,
+                children: 4,
+                original_len: 120,
+                data_offset: 321,
+                data_len: 96,
+                mime: Some("text/plain"),
+            },
+        );
+        super::push(
+            &mut json,
+            &Entry {
+                names: b"\x04\x00caf\xe9",
+                content: 2,
+                children: 3,
+                original_len: 0,
+                data_offset: 400,
+                data_len: 0,
+                mime: None,
+            },
+        );
+        super::finish(&mut json);
+        assert_eq!(
+            concat!(
+                "[\n",
+                r#"{"path":"foo.tar/a \"b\"\u000a","content":1,"children":4,"#,
+                r#""original_len":120,"data_offset":321,"data_len":96,"mime":"text/plain"},"#,
+                "\n",
+                "{\"path\":\"caf\u{fffd}\",\"content\":2,\"children\":3,",
+                r#""original_len":0,"data_offset":400,"data_len":0}"#,
+                "\n]\n",
+            ),
+            String::from_utf8(json).unwrap()
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::io::Read;
+
+use crate::format;
+
+/// How much of a member `--detect-mime` looks at.
+const SNIFF_LEN: u64 = 4096;
+
+/// Guess a MIME type for some content, from its first few KiB.
+pub fn detect<R: Read>(src: R) -> io::Result<&'static str> {
+    let mut header = Vec::with_capacity(SNIFF_LEN as usize);
+    src.take(SNIFF_LEN).read_to_end(&mut header)?;
+    Ok(detect_header(&header))
+}
+
+fn detect_header(header: &[u8]) -> &'static str {
+    match header {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => return "image/png",
+        [0xff, 0xd8, 0xff, ..] => return "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7', b'a', ..] | [b'G', b'I', b'F', b'8', b'9', b'a', ..] => {
+            return "image/gif"
+        }
+        [b'%', b'P', b'D', b'F', b'-', ..] => return "application/pdf",
+        [0x7f, b'E', b'L', b'F', ..] => return "application/x-executable",
+        _ => (),
+    }
+
+    if let Some(archive) = format::detect_header(header) {
+        return match archive {
+            "7z" => "application/x-7z-compressed",
+            "ar" => "application/x-archive",
+            "bzip2" => "application/x-bzip2",
+            "cpio" => "application/x-cpio",
+            "gzip" => "application/gzip",
+            "rpm" => "application/x-rpm",
+            "tar" => "application/x-tar",
+            "xz" => "application/x-xz",
+            "zip" => "application/zip",
+            "zstd" => "application/zstd",
+            _ => "application/octet-stream",
+        };
+    }
+
+    if looks_like_text(header) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// UTF-8, allowing for a character cut off at the end, and no control characters but whitespace.
+fn looks_like_text(header: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(header) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&header[..e.valid_up_to()]).expect("just checked")
+        }
+        Err(_) => return false,
+    };
+
+    valid
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn png() {
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00\x00\x00\x01\x00\x00\x00\x01\x08\x06";
+        assert_eq!("image/png", super::detect(&png[..]).unwrap());
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            "text/plain",
+            super::detect(&b"#include <stdio.h>\n\nint main() {}\n"[..]).unwrap()
+        );
+        assert_eq!(
+            "text/plain",
+            super::detect("caf\u{e9}\n".as_bytes()).unwrap()
+        );
+        assert_eq!(
+            "application/octet-stream",
+            super::detect(&b"text\x00with a nul"[..]).unwrap()
+        );
+        assert_eq!(
+            "application/gzip",
+            super::detect(&b"\x1f\x8b\x08\x00"[..]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,903 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::iter;
+use std::path::Path;
+
+use byteorder::ByteOrder;
+use byteorder::ReadBytesExt;
+use byteorder::LE;
+use cast::u64;
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::buckets;
+use crate::dict;
+use crate::flags::ChildrenState;
+use crate::flags::ContentState;
+use crate::shard;
+use crate::skippable;
+use crate::Checksummed;
+
+/// Reads back the (decompressed) record stream written by `output`.
+///
+/// The input is not trusted: every length is checked against the frame it claims to be in, and
+/// against what's actually available, before anything is read.
+pub struct AnnulReader<R> {
+    inner: R,
+    index: u64,
+    /// content which later entries refer back to, by index; see `--dedup-members`
+    shared: HashMap<u64, (u8, Vec<u8>)>,
+    /// where each record starts, from the `--index` footer, once it's been found
+    offsets: Option<Vec<u64>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnulEntry {
+    pub meta: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Open an `.annul` file, which must have been compressed with one of the embedded dictionaries,
+/// or with xz or gzip, or not at all.
+pub fn open(path: &Path) -> Result<AnnulReader<impl Read>, Error> {
+    let mut decoder = decompress(path)?;
+    read_header(&mut decoder).with_context(|_| format_err!("reading {:?}", path))?;
+    Ok(AnnulReader::new(decoder))
+}
+
+/// The path components of an entry, then its content, from `iter_annul`.
+pub type Record = (Vec<Box<[u8]>>, Vec<u8>);
+
+/// The path components and content of each entry in an `.annul` file, as for `open`, with
+/// duplicates resolved. The content is as it was stored, i.e. after strings, or the hash, for
+/// `--shard-raw`; content compressed with a `--bucket-dict` dictionary is an error, as the
+/// dictionary isn't in the file. Nothing more is read after an error in the stream itself.
+pub fn iter_annul(path: &Path) -> Result<impl Iterator<Item = Result<Record, Error>>, Error> {
+    let mut reader = open(path)?;
+    let mut broken = false;
+    Ok(iter::from_fn(move || {
+        if broken {
+            return None;
+        }
+        let mut entry = match reader.next_entry() {
+            Ok(entry) => entry?,
+            Err(e) => {
+                broken = true;
+                return Some(Err(e));
+            }
+        };
+        Some(reader.resolve_duplicate(&mut entry).and_then(|()| {
+            ensure!(
+                !entry.bucketed(),
+                "content compressed with a bucket dictionary"
+            );
+            let path = entry
+                .path_components()
+                .into_iter()
+                .map(|component| component.to_vec().into_boxed_slice())
+                .collect();
+            Ok((path, entry.data))
+        }))
+    }))
+}
+
+/// The decompressed stream of an `.annul` file, as for `open`, but from before the header.
+pub fn decompress(path: &Path) -> Result<Box<dyn Read>, Error> {
+    let mut file = fs::File::open(path)?;
+    let mut magic = Vec::with_capacity(6);
+    (&mut file).take(6).read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match magic.as_slice() {
+        [0x1f, 0x8b, ..] => return Ok(Box::new(flate2::read::GzDecoder::new(file))),
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00] => {
+            return Ok(Box::new(xz2::read::XzDecoder::new(file)))
+        }
+        magic if magic.starts_with(&crate::FORMAT_MAGIC) => return Ok(Box::new(file)),
+        _ => (),
+    }
+
+    let id = dict::frame_id(&mut file)?
+        .ok_or_else(|| format_err!("no dictionary recorded in {:?}", path))?;
+    let dictionary =
+        dict::embedded(id).ok_or_else(|| format_err!("unknown dictionary: {:08x}", id))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Box::new(zstd::Decoder::with_dictionary(file, dictionary)?))
+}
+
+/// Check the stream starts with `FORMAT_MAGIC`, and a `FORMAT_VERSION` we understand.
+pub fn read_header<R: Read>(mut from: R) -> Result<(), Error> {
+    let mut header = [0u8; 5];
+    from.read_exact(&mut header)
+        .map_err(|e| format_err!("reading header: {}", e))?;
+
+    ensure!(
+        header[..4] == crate::FORMAT_MAGIC,
+        "not an annul file, or one from before the format had a header: {:?}",
+        String::from_utf8_lossy(&header[..4])
+    );
+
+    let version = header[4];
+    ensure!(
+        crate::FORMAT_VERSION == version,
+        "unsupported annul format version {}; this reader understands {}",
+        version,
+        crate::FORMAT_VERSION
+    );
+
+    Ok(())
+}
+
+/// The metadata written by `--provenance`, if the file has any.
+pub fn provenance(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    Ok(skippable::read(fs::File::open(path)?)?)
+}
+
+impl AnnulEntry {
+    /// How the content was transformed, e.g. `1`: changed by strings.
+    pub fn content_flag(&self) -> Option<u8> {
+        self.meta.first().map(|flag| flag & 0x1f)
+    }
+
+    /// `content_flag`, by name, if it's one this version knows about.
+    pub fn content_state(&self) -> Option<ContentState> {
+        self.content_flag().and_then(ContentState::from_u8)
+    }
+
+    /// The unix mode bits the archive had for it, if it said.
+    pub fn mode(&self) -> Option<u32> {
+        self.meta
+            .get(2..6)
+            .map(LE::read_u32)
+            .filter(|&mode| crate::UNKNOWN_MODE != mode)
+    }
+
+    /// How long the member was before strings, or `0` if it had no content.
+    pub fn original_len(&self) -> Option<u64> {
+        self.meta.get(6..14).map(LE::read_u64)
+    }
+
+    /// What the entry was in the archive, e.g. `1`: a regular file, `2`: a directory.
+    pub fn kind(&self) -> Option<u8> {
+        self.meta.get(14).map(|kind| kind & 0x7f)
+    }
+
+    /// Was the name made safe to extract, as the archive had it escaping, e.g. `../../etc/passwd`?
+    pub fn unsafe_path(&self) -> bool {
+        self.meta.get(14).is_some_and(|kind| 0 != kind & 0x80)
+    }
+
+    /// The sha256 of the member, as it was in the archive, if it was read, e.g. to strings it.
+    pub fn content_hash(&self) -> Option<&[u8]> {
+        self.meta
+            .get(15..47)
+            .filter(|hash| hash.iter().any(|&b| 0 != b))
+    }
+
+    /// Where the entry was amongst its siblings in the archive, before they were sorted by path.
+    pub fn archive_index(&self) -> Option<u32> {
+        self.meta.get(47..51).map(LE::read_u32)
+    }
+
+    /// The MIME type recorded by `--detect-mime`, if any.
+    pub fn mime(&self) -> Option<&str> {
+        let len = usize::from(*self.meta.get(51)?);
+        self.meta
+            .first()
+            .filter(|&flag| 0 != flag & 0x20)
+            .and_then(|_| self.meta.get(52..52 + len))
+            .and_then(|mime| std::str::from_utf8(mime).ok())
+    }
+
+    /// Why the content couldn't be unpacked, for children statuses `6` and `7`.
+    pub fn reason(&self) -> Option<&str> {
+        if !self.children_state().is_some_and(ChildrenState::has_reason) {
+            return None;
+        }
+        let start = self.after_mime();
+        let len = usize::from(LE::read_u16(self.meta.get(start..start + 2)?));
+        self.meta
+            .get(start + 2..start + 2 + len)
+            .and_then(|reason| std::str::from_utf8(reason).ok())
+    }
+
+    /// Where whatever follows the MIME type, if there is one, starts.
+    fn after_mime(&self) -> usize {
+        match self.mime() {
+            Some(mime) => 52 + mime.len(),
+            None => 51,
+        }
+    }
+
+    /// Is the content compressed on its own, with a `--bucket-dict` dictionary?
+    pub fn bucketed(&self) -> bool {
+        self.meta.first().is_some_and(|flag| 0 != flag & 0x40)
+    }
+
+    /// Do later entries refer back to this content?
+    pub fn shared(&self) -> bool {
+        self.meta.first().is_some_and(|flag| 0 != flag & 0x80)
+    }
+
+    /// What happened when trying to unpack the content, e.g. `8`: success.
+    pub fn children_status(&self) -> Option<u8> {
+        self.meta.get(1).cloned()
+    }
+
+    /// `children_status`, by name, if it's one this version knows about.
+    pub fn children_state(&self) -> Option<ChildrenState> {
+        self.children_status().and_then(ChildrenState::from_u8)
+    }
+
+    /// The names of the containers this was found in, outermost first, then its own name.
+    pub fn path_components(&self) -> Vec<&[u8]> {
+        let start = match self.reason() {
+            Some(reason) => self.after_mime() + 2 + reason.len(),
+            None => self.after_mime(),
+        };
+
+        self.meta
+            .get(start..)
+            .and_then(split_names)
+            .unwrap_or_default()
+    }
+
+    /// If the content was compressed with a bucket dictionary, decompress it.
+    pub fn decompress(&mut self, buckets: &buckets::Registry) -> Result<(), Error> {
+        if !self.bucketed() {
+            return Ok(());
+        }
+
+        let id = dict::frame_id(self.data.as_slice())?
+            .ok_or_else(|| format_err!("bucketed content without a dictionary id"))?;
+        let dictionary = buckets
+            .by_id(id)
+            .ok_or_else(|| format_err!("no bucket dictionary with id {:08x}", id))?;
+
+        let mut data = Vec::with_capacity(self.data.len() * 4);
+        zstd::Decoder::with_dictionary(self.data.as_slice(), dictionary)?.read_to_end(&mut data)?;
+        self.data = data;
+        self.meta[0] &= !0x40;
+        Ok(())
+    }
+
+    /// If the content was left in a `--shard-raw` directory, replace the reference with it.
+    pub fn resolve(&mut self, shards: &Path) -> Result<(), Error> {
+        if Some(ContentState::Sharded) != self.content_state() {
+            return Ok(());
+        }
+
+        let hash = String::from_utf8_lossy(&self.data).to_string();
+        self.data = fs::read(shard::path(shards, &hash)?)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> AnnulReader<R> {
+    pub fn new(inner: R) -> AnnulReader<R> {
+        AnnulReader {
+            inner,
+            index: 0,
+            shared: HashMap::new(),
+            offsets: None,
+        }
+    }
+
+    /// Where each record starts in the stream, from an `--index` footer, once `next_entry` has
+    /// reached it, or `load_index` has found it.
+    pub fn index(&self) -> Option<&[u64]> {
+        self.offsets.as_deref()
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<AnnulEntry>, Error> {
+        Ok(self
+            .next_entry_if(&mut |_| true)?
+            .map(|entry| entry.expect("always wanted")))
+    }
+
+    /// Iterate over the remaining entries.
+    pub fn entries(&mut self) -> Entries<'_, R> {
+        Entries { reader: self }
+    }
+
+    /// `None` at the end of the stream; `Some(None)` if `wanted` rejected the entry, based on its
+    /// meta (`data` is empty when it's called), and its data was skipped.
+    fn next_entry_if(
+        &mut self,
+        wanted: &mut dyn FnMut(&AnnulEntry) -> bool,
+    ) -> Result<Option<Option<AnnulEntry>>, Error> {
+        let first = 0 == self.index;
+        self.read_entry_if(wanted).map_err(|e| {
+            // the lengths are all LE; bad ones this early are more likely a bad file than a bug
+            if first {
+                format_err!("possibly wrong-endian, or not an annul file: {}", e)
+            } else {
+                e
+            }
+        })
+    }
+
+    fn read_entry_if(
+        &mut self,
+        wanted: &mut dyn FnMut(&AnnulEntry) -> bool,
+    ) -> Result<Option<Option<AnnulEntry>>, Error> {
+        let index = self.index;
+
+        let total_len = match read_u64_or_eof(&mut self.inner)? {
+            Some(crate::INDEX_MARKER) => {
+                let offsets = read_index(&mut self.inner)
+                    .map_err(|e| format_err!("after entry {}: index: {}", index, e))?;
+                ensure!(
+                    u64(offsets.len()) == index,
+                    "the index is of {} entries, but there were {}",
+                    offsets.len(),
+                    index
+                );
+                self.offsets = Some(offsets);
+                return Ok(None);
+            }
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let meta_len = self
+            .inner
+            .read_u64::<LE>()
+            .map_err(|e| format_err!("entry {}: reading meta length: {}", index, e))?;
+
+        // the meta length, and the CRC at the end
+        ensure!(
+            meta_len.checked_add(12).is_some_and(|min| min <= total_len),
+            "entry {}: meta_len ({}) + 12 exceeds total_len ({})",
+            index,
+            meta_len,
+            total_len
+        );
+
+        let data_len = total_len - 12 - meta_len;
+
+        let meta = read_exactly(&mut self.inner, meta_len)
+            .map_err(|e| format_err!("entry {}: meta (meta_len: {}): {}", index, meta_len, e))?;
+
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&meta_len.to_le_bytes());
+        crc.update(&meta);
+
+        let mut entry = AnnulEntry {
+            meta,
+            data: Vec::new(),
+        };
+
+        let wanted = wanted(&entry);
+
+        // shared content has to be kept, wanted or not, for the entries which refer back to it
+        let data = if wanted || entry.shared() {
+            read_exactly(&mut self.inner, data_len).inspect(|data| crc.update(data))
+        } else {
+            skip_exactly(&mut self.inner, data_len, &mut crc)
+        };
+
+        entry.data = data.map_err(|e| {
+            format_err!(
+                "entry {}: data (total_len: {}, meta_len: {}, data_len: {}): {}",
+                index,
+                total_len,
+                meta_len,
+                data_len,
+                e
+            )
+        })?;
+
+        let recorded = self
+            .inner
+            .read_u32::<LE>()
+            .map_err(|e| format_err!("entry {}: reading CRC: {}", index, e))?;
+        let calculated = crc.finalize();
+        ensure!(
+            recorded == calculated,
+            "entry {}: corrupt: recorded CRC {:08x}, but the content has {:08x}",
+            index,
+            recorded,
+            calculated
+        );
+
+        if entry.shared() {
+            let flag = entry.meta[0] & !0x80;
+            self.shared.insert(index, (flag, entry.data.clone()));
+        }
+
+        self.index += 1;
+
+        Ok(Some(if wanted { Some(entry) } else { None }))
+    }
+
+    /// If `entry` refers back to an earlier entry's content, replace the reference with a copy,
+    /// and take on its content flag.
+    pub fn resolve_duplicate(&self, entry: &mut AnnulEntry) -> Result<(), Error> {
+        if Some(ContentState::Duplicate) != entry.content_state() {
+            return Ok(());
+        }
+
+        ensure!(8 == entry.data.len(), "invalid duplicate reference");
+        let index = (&entry.data[..]).read_u64::<LE>()?;
+        let (flag, data) = self
+            .shared
+            .get(&index)
+            .ok_or_else(|| format_err!("reference to unshared entry {}", index))?;
+        entry.meta[0] = *flag;
+        entry.data = data.clone();
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> AnnulReader<R> {
+    /// Find the `--index` footer of a whole decompressed stream, without reading any records,
+    /// returning false if it doesn't have one.
+    pub fn load_index(&mut self) -> Result<bool, Error> {
+        let here = self.inner.stream_position()?;
+        let end = self.inner.seek(SeekFrom::End(0))?;
+
+        // the marker, the count, and the CRC, after at least the header
+        let found = if end >= 5 + 8 + 8 + 4 {
+            self.inner.seek(SeekFrom::End(-12))?;
+            let count = self.inner.read_u64::<LE>()?;
+            match count
+                .checked_mul(8)
+                .and_then(|offsets| offsets.checked_add(8 + 8 + 4))
+                .filter(|&len| len <= end - 5)
+            {
+                Some(len) => {
+                    self.inner.seek(SeekFrom::Start(end - len))?;
+                    crate::INDEX_MARKER == self.inner.read_u64::<LE>()?
+                }
+                None => false,
+            }
+        } else {
+            false
+        };
+
+        if found {
+            self.offsets = Some(read_index(&mut self.inner)?);
+        }
+        self.inner.seek(SeekFrom::Start(here))?;
+        Ok(found)
+    }
+
+    /// Carry on reading from entry `index`, found with the `--index` footer. Content it refers
+    /// back to, with `--dedup-members`, can only be resolved if that's been read already.
+    pub fn seek_to(&mut self, index: u64) -> Result<(), Error> {
+        if self.offsets.is_none() {
+            ensure!(self.load_index()?, "no index");
+        }
+        let offsets = self.offsets.as_ref().expect("just loaded");
+        let offset = *offsets
+            .get(cast::usize(index))
+            .ok_or_else(|| format_err!("no entry {}; there are {}", index, offsets.len()))?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.index = index;
+        Ok(())
+    }
+}
+
+/// The rest of an `--index` footer, after the marker, checked against its CRC.
+pub(crate) fn read_index<R: Read>(from: R) -> Result<Vec<u64>, Error> {
+    let mut footer = Vec::new();
+    from.take(u64::MAX).read_to_end(&mut footer)?;
+    ensure!(
+        footer.len() >= 12 && 0 == (footer.len() - 12) % 8,
+        "{} bytes isn't a whole number of offsets, then the count and the CRC",
+        footer.len()
+    );
+
+    let (body, crc) = footer.split_at(footer.len() - 4);
+    let (offsets, count) = body.split_at(body.len() - 8);
+    ensure!(
+        LE::read_u64(count) == u64(offsets.len() / 8),
+        "it says it has {} offsets, but there are {}",
+        LE::read_u64(count),
+        offsets.len() / 8
+    );
+
+    let recorded = LE::read_u32(crc);
+    let calculated = crc32fast::hash(body);
+    ensure!(
+        recorded == calculated,
+        "corrupt: recorded CRC {:08x}, but it has {:08x}",
+        recorded,
+        calculated
+    );
+
+    Ok(offsets.chunks(8).map(LE::read_u64).collect())
+}
+
+pub struct Entries<'r, R> {
+    reader: &'r mut AnnulReader<R>,
+}
+
+impl<'r, R: Read> Entries<'r, R> {
+    /// Only the entries whose path matches `glob`, where `*` matches anything (including `/`)
+    /// and `?` matches any one byte. The path is the components of `path_components`, joined
+    /// with `/`.
+    ///
+    /// The content of other entries is never copied out of the stream, but it does still have to
+    /// be decompressed to get past it: an `.annul` is a single zstd stream, so skipping an entry
+    /// costs nearly as much as reading it. Content compressed alone (`--bucket-dict`) is not
+    /// additionally decompressed for skipped entries, as `decompress` is never called.
+    pub fn filter_paths(self, glob: &str) -> FilterPaths<'r, R> {
+        FilterPaths {
+            reader: self.reader,
+            glob: glob.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl<'r, R: Read> Iterator for Entries<'r, R> {
+    type Item = Result<AnnulEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_entry().transpose()
+    }
+}
+
+pub struct FilterPaths<'r, R> {
+    reader: &'r mut AnnulReader<R>,
+    glob: Vec<u8>,
+}
+
+impl<'r, R: Read> Iterator for FilterPaths<'r, R> {
+    type Item = Result<AnnulEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let glob = &self.glob;
+        let mut wanted =
+            |entry: &AnnulEntry| glob_matches(glob, &entry.path_components().join(&b'/'));
+        loop {
+            match self.reader.next_entry_if(&mut wanted) {
+                Ok(Some(Some(entry))) => return Some(Ok(entry)),
+                Ok(Some(None)) => continue,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The names at the end of a meta, each a `u16` (LE) length then the name, or `None` if they
+/// don't add up to exactly what's there.
+pub fn split_names(mut names: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut split = Vec::new();
+    while !names.is_empty() {
+        let len = usize::from(LE::read_u16(names.get(..2)?));
+        let name = names.get(2..2 + len)?;
+        split.push(name);
+        names = &names[2 + len..];
+    }
+    Some(split)
+}
+
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_matches(rest, &text[1..]),
+        Some((&c, rest)) => text.first() == Some(&c) && glob_matches(rest, &text[1..]),
+    }
+}
+
+/// Like `read_exactly`, but throw the bytes away, after adding them to `crc`.
+fn skip_exactly<R: Read>(from: R, len: u64, crc: &mut crc32fast::Hasher) -> Result<Vec<u8>, Error> {
+    let mut sink = Checksummed {
+        inner: io::sink(),
+        crc: crc.clone(),
+    };
+    let skipped = io::copy(&mut from.take(len), &mut sink)?;
+    ensure!(skipped == len, "only {} bytes available", skipped);
+    *crc = sink.crc;
+    Ok(Vec::new())
+}
+
+/// `None` on a clean end of stream, an error if it ends part way through the `u64`.
+fn read_u64_or_eof<R: Read>(mut from: R) -> Result<Option<u64>, Error> {
+    let mut buf = [0u8; 8];
+    let mut pos = 0;
+    while pos < buf.len() {
+        match from.read(&mut buf[pos..]) {
+            Ok(0) if 0 == pos => return Ok(None),
+            Ok(0) => bail!("truncated length: only {} bytes", pos),
+            Ok(len) => pos += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some((&buf[..]).read_u64::<LE>()?))
+}
+
+/// Read exactly `len` bytes, without trusting `len` enough to allocate it up front.
+fn read_exactly<R: Read>(from: R, len: u64) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    from.take(len).read_to_end(&mut buf)?;
+    ensure!(u64(buf.len()) == len, "only {} bytes available", buf.len());
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+    use byteorder::BE;
+    use byteorder::LE;
+
+    use super::AnnulEntry;
+    use super::AnnulReader;
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    /// A record which may well be broken, with no CRC.
+    fn raw(out: &mut Vec<u8>, total_len: u64, meta_len: u64, body: &[u8]) {
+        out.write_u64::<LE>(total_len).unwrap();
+        out.write_u64::<LE>(meta_len).unwrap();
+        out.extend_from_slice(body);
+    }
+
+    fn read_all(data: &[u8]) -> Result<Vec<AnnulEntry>, String> {
+        let mut reader = AnnulReader::new(data);
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry().map_err(|e| e.to_string())? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn valid() {
+        let mut data = Vec::new();
+        record(&mut data, b"\x00\x03a\x00", b"hello");
+        record(&mut data, b"\x02\x04b\x00", b"");
+        assert_eq!(
+            vec![
+                AnnulEntry {
+                    meta: b"\x00\x03a\x00".to_vec(),
+                    data: b"hello".to_vec(),
+                },
+                AnnulEntry {
+                    meta: b"\x02\x04b\x00".to_vec(),
+                    data: Vec::new(),
+                },
+            ],
+            read_all(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn meta_longer_than_total() {
+        let mut data = Vec::new();
+        record(&mut data, b"\x02\x04b\x00", b"");
+        raw(&mut data, 10, 4, b"\x02\x04b\x00");
+        let err = read_all(&data).unwrap_err();
+        assert!(err.contains("entry 1"), "{}", err);
+        assert!(err.contains("(4) + 12 exceeds total_len (10)"), "{}", err);
+    }
+
+    #[test]
+    fn meta_len_overflow() {
+        let mut data = Vec::new();
+        raw(&mut data, 8, u64::MAX - 3, b"");
+        let err = read_all(&data).unwrap_err();
+        assert!(err.contains("exceeds total_len (8)"), "{}", err);
+    }
+
+    #[test]
+    fn total_past_end() {
+        let mut data = Vec::new();
+        raw(&mut data, 8 + 4 + 1000 + 4, 4, b"\x00\x04b\x00short");
+        let err = read_all(&data).unwrap_err();
+        assert!(err.contains("entry 0: data"), "{}", err);
+        assert!(err.contains("data_len: 1000"), "{}", err);
+        assert!(err.contains("only 5 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn accessors() {
+        let entry = AnnulEntry {
+            meta: meta()
+                .flags(1)
+                .children(8)
+                .mode(0o100_755)
+                .original_len(256)
+                .name("foo.tar")
+                .name("bar")
+                .build(),
+            data: Vec::new(),
+        };
+        assert_eq!(Some(1), entry.content_flag());
+        assert_eq!(Some(8), entry.children_status());
+        assert_eq!(Some(0o100_755), entry.mode());
+        assert_eq!(Some(256), entry.original_len());
+        assert_eq!(Some(1), entry.kind());
+        assert_eq!(None, entry.content_hash());
+        assert_eq!(Some(0), entry.archive_index());
+        assert_eq!(vec![&b"foo.tar"[..], b"bar"], entry.path_components());
+
+        let empty = AnnulEntry {
+            meta: Vec::new(),
+            data: Vec::new(),
+        };
+        assert_eq!(None, empty.content_flag());
+        assert_eq!(None, empty.mode());
+        assert_eq!(None, empty.original_len());
+        assert_eq!(None, empty.kind());
+        assert!(empty.path_components().is_empty());
+    }
+
+    #[test]
+    fn resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::write(&src, b"\x01raw\x02").unwrap();
+        let hash = crate::shard::store(dir.path(), &src).unwrap();
+
+        let mut data = Vec::new();
+        record(&mut data, b"\x0d\x03a\x00", hash.as_bytes());
+        record(&mut data, b"\x00\x03b\x00", b"hello");
+        let mut entries = read_all(&data).unwrap();
+        for entry in &mut entries {
+            entry.resolve(dir.path()).unwrap();
+        }
+        assert_eq!(b"\x01raw\x02", entries[0].data.as_slice());
+        assert_eq!(b"hello", entries[1].data.as_slice());
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut data = Vec::new();
+        record(&mut data, b"\x81\x04a\x00", b"hello");
+        record(&mut data, b"\x0e\x04b\x00", &0u64.to_le_bytes());
+        record(&mut data, b"\x0e\x04c\x00", &1u64.to_le_bytes());
+
+        let mut reader = AnnulReader::new(&data[..]);
+        let first = reader.next_entry().unwrap().unwrap();
+        assert!(first.shared());
+        assert_eq!(Some(1), first.content_flag());
+
+        let mut second = reader.next_entry().unwrap().unwrap();
+        reader.resolve_duplicate(&mut second).unwrap();
+        assert_eq!(b"hello", second.data.as_slice());
+        assert_eq!(Some(1), second.content_flag());
+        assert!(!second.shared());
+
+        let mut third = reader.next_entry().unwrap().unwrap();
+        assert!(reader.resolve_duplicate(&mut third).is_err());
+    }
+
+    #[test]
+    fn filter_paths() {
+        let wanted = meta().original_len(1).name("e").name("f.c").build();
+        let mut data = Vec::new();
+        record(
+            &mut data,
+            &meta()
+                .children(3)
+                .original_len(1)
+                .name("a.c")
+                .name("b")
+                .build(),
+            b"x",
+        );
+        record(
+            &mut data,
+            &meta().flags(0x81).original_len(1).name("c.h").build(),
+            b"y",
+        );
+        record(
+            &mut data,
+            &meta().flags(14).original_len(8).name("d").build(),
+            &1u64.to_le_bytes(),
+        );
+        record(&mut data, &wanted, b"z");
+
+        let mut reader = AnnulReader::new(&data[..]);
+        let found: Vec<AnnulEntry> = reader
+            .entries()
+            .filter_paths("*.c")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(1, found.len());
+        assert_eq!(wanted, found[0].meta);
+
+        let mut reader = AnnulReader::new(&data[..]);
+        let mut found: Vec<AnnulEntry> = reader
+            .entries()
+            .filter_paths("?")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(1, found.len());
+        reader.resolve_duplicate(&mut found[0]).unwrap();
+        assert_eq!(b"y", found[0].data.as_slice());
+
+        let mut reader = AnnulReader::new(&data[..]);
+        assert_eq!(4, reader.entries().count());
+    }
+
+    #[test]
+    fn globs() {
+        use super::glob_matches;
+        assert!(glob_matches(b"*.c", b"src/foo.c"));
+        assert!(glob_matches(b"src/*", b"src/foo.c"));
+        assert!(!glob_matches(b"*.c", b"src/foo.h"));
+        assert!(glob_matches(b"a?c", b"abc"));
+        assert!(!glob_matches(b"a?c", b"ac"));
+        assert!(glob_matches(b"*", b""));
+    }
+
+    #[test]
+    fn wrong_endian() {
+        let mut data = Vec::new();
+        data.write_u64::<BE>(8 + 4 + 5 + 4).unwrap();
+        data.write_u64::<BE>(4).unwrap();
+        data.extend_from_slice(b"\x00\x03a\x00hello");
+        let err = read_all(&data).unwrap_err();
+        assert!(err.contains("possibly wrong-endian"), "{}", err);
+        assert!(err.contains("entry 0: meta"), "{}", err);
+
+        let mut data = Vec::new();
+        record(&mut data, b"\x02\x04b\x00", b"");
+        raw(&mut data, 10, 4, b"\x02\x04b\x00");
+        let err = read_all(&data).unwrap_err();
+        assert!(!err.contains("wrong-endian"), "{}", err);
+    }
+
+    #[test]
+    fn header() {
+        let mut data = Vec::new();
+        crate::write_header(&mut data).unwrap();
+        super::read_header(data.as_slice()).unwrap();
+
+        let err = super::read_header(&b"\x11\x00\x00\x00\x00"[..]).unwrap_err();
+        assert!(err.to_string().contains("not an annul file"), "{}", err);
+
+        let err = super::read_header(&b"ANUL\x01"[..]).unwrap_err();
+        assert!(err.to_string().contains("version 1"), "{}", err);
+
+        let err = super::read_header(&b"AN"[..]).unwrap_err();
+        assert!(err.to_string().contains("reading header"), "{}", err);
+    }
+
+    #[test]
+    fn truncated_length() {
+        let mut data = Vec::new();
+        record(&mut data, b"\x02\x04b\x00", b"");
+        data.extend_from_slice(&[1, 2, 3]);
+        let err = read_all(&data).unwrap_err();
+        assert!(err.contains("truncated length"), "{}", err);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut data = Vec::new();
+        record(&mut data, b"\x00\x03a.c\x00", b"hello");
+        record(&mut data, b"\x00\x03b.h\x00", b"world");
+        assert_eq!(2, read_all(&data).unwrap().len());
+
+        // a flipped bit in the first record's data
+        let mut flipped = data.clone();
+        flipped[8 + 8 + 6] ^= 0x04;
+        let err = read_all(&flipped).unwrap_err();
+        assert!(err.contains("entry 0: corrupt"), "{}", err);
+
+        // ..noticed even when the data is skipped
+        let mut reader = AnnulReader::new(&flipped[..]);
+        let err = reader
+            .entries()
+            .filter_paths("*.h")
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("entry 0: corrupt"), "{}", err);
+    }
+}
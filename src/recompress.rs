@@ -0,0 +1,167 @@
+//! Rewrites an existing `.annul` with different compression settings, without going back to the
+//! source. The records themselves, including references between them, are copied unchanged.
+
+use std::fs;
+use std::path::Path;
+
+use failure::format_err;
+use failure::Error;
+
+use crate::dict;
+use crate::reader;
+use crate::skippable;
+
+#[derive(Copy, Clone, Debug)]
+pub struct RecompressOpts {
+    /// the zstd level to re-encode at
+    pub level: i32,
+    /// re-encode with this dictionary, instead of the one it was written with
+    pub dictionary: Option<dict::Dictionary>,
+}
+
+impl Default for RecompressOpts {
+    fn default() -> RecompressOpts {
+        RecompressOpts {
+            level: crate::DEFAULT_LEVEL,
+            dictionary: None,
+        }
+    }
+}
+
+/// Decode every record in `src`, and write them all to a new file, `dst`, which must not exist.
+pub fn recompress(src: &Path, dst: &Path, opts: RecompressOpts) -> Result<(), Error> {
+    let dictionary = match opts.dictionary {
+        Some(dictionary) => dictionary.bytes(),
+        None => {
+            let id = dict::frame_id(fs::File::open(src)?)?
+                .ok_or_else(|| format_err!("no dictionary recorded in {:?}", src))?;
+            dict::embedded(id).ok_or_else(|| format_err!("unknown dictionary: {:08x}", id))?
+        }
+    };
+
+    let root = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut out = tempfile_fast::PersistableTempFile::new_in(root)?;
+
+    if let Some(provenance) = reader::provenance(src)? {
+        skippable::write(&mut out, &with_dictionary_id(&provenance, dictionary))?;
+    }
+
+    let mut out = zstd::Encoder::with_dictionary(out, opts.level, dictionary)?;
+    crate::write_header(&mut out)?;
+
+    let mut entries = reader::open(src)?;
+    while let Some(entry) = entries.next_entry()? {
+        crate::write_record_bytes(&mut out, &entry.meta, &entry.data)?;
+    }
+    // the records are unchanged, so they're all still where they were
+    if let Some(offsets) = entries.index() {
+        crate::write_index(&mut out, offsets)?;
+    }
+
+    crate::persist(out.finish()?, dst, false)
+        .map_err(|e| format_err!("writing {:?}: {}", dst, e))?;
+
+    Ok(())
+}
+
+/// `--provenance` records the dictionary; keep it true if that's what's changing.
+fn with_dictionary_id(provenance: &[u8], dictionary: &[u8]) -> Vec<u8> {
+    let mut updated = Vec::with_capacity(provenance.len());
+    for line in provenance.split_inclusive(|&b| b'\n' == b) {
+        if !line.starts_with(b"Dictionary-Id:") {
+            updated.extend_from_slice(line);
+        }
+    }
+    if let Some(id) = dict::id(dictionary) {
+        updated.extend_from_slice(format!("Dictionary-Id: {:08x}\n", id).as_bytes());
+    }
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::RecompressOpts;
+    use crate::dict;
+    use crate::reader;
+    use crate::skippable;
+    use crate::testing::meta;
+    use crate::testing::record;
+
+    fn entries(path: &std::path::Path) -> Vec<reader::AnnulEntry> {
+        reader::open(path)
+            .unwrap()
+            .entries()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.annul");
+
+        let mut records = Vec::new();
+        record(
+            &mut records,
+            &meta().flags(0x80).original_len(14).name("a.c").build(),
+            b"int main() {}\n",
+        );
+        record(
+            &mut records,
+            &meta().flags(14).original_len(8).name("b.c").build(),
+            &0u64.to_le_bytes(),
+        );
+        record(
+            &mut records,
+            &meta().flags(2).children(7).reason("bad").name("c").build(),
+            b"",
+        );
+
+        let mut file = fs::File::create(&src).unwrap();
+        skippable::write(&mut file, b"Source-Url: x\nDictionary-Id: 00000000\n").unwrap();
+        let mut encoder = zstd::Encoder::with_dictionary(file, 3, dict::ORIG).unwrap();
+        crate::write_header(&mut encoder).unwrap();
+        encoder.write_all(&records).unwrap();
+        encoder.finish().unwrap();
+
+        let same = dir.path().join("same.annul");
+        super::recompress(
+            &src,
+            &same,
+            RecompressOpts {
+                level: 19,
+                ..RecompressOpts::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(entries(&src), entries(&same));
+        assert_eq!(
+            Some(b"Source-Url: x\nDictionary-Id: 360e6b0b\n".to_vec()),
+            reader::provenance(&same).unwrap()
+        );
+
+        let debian = dir.path().join("debian.annul");
+        super::recompress(
+            &src,
+            &debian,
+            RecompressOpts {
+                dictionary: Some(dict::Dictionary::Debian),
+                ..RecompressOpts::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            dict::id(dict::DEBIAN),
+            dict::frame_id(fs::File::open(&debian).unwrap()).unwrap()
+        );
+        assert_eq!(entries(&src), entries(&debian));
+
+        assert!(super::recompress(&src, &same, RecompressOpts::default()).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Content-addressed storage for raw member content, for `--shard-raw`.
+//!
+//! Each distinct member is stored once, however many packages it appears in, at
+//! `<dir>/<first two hex digits of its sha256>/<remaining 62 digits>.bin`, exactly as it was
+//! unpacked: it has not been through strings. The `.annul` then carries the 64 hex digits of the
+//! hash in place of the content, and content flag 13.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure::ensure;
+use failure::Error;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Copy `src` into the shard `dir`, unless it's already there, and return its hash.
+pub fn store(dir: &Path, src: &Path) -> Result<String, Error> {
+    let hash = hash(fs::File::open(src)?)?;
+    let dest = path(dir, &hash)?;
+
+    if dest.exists() {
+        return Ok(hash);
+    }
+
+    let parent = dest.parent().expect("always in a sub-directory");
+    fs::create_dir_all(parent)?;
+
+    let mut tmp = tempfile_fast::PersistableTempFile::new_in(parent)?;
+    io::copy(&mut fs::File::open(src)?, &mut tmp)?;
+
+    match tmp.persist_noclobber(&dest) {
+        Ok(()) => (),
+        // someone else stored the same content while we were copying
+        Err(ref e) if e.error.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e.error.into()),
+    }
+
+    Ok(hash)
+}
+
+/// Where the content with this `hash` lives in the shard `dir`.
+pub fn path(dir: &Path, hash: &str) -> Result<PathBuf, Error> {
+    ensure!(
+        64 == hash.len() && hash.bytes().all(|b| b.is_ascii_hexdigit()),
+        "invalid shard reference: {:?}",
+        hash
+    );
+
+    Ok(dir.join(&hash[..2]).join(format!("{}.bin", &hash[2..])))
+}
+
+/// The sha256 of everything in `src`, in lowercase hex.
+pub fn hash<R: Read>(mut src: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let len = src.read(&mut buf)?;
+        if 0 == len {
+            break;
+        }
+        hasher.input(&buf[..len]);
+    }
+
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = dir.path().join("shards");
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, b"abc").unwrap();
+        fs::write(&b, b"abc").unwrap();
+
+        let hash = super::store(&shards, &a).unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hash
+        );
+        assert_eq!(hash, super::store(&shards, &b).unwrap());
+
+        let stored = shards.join("ba").join(format!("{}.bin", &hash[2..]));
+        assert_eq!(stored, super::path(&shards, &hash).unwrap());
+        assert_eq!(b"abc", fs::read(stored).unwrap().as_slice());
+        assert_eq!(1, fs::read_dir(shards.join("ba")).unwrap().count());
+    }
+
+    #[test]
+    fn bad_reference() {
+        let dir = std::path::Path::new("shards");
+        assert!(super::path(dir, "../../etc/passwd").is_err());
+        assert!(super::path(dir, &"g".repeat(64)).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! zstd "skippable frames": a magic, a length, then that many bytes, which zstd decoders step
+//! over. We use one at the very start of an `.annul` to carry metadata about how it was made,
+//! which plain `zstd -d` then ignores.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::ByteOrder;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LE;
+use cast::u32;
+use cast::u64;
+
+/// zstd reserves 0x184D2A50 to 0x184D2A5F; this is the one we write.
+pub const MAGIC: u32 = 0x184D_2A5A;
+
+pub fn is_magic(magic: u32) -> bool {
+    0x184D_2A50 == magic & 0xFFFF_FFF0
+}
+
+pub fn write<W: Write>(mut out: W, data: &[u8]) -> io::Result<()> {
+    let len = u32(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "skippable frame too large"))?;
+    out.write_u32::<LE>(MAGIC)?;
+    out.write_u32::<LE>(len)?;
+    out.write_all(data)
+}
+
+/// The content of our skippable frame, if `from` starts with one.
+pub fn read<R: Read>(mut from: R) -> io::Result<Option<Vec<u8>>> {
+    let mut magic = [0u8; 4];
+    if let Err(e) = from.read_exact(&mut magic) {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    if MAGIC != LE::read_u32(&magic) {
+        return Ok(None);
+    }
+
+    let len = u64::from(from.read_u32::<LE>()?);
+    let mut data = Vec::new();
+    from.take(len).read_to_end(&mut data)?;
+    if u64(data.len()) != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn round_trip() {
+        let mut out = Vec::new();
+        super::write(&mut out, b"Source-Url: http://example.com/\n").unwrap();
+        assert_eq!(
+            Some(b"Source-Url: http://example.com/\n".to_vec()),
+            super::read(out.as_slice()).unwrap()
+        );
+        assert_eq!(None, super::read(&b"\x28\xb5\x2f\xfd"[..]).unwrap());
+        assert_eq!(None, super::read(&b""[..]).unwrap());
+        assert!(super::read(&out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn ignored_by_zstd() {
+        let mut out = Vec::new();
+        super::write(&mut out, b"not part of the content").unwrap();
+
+        let mut encoder = zstd::Encoder::new(&mut out, 3).unwrap();
+        encoder.write_all(b"hello").unwrap();
+        encoder.finish().unwrap();
+
+        assert_eq!(
+            b"hello",
+            zstd::stream::decode_all(out.as_slice()).unwrap().as_slice()
+        );
+    }
+}
@@ -1,5 +1,10 @@
+use std::cmp;
 use std::io;
+use std::io::Read;
 use std::io::Write;
+use std::mem;
+
+use crate::text::UTF8_BOM;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum ShortArray {
@@ -72,10 +77,9 @@ fn get_char(bytes: &[u8]) -> Option<Char> {
         return None;
     }
 
-    for i in 1..wanted {
-        if !follower(bytes[i]) {
-            return Some(Char::Binary(byte));
-        }
+    // not just the bit patterns: overlong forms, surrogates and anything past U+10FFFF are binary
+    if std::str::from_utf8(&bytes[..wanted]).is_err() {
+        return Some(Char::Binary(byte));
     }
 
     Some(Char::Printable(match wanted {
@@ -86,11 +90,6 @@ fn get_char(bytes: &[u8]) -> Option<Char> {
     }))
 }
 
-#[inline]
-fn follower(byte: u8) -> bool {
-    byte & 0b1100_0000 == 0b1000_0000
-}
-
 struct CharBuf {
     buf: Vec<u8>,
 }
@@ -98,13 +97,46 @@ struct CharBuf {
 impl CharBuf {
     fn push(&mut self, byte: u8) -> Option<Char> {
         self.buf.push(byte);
+        self.pop()
+    }
 
-        let opt = get_char(&self.buf);
-        if let Some(c) = opt {
-            let _ = self.buf.drain(..c.len());
-        }
+    fn pop(&mut self) -> Option<Char> {
+        let c = get_char(&self.buf)?;
+        let _ = self.buf.drain(..c.len());
+        Some(c)
+    }
+}
+
+/// Decode a slice lazily, stopping before a character which is cut off at the end.
+struct Chars<'b> {
+    bytes: &'b [u8],
+}
 
-        opt
+impl<'b> Chars<'b> {
+    /// Take the run of plain, printable ASCII at the front, which needs no decoding.
+    fn ascii(&mut self) -> &'b [u8] {
+        let len = self
+            .bytes
+            .iter()
+            .position(|&b| !plain_ascii(b))
+            .unwrap_or(self.bytes.len());
+        let (run, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        run
+    }
+}
+
+fn plain_ascii(byte: u8) -> bool {
+    (b' '..0x7f).contains(&byte) || b'\t' == byte || b'\n' == byte || b'\r' == byte
+}
+
+impl<'b> Iterator for Chars<'b> {
+    type Item = Char;
+
+    fn next(&mut self) -> Option<Char> {
+        let c = get_char(self.bytes)?;
+        self.bytes = &self.bytes[c.len()..];
+        Some(c)
     }
 }
 
@@ -116,94 +148,356 @@ impl Default for CharBuf {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct StringsConfig {
+    /// Terminate a printable run once it reaches this many bytes, and start a new one.
+    /// By default, long runs are written out as they grow, but never split.
+    pub max_run: Option<usize>,
+    /// Drop printable runs shorter than this many bytes, which are probably just noise in binary.
+    pub min_len: usize,
+    /// Let a run carry on through up to this many binary bytes in a row, e.g. separators.
+    pub max_embedded_binary: usize,
+    /// Drop a UTF-8 byte order mark from the very start of the input.
+    pub strip_bom: bool,
+    /// What to do with spaces, tabs and line breaks inside runs.
+    pub whitespace: Whitespace,
+}
+
+/// What `StringBuf` does with the whitespace it finds: spaces, tabs, and line breaks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Whitespace {
+    /// Keep it exactly as it was.
+    Preserve,
+    /// Replace each run of it with a single space.
+    Collapse,
+    /// Leave it out entirely, joining up whatever was either side.
+    Drop,
+}
+
+fn whitespace(byte: u8) -> bool {
+    b' ' == byte || b'\t' == byte || b'\n' == byte || b'\r' == byte
+}
+
+impl Default for StringsConfig {
+    fn default() -> StringsConfig {
+        StringsConfig {
+            max_run: None,
+            min_len: 4,
+            max_embedded_binary: 2,
+            strip_bom: false,
+            whitespace: Whitespace::Preserve,
+        }
+    }
+}
+
+/// Writes the printable runs of what it's given to `output` as soon as they're certain, so only
+/// the end of the current run, which could yet be extended, or be dropped as too short, is held.
 pub struct StringBuf<W> {
     output: W,
+    config: StringsConfig,
     chars: CharBuf,
     buf: Vec<u8>,
+    /// bytes of the current run which have already been written out
+    flushed: usize,
     binary_run: usize,
     binary_sum: usize,
+    /// how much of a BOM the input has started with, while that's still all it's been
+    bom: Option<usize>,
+    /// the last printable character was whitespace, so more of it would be collapsed into it
+    in_whitespace: bool,
 }
 
 impl<W: Write> StringBuf<W> {
-    pub fn accept(&mut self, buf: &[u8]) -> io::Result<()> {
-        for &b in buf {
-            self.push(b)?;
+    pub fn accept(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        if let Some(matched) = self.bom {
+            // the BOM could be split between buffers, too
+            let more = cmp::min(buf.len(), UTF8_BOM.len() - matched);
+            if buf[..more] != UTF8_BOM[matched..matched + more] {
+                self.bom = None;
+                self.decode(&UTF8_BOM[..matched])?;
+            } else if matched + more == UTF8_BOM.len() {
+                self.bom = None;
+                buf = &buf[more..];
+            } else {
+                self.bom = Some(matched + more);
+                return Ok(());
+            }
         }
+
+        self.decode(buf)
+    }
+
+    fn decode(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        // finish off any character the last buffer cut in half, a byte at a time
+        while !self.chars.buf.is_empty() {
+            let (&b, rest) = match buf.split_first() {
+                Some(split) => split,
+                None => return Ok(()),
+            };
+            buf = rest;
+
+            if let Some(c) = self.chars.push(b) {
+                self.accept_char(c)?;
+            }
+            while let Some(c) = self.chars.pop() {
+                self.accept_char(c)?;
+            }
+        }
+
+        let mut chars = Chars { bytes: buf };
+        loop {
+            let run = chars.ascii();
+            if !run.is_empty() {
+                self.accept_ascii(run)?;
+            }
+            match chars.next() {
+                Some(c) => self.accept_char(c)?,
+                None => break,
+            }
+        }
+        self.chars.buf.extend_from_slice(chars.bytes);
         Ok(())
     }
 
-    fn push(&mut self, b: u8) -> io::Result<()> {
-        let c = match self.chars.push(b) {
-            Some(c) => c,
-            None => return Ok(()),
-        };
+    /// The same as `accept_char` for each byte of `run`, but copying as much as possible at once.
+    fn accept_ascii(&mut self, mut run: &[u8]) -> io::Result<()> {
+        if Whitespace::Preserve != self.config.whitespace {
+            // not worth the trouble of being clever
+            for &b in run {
+                self.accept_char(Char::Printable(ShortArray::One([b])))?;
+            }
+            return Ok(());
+        }
+
+        if self.binary_run == self.buf.len() {
+            self.buf.clear();
+        }
 
+        while !run.is_empty() {
+            // the most bytes which can go in before one of `grew`'s limits could be hit
+            let mut room = 256usize.saturating_sub(self.buf.len()).max(1);
+            if let Some(max) = self.config.max_run {
+                room = room.min(max.saturating_sub(self.flushed + self.buf.len()).max(1));
+            }
+
+            let (now, later) = run.split_at(room.min(run.len()));
+            self.buf.extend_from_slice(now);
+            self.grew()?;
+            run = later;
+        }
+
+        Ok(())
+    }
+
+    fn accept_char(&mut self, c: Char) -> io::Result<()> {
         match c {
-            Char::Binary(c) if self.binary_run < 2 && self.binary_sum < self.buf.len() / 4 => {
+            Char::Binary(c)
+                if self.binary_run < self.config.max_embedded_binary
+                    && self.binary_sum < self.buf.len() / 4 =>
+            {
                 self.binary_run += 1;
                 self.binary_sum += 1;
+                self.in_whitespace = false;
                 self.buf.push(c);
             }
 
             Char::Binary(_) => {
                 self.buf.truncate(self.buf.len() - self.binary_run);
 
-                if self.buf.len() > 3 {
+                if self.flushed + self.buf.len() >= self.config.min_len {
                     self.output.write_all(&self.buf)?;
                     self.output.write_all(&[0])?;
                 }
+                self.flushed = 0;
                 self.binary_run = 0;
                 self.binary_sum = 0;
+                self.in_whitespace = false;
                 self.buf.clear()
             }
             Char::Printable(arr) => {
                 if self.binary_run == self.buf.len() {
                     self.buf.clear();
                 }
-                arr.push_to(&mut self.buf);
-                if self.buf.len() > 255 {
-                    self.output.write_all(&self.buf[..250])?;
-                    self.binary_sum = 0;
-                    let _ = self.buf.drain(..250);
+                match arr {
+                    ShortArray::One([b]) if whitespace(b) => {
+                        let first = !self.in_whitespace;
+                        self.in_whitespace = true;
+                        match self.config.whitespace {
+                            Whitespace::Preserve => self.buf.push(b),
+                            Whitespace::Collapse if first => self.buf.push(b' '),
+                            Whitespace::Collapse | Whitespace::Drop => (),
+                        }
+                    }
+                    _ => {
+                        self.in_whitespace = false;
+                        arr.push_to(&mut self.buf);
+                    }
                 }
-                self.binary_run = 0;
+                self.grew()?;
             }
         }
 
         Ok(())
     }
 
+    /// Printable bytes were added to the run; write it out if it's now too long.
+    fn grew(&mut self) -> io::Result<()> {
+        let run = self.flushed + self.buf.len();
+        if self.config.max_run.is_some_and(|max| run >= max) {
+            self.output.write_all(&self.buf)?;
+            self.output.write_all(&[0])?;
+            self.flushed = 0;
+            self.binary_sum = 0;
+            self.buf.clear();
+        } else if self.buf.len() > 255 {
+            self.output.write_all(&self.buf[..250])?;
+            self.flushed += 250;
+            self.binary_sum = 0;
+            let _ = self.buf.drain(..250);
+        }
+        self.binary_run = 0;
+        Ok(())
+    }
+
     pub fn finish(mut self) -> io::Result<W> {
-        self.output.write_all(&self.buf)?;
-        self.output.write_all(&self.chars.buf)?;
+        // only the start of a BOM, so just more content
+        if let Some(matched) = self.bom.take() {
+            self.decode(&UTF8_BOM[..matched])?;
+        }
+
+        // whatever's left is waiting for bytes which are never coming, so can't be printable
+        while let Some(&first) = self.chars.buf.first() {
+            let c = get_char(&self.chars.buf).unwrap_or(Char::Binary(first));
+            let _ = self.chars.buf.drain(..c.len());
+            self.accept_char(c)?;
+        }
+
+        // binary is only embedded if the run carries on after it, which the last one doesn't,
+        // so it ended the run, and a run that short would've been dropped anywhere else
+        let ended_by_binary = 0 != self.binary_run;
+        self.buf.truncate(self.buf.len() - self.binary_run);
+        if !ended_by_binary || self.flushed + self.buf.len() >= self.config.min_len {
+            self.output.write_all(&self.buf)?;
+        }
         Ok(self.output)
     }
 }
 
 impl<W> StringBuf<W> {
     pub fn new(output: W) -> StringBuf<W> {
+        StringBuf::with_config(output, StringsConfig::default())
+    }
+
+    pub fn with_config(output: W, config: StringsConfig) -> StringBuf<W> {
         StringBuf {
             chars: CharBuf::default(),
             output,
+            config,
             buf: Vec::with_capacity(4096),
+            flushed: 0,
             binary_run: 0,
             binary_sum: 0,
+            bom: if config.strip_bom { Some(0) } else { None },
+            in_whitespace: false,
+        }
+    }
+}
+
+/// Like `StringBuf`, but pulls from `src` as it is read, instead of being pushed to.
+pub fn strings_reader<R: Read>(src: R, config: StringsConfig) -> impl Read {
+    StringsReader {
+        src,
+        state: Some(StringBuf::with_config(Vec::with_capacity(4096), config)),
+        pending: Vec::new(),
+        pos: 0,
+    }
+}
+
+struct StringsReader<R> {
+    src: R,
+    /// `None` once `src` is exhausted, and the state has been finished into `pending`
+    state: Option<StringBuf<Vec<u8>>>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for StringsReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0u8; 16 * 1024];
+
+        loop {
+            if self.pos < self.pending.len() {
+                let len = cmp::min(buf.len(), self.pending.len() - self.pos);
+                buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+
+            self.pending.clear();
+            self.pos = 0;
+
+            let state = match self.state.as_mut() {
+                Some(state) => state,
+                None => return Ok(0),
+            };
+
+            let found = match self.src.read(&mut chunk) {
+                Ok(found) => found,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if 0 == found {
+                let state = self.state.take().expect("just checked");
+                self.pending = state.finish()?;
+            } else {
+                state.accept(&chunk[..found])?;
+                mem::swap(&mut self.pending, &mut state.output);
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io;
+    use std::io::Read;
+
+    use super::get_char;
     use super::Char;
     use super::CharBuf;
+    use super::Chars;
     use super::ShortArray;
     use super::StringBuf;
+    use super::StringsConfig;
+    use super::Whitespace;
+
+    /// Hands out `data` a few bytes at a time, to split characters between reads.
+    struct Dribble<'d>(&'d [u8]);
+
+    impl<'d> Read for Dribble<'d> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
 
-    fn check(expected: &[u8], data: &[u8]) {
+    fn read_strings<R: Read>(src: R, config: StringsConfig) -> Vec<u8> {
         let mut actual = Vec::new();
-        let mut state = StringBuf::new(&mut actual);
-        state.accept(data).expect("only for vec");
-        state.finish().expect("only for vec");
+        super::strings_reader(src, config)
+            .read_to_end(&mut actual)
+            .expect("only for slices");
+        actual
+    }
+
+    fn check(expected: &[u8], data: &[u8]) {
+        check_with(StringsConfig::default(), expected, data)
+    }
+
+    fn check_with(config: StringsConfig, expected: &[u8], data: &[u8]) {
+        let actual = run(config, data);
 
         assert_eq!(
             String::from_utf8_lossy(expected),
@@ -212,6 +506,43 @@ mod tests {
         assert_eq!(expected, actual.as_slice());
     }
 
+    fn run(config: StringsConfig, data: &[u8]) -> Vec<u8> {
+        let mut actual = Vec::new();
+        let mut state = StringBuf::with_config(&mut actual, config);
+        state.accept(data).expect("only for vec");
+        state.finish().expect("only for vec");
+        actual
+    }
+
+    #[test]
+    fn flushes_as_it_goes() {
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            data.extend_from_slice(format!("line {} of some text\n", i).as_bytes());
+            if 0 == i % 7 {
+                data.extend_from_slice(b"\x00\x01\x02");
+            }
+            if 0 == i % 11 {
+                data.extend_from_slice("h\u{e9}llo \u{1f600}".as_bytes());
+            }
+            if 0 == i % 13 {
+                data.extend_from_slice(b"ab\xff");
+            }
+        }
+        let expected = run(StringsConfig::default(), &data);
+
+        let mut actual = Vec::new();
+        let mut state = StringBuf::new(&mut actual);
+        for chunk in data.chunks(37) {
+            state.accept(chunk).expect("only for vec");
+            // a run's end, plus the binary bytes it might yet carry on through
+            assert!(state.buf.len() <= 256 + 2, "{}", state.buf.len());
+        }
+        assert!(state.output.len() + 256 + 2 >= expected.len());
+        state.finish().expect("only for vec");
+        assert_eq!(expected, actual);
+    }
+
     #[test]
     fn strings_all_ascii() {
         check(b"hello", b"hello");
@@ -222,6 +553,314 @@ mod tests {
         check(b"hello\0world", b"hello\0\x01\x02\x03world");
     }
 
+    #[test]
+    fn strings_max_run_splits() {
+        let config = StringsConfig {
+            max_run: Some(5),
+            ..StringsConfig::default()
+        };
+        check_with(config, b"hello\0world\0!", b"helloworld!");
+    }
+
+    #[test]
+    fn strings_max_embedded_binary() {
+        let embedded = |n| {
+            let mut data = b"hello there world".to_vec();
+            data.extend(vec![1u8; n]);
+            data.extend_from_slice(b"again");
+            data
+        };
+        let tolerant = |max_embedded_binary| StringsConfig {
+            max_embedded_binary,
+            ..StringsConfig::default()
+        };
+
+        check(&embedded(1), &embedded(1));
+        check(&embedded(2), &embedded(2));
+        check(b"hello there world\0again", &embedded(3));
+
+        check_with(tolerant(1), &embedded(1), &embedded(1));
+        check_with(tolerant(1), b"hello there world\0again", &embedded(2));
+
+        check_with(tolerant(3), &embedded(2), &embedded(2));
+        check_with(tolerant(3), &embedded(3), &embedded(3));
+
+        check_with(tolerant(0), b"hello there world\0again", &embedded(1));
+    }
+
+    #[test]
+    fn strings_min_len() {
+        let data = b"ab\x01\x02\x03abcd\x01\x02\x03abcdefgh\x01\x02\x03!";
+        check(b"abcd\0abcdefgh\0!", data);
+
+        let min_len = |min_len| StringsConfig {
+            min_len,
+            ..StringsConfig::default()
+        };
+        check_with(min_len(2), b"ab\0abcd\0abcdefgh\0!", data);
+        check_with(min_len(5), b"abcdefgh\0!", data);
+        check_with(min_len(8), b"abcdefgh\0!", data);
+        check_with(min_len(9), b"!", data);
+    }
+
+    #[test]
+    fn whitespace() {
+        let data = b"fn main() {\n\t  let x = 1;\r\n\n    x\n} \t\x01\x02\x03 \n  end  ";
+        let config = |whitespace| StringsConfig {
+            whitespace,
+            ..StringsConfig::default()
+        };
+
+        check(
+            b"fn main() {\n\t  let x = 1;\r\n\n    x\n} \t\0 \n  end  ",
+            data,
+        );
+        check_with(
+            config(Whitespace::Collapse),
+            b"fn main() { let x = 1; x } \0 end ",
+            data,
+        );
+        check_with(config(Whitespace::Drop), b"fnmain(){letx=1;x}\0end", data);
+
+        // only what's left counts towards the minimum length
+        check(b"a \t \n bc\0", b"a \t \n bc\x01\x02\x03");
+        check_with(
+            config(Whitespace::Collapse),
+            b"a bc\0",
+            b"a \t \n bc\x01\x02\x03",
+        );
+        check_with(config(Whitespace::Drop), b"", b"a \t \n bc\x01\x02\x03");
+
+        // whitespace can still end a run of embedded binary
+        check_with(
+            config(Whitespace::Drop),
+            b"hello\x01world",
+            b"hello\x01 world",
+        );
+    }
+
+    #[test]
+    fn strings_max_run_huge() {
+        let data = vec![b'a'; 1024 * 1024];
+
+        // unsplit by default
+        assert_eq!(data, run(StringsConfig::default(), &data));
+
+        let config = StringsConfig {
+            max_run: Some(1024),
+            ..StringsConfig::default()
+        };
+        let actual = run(config, &data);
+        assert_eq!(1024 * 1025, actual.len());
+        for piece in actual.chunks(1025) {
+            assert_eq!(&data[..1024], &piece[..1024]);
+            assert_eq!(0, piece[1024]);
+        }
+    }
+
+    #[test]
+    fn strings_utf_widths() {
+        for printable in &["a", "\u{e9}", "\u{2192}", "\u{1f600}"] {
+            let run = printable.repeat(8);
+            let mut data = b"\x01\x02\x03".to_vec();
+            data.extend_from_slice(run.as_bytes());
+            data.extend_from_slice(b"\x01\x02\x03");
+            data.extend_from_slice(run.as_bytes());
+            let mut expected = run.as_bytes().to_vec();
+            expected.push(0);
+            expected.extend_from_slice(run.as_bytes());
+            check(&expected, &data);
+        }
+
+        // a lead byte whose followers are missing is a single binary byte, at every width
+        for lead in &[0xc3u8, 0xe2, 0xf0] {
+            let mut data = b"hello".to_vec();
+            data.extend_from_slice(&[*lead, b'x', b'y', b'z', 0x01, 0x02, 0x03]);
+            data.extend_from_slice(b"world");
+            let mut expected = b"hello".to_vec();
+            expected.extend_from_slice(&[*lead, b'x', b'y', b'z', 0]);
+            expected.extend_from_slice(b"world");
+            check(&expected, &data);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        // the shortest overlong form at each width
+        assert_eq!(Some(Char::Binary(0xc0)), get_char(b"\xc0\x80"));
+        assert_eq!(Some(Char::Binary(0xe0)), get_char(b"\xe0\x80\x80"));
+        assert_eq!(Some(Char::Binary(0xf0)), get_char(b"\xf0\x80\x80\x80"));
+
+        // U+D800, a surrogate, and U+110000, out of range
+        assert_eq!(Some(Char::Binary(0xed)), get_char(b"\xed\xa0\x80"));
+        assert_eq!(Some(Char::Binary(0xf4)), get_char(b"\xf4\x90\x80\x80"));
+
+        // the ends of the valid ranges are still fine
+        assert_eq!(
+            Some(Char::Printable(ShortArray::Two([0xc2, 0x80]))),
+            get_char(b"\xc2\x80")
+        );
+        assert_eq!(
+            Some(Char::Printable(ShortArray::Three([0xed, 0x9f, 0xbf]))),
+            get_char(b"\xed\x9f\xbf")
+        );
+        assert_eq!(
+            Some(Char::Printable(ShortArray::Four([0xf4, 0x8f, 0xbf, 0xbf]))),
+            get_char(b"\xf4\x8f\xbf\xbf")
+        );
+    }
+
+    #[test]
+    fn multibyte_runs() {
+        let check_split = |expected: &[u8], data: &[u8]| {
+            check(expected, data);
+            assert_eq!(
+                expected,
+                read_strings(Dribble(data), StringsConfig::default()).as_slice()
+            );
+        };
+
+        let pure = "日本語のテキスト, ελληνικά, 🦀é".as_bytes();
+        check_split(pure, pure);
+
+        let interrupted = |n| {
+            let mut data = "日本語".as_bytes().to_vec();
+            data.extend(vec![1u8; n]);
+            data.extend_from_slice("テキスト".as_bytes());
+            data
+        };
+        check_split(&interrupted(1), &interrupted(1));
+        check_split(&interrupted(2), &interrupted(2));
+        // the binary which was held, in case the run carried on, is dropped with it
+        check_split("日本語\0テキスト".as_bytes(), &interrupted(3));
+
+        // too few bytes, not characters, to be kept: é is two, 日 three, 🦀 four
+        check_split("日本語".as_bytes(), "é\x01\x02\x03日本語".as_bytes());
+        check_split("🦀\0".as_bytes(), "日\x01\x02\x03🦀\x01\x02\x03".as_bytes());
+
+        // a run needs to be four times longer than the binary it's carrying, in bytes
+        check_split("éé\x01éé".as_bytes(), "éé\x01éé".as_bytes());
+        check_split("éé".as_bytes(), "é\x01éé".as_bytes());
+    }
+
+    #[test]
+    fn strings_split_multibyte() {
+        let data = "caf\u{e9} \u{2192} \u{1f600}!".as_bytes();
+        let expected = run(StringsConfig::default(), data);
+        assert_eq!(data, expected.as_slice());
+
+        let mut actual = Vec::new();
+        let mut state = StringBuf::new(&mut actual);
+        for b in data {
+            state.accept(&[*b]).expect("only for vec");
+        }
+        state.finish().expect("only for vec");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn strings_truncated_multibyte() {
+        // a truncated sequence at the end is binary, just like any other
+        check(b"hello world\0", b"hello world\xf0\x9f\x98");
+        check(b"hello world\0", b"hello world\x01\x02\x03");
+
+        // short enough to be embedded, if anything followed it
+        check(b"hello world", b"hello world\xc3");
+        check(b"hello world", b"hello world\x01");
+        check(b"hello\xc3\xa9 world", b"hello\xc3\xa9 world\xc3");
+
+        // but it still ends the run, so a short one is dropped, as one ended mid-stream would be
+        check(b"", b"ab\xc3");
+        check(b"", b"abc\x01");
+        let min_len = StringsConfig {
+            min_len: 12,
+            ..StringsConfig::default()
+        };
+        check_with(min_len, b"", b"hello world\xc3");
+    }
+
+    #[test]
+    fn reader_matches_buf() {
+        let mut data = Vec::new();
+        for i in 0..20_000u32 {
+            data.extend_from_slice(b"hello \xc3\xbf world ");
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+
+        let split = StringsConfig {
+            max_run: Some(7),
+            ..StringsConfig::default()
+        };
+        for &config in &[StringsConfig::default(), split] {
+            let expected = run(config, &data);
+            assert_eq!(expected, read_strings(&data[..], config));
+            assert_eq!(expected, read_strings(Dribble(&data), config));
+        }
+    }
+
+    #[test]
+    fn reader_small_reads() {
+        let data = b"hello\0\x01\x02\x03world\xe2\x82";
+        let mut reader = super::strings_reader(&data[..], StringsConfig::default());
+        let mut actual = Vec::new();
+        let mut buf = [0u8; 2];
+        loop {
+            let found = reader.read(&mut buf).unwrap();
+            if 0 == found {
+                break;
+            }
+            actual.extend_from_slice(&buf[..found]);
+        }
+        assert_eq!(run(StringsConfig::default(), data), actual);
+    }
+
+    /// Keeps track of how much has been written, but not what.
+    #[derive(Default)]
+    struct Counting(u64);
+
+    impl io::Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn strings_long_line_streams() {
+        long_line(16);
+    }
+
+    /// Slow in debug builds; run with `--ignored`.
+    #[test]
+    #[ignore]
+    fn strings_long_line_streams_200mb() {
+        long_line(200);
+    }
+
+    /// A single line of text, `chunks` MB long, i.e. no binary at all to break it up.
+    fn long_line(chunks: u64) {
+        let chunk = vec![b'a'; 1024 * 1024];
+
+        let mut state = StringBuf::new(Counting::default());
+        for i in 1..=chunks {
+            state.accept(&chunk).expect("counting");
+
+            // nearly everything has already been handed to the writer..
+            let fed = i * chunk.len() as u64;
+            assert!(state.output.0 + 256 >= fed, "{} of {}", state.output.0, fed);
+
+            // ..and the buffer has never had to grow
+            assert!(state.buf.len() <= 256 + 4);
+            assert_eq!(4096, state.buf.capacity());
+        }
+
+        assert_eq!(chunks * chunk.len() as u64, state.finish().unwrap().0);
+    }
+
     #[test]
     fn charer() {
         let mut c = CharBuf::default();
@@ -232,4 +871,70 @@ mod tests {
             c.push(0b1011_1111)
         );
     }
+
+    #[test]
+    fn chars_stop_before_cut_off() {
+        let mut chars = Chars {
+            bytes: b"h\xff\xe2\x82",
+        };
+        assert_eq!(
+            vec![Char::Printable(ShortArray::One([b'h'])), Char::Binary(0xff)],
+            chars.by_ref().collect::<Vec<_>>()
+        );
+        assert_eq!(b"\xe2\x82", chars.bytes);
+    }
+
+    #[test]
+    fn ascii_runs_match_per_char() {
+        let mut data = Vec::new();
+        for i in 0..4000u32 {
+            data.extend_from_slice(match i % 97 {
+                0 => &b"\x01"[..],
+                13 => "\u{e9}".as_bytes(),
+                50 => b"\x00\x00\x00",
+                _ => b"chars ",
+            });
+        }
+
+        for &max_run in &[None, Some(1), Some(7), Some(255), Some(256), Some(300)] {
+            let config = StringsConfig {
+                max_run,
+                ..StringsConfig::default()
+            };
+
+            let mut expected = Vec::new();
+            let mut state = StringBuf::with_config(&mut expected, config);
+            let mut rest = &data[..];
+            while let Some(c) = get_char(rest) {
+                state.accept_char(c).expect("only for vec");
+                rest = &rest[c.len()..];
+            }
+            state.finish().expect("only for vec");
+
+            assert_eq!(expected, run(config, &data), "{:?}", max_run);
+        }
+    }
+
+    #[test]
+    fn strip_bom() {
+        let stripping = StringsConfig {
+            strip_bom: true,
+            ..StringsConfig::default()
+        };
+        let with = "\u{feff}hello world".as_bytes();
+
+        check(with, with);
+        check_with(stripping, b"hello world", with);
+        check_with(stripping, b"hello world", b"hello world");
+        assert_eq!(
+            b"hello world".to_vec(),
+            read_strings(Dribble(with), stripping)
+        );
+
+        // only at the start, and only the whole thing
+        let later = "hi \u{feff}there".as_bytes();
+        check_with(stripping, later, later);
+        let partial = b"\xef\xbbhello\x01\x02\x03";
+        check_with(stripping, &run(StringsConfig::default(), partial), partial);
+    }
 }
@@ -0,0 +1,128 @@
+//! Helpers for tests which write records by hand, instead of getting `output` to.
+
+use cast::u16;
+use cast::u64;
+use cast::u8;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// The meta of a record: an unchanged regular file, with an unknown mode, no content, no MIME
+/// type, first in its archive, and no names, until it's told otherwise.
+pub fn meta() -> Meta {
+    Meta {
+        flags: 0,
+        children: 4,
+        mode: crate::UNKNOWN_MODE,
+        original_len: 0,
+        kind: 1,
+        hash: [0; 32],
+        archive_index: 0,
+        mime: None,
+        reason: None,
+        names: Vec::new(),
+    }
+}
+
+pub struct Meta {
+    flags: u8,
+    children: u8,
+    mode: u32,
+    original_len: u64,
+    kind: u8,
+    hash: [u8; 32],
+    archive_index: u32,
+    mime: Option<String>,
+    reason: Option<String>,
+    names: Vec<u8>,
+}
+
+impl Meta {
+    /// The content flag, with any of the MIME, bucketed and shared bits.
+    pub fn flags(mut self, flags: u8) -> Meta {
+        self.flags = flags;
+        self
+    }
+
+    pub fn children(mut self, children: u8) -> Meta {
+        self.children = children;
+        self
+    }
+
+    pub fn mode(mut self, mode: u32) -> Meta {
+        self.mode = mode;
+        self
+    }
+
+    pub fn original_len(mut self, original_len: u64) -> Meta {
+        self.original_len = original_len;
+        self
+    }
+
+    /// With the `0x80` bit, for an unsafe path.
+    pub fn kind(mut self, kind: u8) -> Meta {
+        self.kind = kind;
+        self
+    }
+
+    /// The sha256 of `content`, as it was in the archive, which also makes it the original length.
+    pub fn hash_of(mut self, content: &[u8]) -> Meta {
+        let mut sha256 = Sha256::new();
+        sha256.input(content);
+        self.hash = crate::digest(sha256);
+        self.original_len = u64(content.len());
+        self
+    }
+
+    pub fn archive_index(mut self, archive_index: u32) -> Meta {
+        self.archive_index = archive_index;
+        self
+    }
+
+    /// Also sets the MIME bit of the flags.
+    pub fn mime(mut self, mime: &str) -> Meta {
+        self.mime = Some(mime.to_string());
+        self
+    }
+
+    /// Only meaningful with children statuses `6` and `7`.
+    pub fn reason(mut self, reason: &str) -> Meta {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    /// The next of the names: the containers it's in, outermost first, then its own.
+    pub fn name<N: AsRef<[u8]>>(mut self, name: N) -> Meta {
+        let name = name.as_ref();
+        self.names
+            .extend_from_slice(&u16(name.len()).unwrap().to_le_bytes());
+        self.names.extend_from_slice(name);
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        let mut meta = vec![
+            self.flags | if self.mime.is_some() { 0x20 } else { 0 },
+            self.children,
+        ];
+        meta.extend_from_slice(&self.mode.to_le_bytes());
+        meta.extend_from_slice(&self.original_len.to_le_bytes());
+        meta.push(self.kind);
+        meta.extend_from_slice(&self.hash);
+        meta.extend_from_slice(&self.archive_index.to_le_bytes());
+        if let Some(mime) = &self.mime {
+            meta.push(u8(mime.len()).unwrap());
+            meta.extend_from_slice(mime.as_bytes());
+        }
+        if let Some(reason) = &self.reason {
+            meta.extend_from_slice(&u16(reason.len()).unwrap().to_le_bytes());
+            meta.extend_from_slice(reason.as_bytes());
+        }
+        meta.extend_from_slice(&self.names);
+        meta
+    }
+}
+
+/// Write a record of `meta` and `data` to `out`, as `output` would.
+pub fn record(out: &mut Vec<u8>, meta: &[u8], data: &[u8]) {
+    crate::write_record_bytes(out, meta, data).unwrap();
+}
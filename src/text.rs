@@ -0,0 +1,250 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+pub(crate) const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Copy a text member, dropping a leading UTF-8 byte order mark and, with `crlf`, folding CRLF
+/// line endings to LF. Returns whether anything was changed; if not, the output is identical to
+/// the input.
+pub fn normalize<R: Read, W: Write>(mut src: R, mut dest: W, crlf: bool) -> io::Result<bool> {
+    let mut changed = false;
+    let mut first = true;
+    let mut carried_cr = false;
+    let mut out = Vec::with_capacity(16 * 1024);
+
+    loop {
+        let mut buf = [0u8; 16 * 1024];
+        let len = read_full(&mut src, &mut buf)?;
+        if 0 == len {
+            break;
+        }
+        let mut buf = &buf[..len];
+
+        if first {
+            first = false;
+            if buf.starts_with(UTF8_BOM) {
+                changed = true;
+                buf = &buf[UTF8_BOM.len()..];
+            }
+        }
+
+        if !crlf {
+            dest.write_all(buf)?;
+            continue;
+        }
+
+        out.clear();
+        for &b in buf {
+            if carried_cr {
+                carried_cr = false;
+                if b'\n' == b {
+                    changed = true;
+                } else {
+                    out.push(b'\r');
+                }
+            }
+
+            if b'\r' == b {
+                carried_cr = true;
+            } else {
+                out.push(b);
+            }
+        }
+        dest.write_all(&out)?;
+    }
+
+    if carried_cr {
+        dest.write_all(b"\r")?;
+    }
+
+    Ok(changed)
+}
+
+/// Is this text in Latin-1 (ISO-8859-1), rather than UTF-8? That is: it isn't valid UTF-8, and
+/// every byte is printable ASCII, whitespace, or in the printable top half of Latin-1. Bytes in
+/// `0x80-0x9f` are control characters in Latin-1, so a member containing them isn't text.
+pub fn looks_latin1<R: Read>(mut src: R) -> io::Result<bool> {
+    let mut high = false;
+    let mut utf8 = true;
+    let mut carried = Vec::with_capacity(4);
+
+    loop {
+        let mut buf = [0u8; 16 * 1024];
+        let len = read_full(&mut src, &mut buf)?;
+        if 0 == len {
+            break;
+        }
+        let buf = &buf[..len];
+
+        if !buf.iter().all(|&b| latin1_text(b)) {
+            return Ok(false);
+        }
+        high |= buf.iter().any(|&b| b >= 0x80);
+
+        if utf8 {
+            // a character split between reads is only invalid if it's still incomplete at the end
+            carried.extend_from_slice(buf);
+            match std::str::from_utf8(&carried) {
+                Ok(_) => carried.clear(),
+                Err(e) if e.error_len().is_none() => {
+                    let _ = carried.drain(..e.valid_up_to());
+                }
+                Err(_) => utf8 = false,
+            }
+        }
+    }
+
+    Ok(high && !(utf8 && carried.is_empty()))
+}
+
+fn latin1_text(byte: u8) -> bool {
+    (b' '..0x7f).contains(&byte) || byte >= 0xa0 || b"\t\n\r\x0c".contains(&byte)
+}
+
+/// Reads Latin-1 (ISO-8859-1) content as UTF-8: each byte is the code point of the same value.
+pub struct FromLatin1<R> {
+    inner: R,
+    /// the second half of a character which didn't fit in the last `read`
+    pending: Option<u8>,
+}
+
+impl<R: Read> FromLatin1<R> {
+    pub fn new(inner: R) -> FromLatin1<R> {
+        FromLatin1 {
+            inner,
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for FromLatin1<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pos = 0;
+        if let Some(b) = self.pending.take() {
+            buf[0] = b;
+            pos = 1;
+        }
+
+        if pos == buf.len() {
+            return Ok(pos);
+        }
+
+        // each byte read can become two, so read half the remaining space (and at least one)
+        let mut raw = [0u8; 8 * 1024];
+        let want = ((buf.len() - pos) / 2).clamp(1, raw.len());
+        let len = self.inner.read(&mut raw[..want])?;
+
+        for &b in &raw[..len] {
+            if b < 0x80 {
+                buf[pos] = b;
+                pos += 1;
+                continue;
+            }
+
+            buf[pos] = 0xc0 | (b >> 6);
+            pos += 1;
+            let second = 0x80 | (b & 0x3f);
+            if pos < buf.len() {
+                buf[pos] = second;
+                pos += 1;
+            } else {
+                self.pending = Some(second);
+            }
+        }
+
+        Ok(pos)
+    }
+}
+
+/// Like `read`, but only returns short at the end of the input, so the BOM check sees whole chunks.
+fn read_full<R: Read>(src: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match src.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(len) => pos += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    fn normalize(data: &[u8], crlf: bool) -> (bool, Vec<u8>) {
+        let mut out = Vec::new();
+        let changed = super::normalize(data, &mut out, crlf).expect("only for vec");
+        (changed, out)
+    }
+
+    #[test]
+    fn crlf() {
+        assert_eq!(
+            (true, b"one\ntwo\n\rthree\r".to_vec()),
+            normalize(b"one\r\ntwo\r\n\rthree\r", true)
+        );
+        assert_eq!(
+            (false, b"one\r\ntwo\r\n".to_vec()),
+            normalize(b"one\r\ntwo\r\n", false)
+        );
+    }
+
+    #[test]
+    fn bom() {
+        for &crlf in &[false, true] {
+            assert_eq!(
+                (true, "hello → world\n".as_bytes().to_vec()),
+                normalize("\u{feff}hello → world\n".as_bytes(), crlf)
+            );
+        }
+        assert_eq!(
+            (true, b"one\r\n".to_vec()),
+            normalize(b"\xef\xbb\xbfone\r\n", false)
+        );
+    }
+
+    #[test]
+    fn untouched() {
+        assert_eq!(
+            (false, b"plain\n\xef\xbb\xbf".to_vec()),
+            normalize(b"plain\n\xef\xbb\xbf", true)
+        );
+    }
+
+    #[test]
+    fn latin1() {
+        assert!(super::looks_latin1(&b"caf\xe9\n"[..]).unwrap());
+        assert!(!super::looks_latin1("caf\u{e9}\n".as_bytes()).unwrap());
+        assert!(!super::looks_latin1(&b"plain\n"[..]).unwrap());
+        assert!(!super::looks_latin1(&b"caf\xe9\x01"[..]).unwrap());
+        assert!(!super::looks_latin1(&b"caf\x85"[..]).unwrap());
+        // valid up to the end, then cut off in the middle of a character
+        assert!(super::looks_latin1(&b"caf\xc3"[..]).unwrap());
+    }
+
+    #[test]
+    fn from_latin1() {
+        let mut out = String::new();
+        super::FromLatin1::new(&b"caf\xe9 \xff\n"[..])
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!("caf\u{e9} \u{ff}\n", out);
+
+        // one byte at a time, so every high byte's second half has to wait
+        let mut from = super::FromLatin1::new(&b"\xe9\xe9"[..]);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1];
+        while 1 == from.read(&mut buf).unwrap() {
+            out.push(buf[0]);
+        }
+        assert_eq!("\u{e9}\u{e9}".as_bytes(), out.as_slice());
+    }
+}